@@ -0,0 +1,82 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use wlvm::engine::Decoded;
+use wlvm::Instructions::{self, *};
+use wlvm::Registers::*;
+use wlvm::Vm;
+
+// Counts a down from 100 000
+fn countdown() -> Vec<Instructions> {
+    vec![
+        Set(A, 100_000),
+        Set(B, 1),
+        Sub(A, B),
+        Tne(A, C),
+        Jmp(2),
+        Hlt,
+    ]
+}
+
+// 300 x 300 iterations, adding 3 to c in the inner loop
+fn nested() -> Vec<Instructions> {
+    vec![
+        Set(A, 300),
+        Set(D, 1),
+        Set(E, 3),
+        Set(B, 300), // 3: outer loop
+        Add(C, E),   // 4: inner loop
+        Sub(B, D),
+        Tne(B, F),
+        Jmp(4),
+        Sub(A, D),
+        Tne(A, F),
+        Jmp(3),
+        Hlt,
+    ]
+}
+
+// Sums 1..=50 000 on the stack
+fn stack_sum() -> Vec<Instructions> {
+    vec![
+        Psh(0),
+        Set(A, 50_000),
+        Set(B, 1),
+        PshR(A), // 3
+        AddS,
+        Sub(A, B),
+        Tne(A, C),
+        Jmp(3),
+        Hlt,
+    ]
+}
+
+fn loops(c: &mut Criterion) {
+    let programs = [
+        ("countdown", countdown()),
+        ("nested", nested()),
+        ("stack_sum", stack_sum()),
+    ];
+
+    let mut group = c.benchmark_group("loops");
+    for (name, program) in programs.iter() {
+        group.bench_with_input(BenchmarkId::new("run", name), program, |b, program| {
+            b.iter(|| Vm::new().run(program).unwrap())
+        });
+        let decoded = Decoded::new(program);
+        group.bench_with_input(
+            BenchmarkId::new("run_decoded", name),
+            &decoded,
+            |b, decoded| b.iter(|| Vm::new().run_decoded(decoded).unwrap()),
+        );
+        #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+        {
+            let jit = wlvm::jit::Jit::compile(program, Default::default()).unwrap();
+            group.bench_with_input(BenchmarkId::new("run_jit", name), program, |b, program| {
+                b.iter(|| Vm::new().run_jit(program, &jit).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, loops);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+// Prints the prime numbers below 50
+let n = 2;
+while n < 50 {
+    let d = 2;
+    let prime = 1;
+    while d * d <= n {
+        if n / d * d == n {
+            prime = 0;
+        }
+        d = d + 1;
+    }
+    if prime {
+        print n;
+    }
+    n = n + 1;
+}
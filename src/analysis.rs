@@ -0,0 +1,487 @@
+use crate::syscall::SYS_EXIT;
+use crate::{Instructions, Instructions::*, Registers, Registers::*};
+use std::fmt;
+
+/// Registers read by the instruction. Implicit uses of special registers (st by the stack
+/// instructions, eq by jmp) are not listed.
+pub fn reads(instr: Instructions) -> Vec<Registers> {
+    match instr {
+        PshR(r) | Drg(r) | Prt(r) | Itf(_, r) | Fre(r) | HltR(r) => vec![r],
+        Add(a, b) | Sub(a, b) | Mul(a, b) | Div(a, b) => vec![a, b],
+        Tee(a, b) | Tne(a, b) | Tll(a, b) | Tmm(a, b) | Tel(a, b) | Tem(a, b) => vec![a, b],
+        Mov(_, b) | Alc(b, _) | Lod(_, b) => vec![b],
+        Sto(a, b) => vec![a, b],
+        _ => vec![],
+    }
+}
+
+/// Registers written by the instruction, eq by the integer and float tests. Syscalls may write
+/// any general purpose register.
+pub fn writes(instr: Instructions) -> Vec<Registers> {
+    match instr {
+        PopR(r) | Add(r, _) | Sub(r, _) | Mul(r, _) | Div(r, _) | Mov(r, _) => vec![r],
+        Fti(r, _) | Alc(_, r) | Lod(r, _) | Set(r, _) => vec![r],
+        Tee(..) | Tne(..) | Tll(..) | Tmm(..) | Tel(..) | Tem(..) => vec![Eq],
+        Fee(..) | Fne(..) | Fll(..) | Fmm(..) | Fel(..) | Fem(..) => vec![Eq],
+        Sys(_) => vec![A, B, C, D, E, F],
+        _ => vec![],
+    }
+}
+
+// Tests comparing a register with itself, and their result
+fn constant_test(instr: Instructions) -> Option<bool> {
+    match instr {
+        Tee(a, b) | Tel(a, b) | Tem(a, b) if a == b => Some(true),
+        Tne(a, b) | Tll(a, b) | Tmm(a, b) if a == b => Some(false),
+        _ => None,
+    }
+}
+
+/// Whether the program computes jumps by writing to ip, in which case its control flow is
+/// unknown.
+pub fn has_computed_jumps(program: &[Instructions]) -> bool {
+    program.iter().any(|i| writes(*i).contains(&Ip))
+}
+
+// Instructions stopping the program, the exit syscall being the one of the standard table
+fn halts(instr: Instructions) -> bool {
+    matches!(instr, Hlt | HltR(_) | Sys(SYS_EXIT))
+}
+
+/// Instructions that can run right after the one at `ip`, ignoring writes to ip. Targets
+/// outside of the program are left out, and nothing runs after hlt or the exit syscall.
+///
+/// A jmp right after a test of a register against itself (`tee a a`) always or never jumps,
+/// unless the jmp is itself the target of another jump.
+pub fn successors(program: &[Instructions], ip: usize) -> Vec<usize> {
+    let mut next = vec![];
+    match program[ip] {
+        instr if halts(instr) => (),
+        Jmp(target) => {
+            let is_target = program.contains(&Jmp(ip as i32));
+            let eq = match ip.checked_sub(1) {
+                Some(prev) if !is_target => constant_test(program[prev]),
+                _ => None,
+            };
+            if eq != Some(true) {
+                next.push(ip + 1);
+            }
+            if eq != Some(false) {
+                next.push(target as usize);
+            }
+        }
+        _ => next.push(ip + 1),
+    }
+    next.retain(|&i| i < program.len());
+    next.dedup();
+    next
+}
+
+/// Instructions reachable from the first one.
+pub fn reachable(program: &[Instructions]) -> Vec<bool> {
+    let mut seen = vec![false; program.len()];
+    let mut todo = vec![0];
+    while let Some(ip) = todo.pop() {
+        if ip >= program.len() || seen[ip] {
+            continue;
+        }
+        seen[ip] = true;
+        todo.extend(successors(program, ip));
+    }
+    seen
+}
+
+// Number of values the instruction needs on the stack, and how it changes the stack depth
+fn stack_effect(instr: Instructions) -> (usize, i64) {
+    match instr {
+        Psh(_) | PshR(_) => (0, 1),
+        Pop | PopR(_) => (1, -1),
+        Dup => (1, 1),
+        Ovr => (2, 1),
+        Swp => (2, 0),
+        Rot => (3, 0),
+        AddS | SubS | MulS | DivS => (2, -1),
+        _ => (0, 0),
+    }
+}
+
+/// Lowest and highest number of values on the stack.
+pub type Depth = (usize, usize);
+
+fn show(depth: Depth) -> String {
+    match depth {
+        (lo, hi) if lo == hi => lo.to_string(),
+        (lo, hi) => format!("{}..{}", lo, hi),
+    }
+}
+
+// Depth after the instruction, None if it always fails
+fn transfer(instr: Instructions, (lo, hi): Depth, stack_size: usize) -> Option<Depth> {
+    if writes(instr).contains(&Sp) {
+        return Some((0, stack_size));
+    }
+    let (needs, delta) = stack_effect(instr);
+    if hi < needs || (lo as i64 + delta) as usize > stack_size {
+        return None;
+    }
+    let lo = (lo.max(needs) as i64 + delta) as usize;
+    let hi = ((hi as i64 + delta) as usize).min(stack_size);
+    Some((lo, hi))
+}
+
+/// Stack depth before each instruction, None for the ones never run. Writes to sp make the
+/// depth unknown, between 0 and the stack size.
+pub fn stack_depths(program: &[Instructions], stack_size: usize) -> Vec<Option<Depth>> {
+    let mut depths = vec![None; program.len()];
+    if program.is_empty() {
+        return depths;
+    }
+    depths[0] = Some((0, 0));
+
+    // The depths only grow and are bounded by the stack size, so this terminates
+    let mut todo = vec![0];
+    while let Some(ip) = todo.pop() {
+        let after = match transfer(program[ip], depths[ip].unwrap(), stack_size) {
+            Some(d) => d,
+            None => continue,
+        };
+        for next in successors(program, ip) {
+            let joined = match depths[next] {
+                Some((lo, hi)) => (lo.min(after.0), hi.max(after.1)),
+                None => after,
+            };
+            if depths[next] != Some(joined) {
+                depths[next] = Some(joined);
+                todo.push(next);
+            }
+        }
+    }
+    depths
+}
+
+/// Stack underflows and overflows found by `stack_depths`, and instructions reached with
+/// different depths.
+pub fn check_stack(program: &[Instructions], stack_size: usize) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    if has_computed_jumps(program) {
+        return diagnostics;
+    }
+    let depths = stack_depths(program, stack_size);
+
+    // Depths coming from each path, the start of the program is a path to the first instruction
+    let mut incoming = vec![vec![]; program.len()];
+    if !program.is_empty() {
+        incoming[0].push((None, (0, 0)));
+    }
+    for (ip, depth) in depths.iter().enumerate() {
+        if let Some(after) = depth.and_then(|d| transfer(program[ip], d, stack_size)) {
+            for next in successors(program, ip) {
+                incoming[next].push((Some(ip), after));
+            }
+        }
+    }
+
+    for (ip, depth) in depths.iter().enumerate() {
+        let (lo, hi) = match depth {
+            Some(d) => *d,
+            None => continue,
+        };
+        let (needs, delta) = stack_effect(program[ip]);
+
+        if hi < needs {
+            let message = format!(
+                "stack underflow : needs {} value(s), there are at most {}",
+                needs, hi
+            );
+            diagnostics.push(Diagnostic::new(Severity::Error, program, ip, message));
+        } else if delta > 0 && lo + delta as usize > stack_size {
+            let message = format!(
+                "stack overflow : there are at least {} values on a stack of {}",
+                lo, stack_size
+            );
+            diagnostics.push(Diagnostic::new(Severity::Error, program, ip, message));
+        } else if delta > 0 && hi + delta as usize > stack_size {
+            let message = format!(
+                "possible stack overflow : there may be {} values on a stack of {}",
+                hi, stack_size
+            );
+            diagnostics.push(Diagnostic::new(Severity::Warning, program, ip, message));
+        }
+
+        let paths = &incoming[ip];
+        if paths.iter().any(|(_, d)| *d != paths[0].1) {
+            let from = paths
+                .iter()
+                .map(|(from, d)| match from {
+                    Some(from) => format!("{} from {}", show(*d), from),
+                    None => format!("{} at the start", show(*d)),
+                })
+                .collect::<Vec<String>>()
+                .join(", ");
+            let message = format!("stack depth differs between paths : {}", from);
+            diagnostics.push(Diagnostic::new(Severity::Warning, program, ip, message));
+        }
+    }
+    diagnostics
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub ip: usize,
+    pub instr: Instructions,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, program: &[Instructions], ip: usize, message: String) -> Self {
+        Diagnostic {
+            severity,
+            ip,
+            instr: program[ip],
+            message,
+        }
+    }
+}
+
+/// Printed like parse errors, with the index of the instruction instead of the line.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} | {}", self.ip, self.instr)?;
+        writeln!(f, "^^^^^^^^^^^^^^^^^^^^")?;
+        match self.severity {
+            Severity::Warning => write!(f, "Warning : {}", self.message),
+            Severity::Error => write!(f, "Error : {}", self.message),
+        }
+    }
+}
+
+/// Static checks of an assembled program, sorted by instruction.
+pub fn check(program: &[Instructions], stack_size: usize) -> Vec<Diagnostic> {
+    let mut diagnostics = check_stack(program, stack_size);
+    let computed = has_computed_jumps(program);
+    let reachable = if computed {
+        vec![true; program.len()]
+    } else {
+        reachable(program)
+    };
+
+    for (ip, instr) in program.iter().enumerate() {
+        if let Jmp(target) = instr {
+            if *target as usize >= program.len() {
+                let message = format!(
+                    "jump to {}, outside of the program ({} instructions)",
+                    target,
+                    program.len()
+                );
+                diagnostics.push(Diagnostic::new(Severity::Error, program, ip, message));
+            }
+        }
+    }
+
+    if !computed && !program.is_empty() {
+        if let Some(first) = (0..program.len()).find(|&ip| !reachable[ip]) {
+            let count = reachable.iter().filter(|r| !**r).count();
+            let message = format!("{} instruction(s) are never run", count);
+            diagnostics.push(Diagnostic::new(Severity::Warning, program, first, message));
+        }
+
+        if !(0..program.len()).any(|ip| reachable[ip] && halts(program[ip])) {
+            let message = String::from("the program never reaches hlt");
+            diagnostics.push(Diagnostic::new(Severity::Error, program, 0, message));
+        }
+    }
+
+    // General purpose registers read but never written are always 0
+    let live = (0..program.len()).filter(|&ip| reachable[ip]);
+    let written = live
+        .clone()
+        .flat_map(|ip| writes(program[ip]))
+        .collect::<Vec<_>>();
+    let mut reported = vec![];
+    for ip in live {
+        for reg in reads(program[ip]) {
+            if (reg as usize) < Ip as usize && !written.contains(&reg) && !reported.contains(&reg) {
+                reported.push(reg);
+                let message = format!("{} is read but never written, it is always 0", reg);
+                diagnostics.push(Diagnostic::new(Severity::Warning, program, ip, message));
+            }
+        }
+    }
+
+    diagnostics.sort_by_key(|d| d.ip);
+    diagnostics
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn messages(program: &[Instructions]) -> Vec<(Severity, usize, String)> {
+        check(program, 4)
+            .into_iter()
+            .map(|d| (d.severity, d.ip, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn valid_program() {
+        let program = [
+            Psh(3),
+            PopR(A),
+            Psh(4),
+            PopR(B),
+            Tee(A, B),
+            Jmp(7),
+            Drg(A),
+            Hlt,
+        ];
+        assert_eq!(messages(&program), vec![]);
+    }
+
+    #[test]
+    fn jump_outside() {
+        assert_eq!(
+            messages(&[Jmp(7), Hlt]),
+            vec![(
+                Severity::Error,
+                0,
+                String::from("jump to 7, outside of the program (2 instructions)")
+            )]
+        );
+    }
+
+    #[test]
+    fn unreachable_and_no_hlt() {
+        // 0: tee a a, 1: jmp 0 loops forever
+        let program = [Tee(A, A), Jmp(0), Psh(1), Hlt];
+        assert_eq!(
+            messages(&program),
+            vec![
+                (
+                    Severity::Error,
+                    0,
+                    String::from("the program never reaches hlt")
+                ),
+                (
+                    Severity::Warning,
+                    0,
+                    String::from("a is read but never written, it is always 0")
+                ),
+                (
+                    Severity::Warning,
+                    2,
+                    String::from("2 instruction(s) are never run")
+                ),
+            ]
+        );
+
+        // The jmp is also reached from another jump, so it may fall through
+        let program = [Tee(A, A), Jmp(3), Jmp(1), Hlt];
+        assert_eq!(successors(&program, 1), vec![2, 3]);
+    }
+
+    #[test]
+    fn exit_syscall_halts() {
+        let program = [Set(A, 3), Sys(SYS_EXIT), Tee(A, A), Jmp(1), Hlt];
+        assert_eq!(successors(&program, 1), vec![]);
+        assert_eq!(
+            messages(&program),
+            vec![(
+                Severity::Warning,
+                2,
+                String::from("3 instruction(s) are never run")
+            )]
+        );
+    }
+
+    #[test]
+    fn computed_jumps() {
+        let program = [Psh(3), PopR(Ip), Tee(A, A), Jmp(2), Hlt];
+        assert!(has_computed_jumps(&program));
+        assert_eq!(messages(&program).len(), 1);
+    }
+
+    #[test]
+    fn syscalls_write_registers() {
+        assert_eq!(messages(&[Sys(3), Drg(B), Hlt]), vec![]);
+    }
+
+    #[test]
+    fn depths() {
+        // 0: psh, 1: tee a a, 2: jmp 5, 3: psh, 4: psh, 5: pop, 6: hlt
+        let program = [Psh(1), Tee(A, A), Jmp(5), Psh(2), Psh(3), Pop, Hlt];
+        assert_eq!(
+            stack_depths(&program, 4),
+            vec![
+                Some((0, 0)),
+                Some((1, 1)),
+                Some((1, 1)),
+                None,
+                None,
+                Some((1, 1)),
+                Some((0, 0))
+            ]
+        );
+
+        let program = [Psh(1), Tne(A, B), Jmp(4), Psh(2), Pop, Hlt];
+        assert_eq!(stack_depths(&program, 4)[4], Some((1, 2)));
+        assert_eq!(
+            check_stack(&program, 4)
+                .into_iter()
+                .map(|d| (d.ip, d.message))
+                .collect::<Vec<_>>(),
+            vec![(
+                4,
+                String::from("stack depth differs between paths : 1 from 2, 2 from 3")
+            )]
+        );
+    }
+
+    #[test]
+    fn stack_errors() {
+        let program = [Psh(1), Swp, Hlt];
+        assert_eq!(
+            messages(&program),
+            vec![(
+                Severity::Error,
+                1,
+                String::from("stack underflow : needs 2 value(s), there are at most 1")
+            )]
+        );
+
+        let program = [Psh(1), Psh(2), Psh(3), Psh(4), Dup, Hlt];
+        assert_eq!(
+            messages(&program)[0],
+            (
+                Severity::Error,
+                4,
+                String::from("stack overflow : there are at least 4 values on a stack of 4")
+            )
+        );
+
+        // Pushes in a loop until a is 0, which may overflow
+        let program = [Psh(1), PopR(A), Psh(0), Tne(A, Of), Jmp(2), Hlt];
+        assert_eq!(
+            messages(&program)
+                .into_iter()
+                .filter(|(s, _, _)| *s == Severity::Warning)
+                .map(|(_, ip, _)| ip)
+                .collect::<Vec<_>>(),
+            vec![2, 2]
+        );
+    }
+
+    #[test]
+    fn unknown_depth() {
+        let program = [Psh(1), Psh(2), Mov(Sp, A), Pop, Pop, Hlt];
+        assert_eq!(stack_depths(&program, 4)[3], Some((0, 4)));
+        assert_eq!(check_stack(&program, 4), vec![]);
+    }
+}
@@ -0,0 +1,412 @@
+use crate::{FloatRegisters, Instructions, Instructions::*, Registers, WordSize};
+use std::fmt;
+
+// Layout of an assembled program :
+//
+// magic   : b"WLVM"
+// version : u8
+// word    : u8, 32 or 64
+// count   : u32 little endian, number of instructions
+// then for each instruction its opcode (u8) followed by its operands :
+// - registers and float registers are one byte
+// - fld immediates are the 8 bytes little endian of the f64
+// - psh and set immediates are 4 or 8 bytes little endian depending on the word size
+// - jmp targets and syscall numbers are 4 bytes little endian
+pub const MAGIC: &[u8; 4] = b"WLVM";
+pub const VERSION: u8 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadWordSize(u8),
+    UnknownOpcode(u8),
+    UnknownRegister(u8),
+    Truncated,
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BytecodeError::BadMagic => write!(f, "not a wlvm bytecode file"),
+            BytecodeError::UnsupportedVersion(v) => {
+                write!(f, "unsupported bytecode version {}", v)
+            }
+            BytecodeError::BadWordSize(w) => write!(f, "invalid word size {}", w),
+            BytecodeError::UnknownOpcode(o) => write!(f, "unknown opcode {}", o),
+            BytecodeError::UnknownRegister(r) => write!(f, "unknown register {}", r),
+            BytecodeError::Truncated => write!(f, "unexpected end of file"),
+        }
+    }
+}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn opcode(instr: Instructions) -> u8 {
+    match instr {
+        Psh(_) => 0,
+        Add(_, _) => 1,
+        Mul(_, _) => 2,
+        Div(_, _) => 3,
+        Sub(_, _) => 4,
+        Pop => 5,
+        PshR(_) => 6,
+        PopR(_) => 7,
+        Dup => 8,
+        Swp => 9,
+        Ovr => 10,
+        Rot => 11,
+        AddS => 12,
+        SubS => 13,
+        MulS => 14,
+        DivS => 15,
+        Mov(_, _) => 16,
+        Hlt => 17,
+        Dst => 18,
+        Drg(_) => 19,
+        Dmp => 20,
+        Prt(_) => 21,
+        Tee(_, _) => 22,
+        Tne(_, _) => 23,
+        Tll(_, _) => 24,
+        Tmm(_, _) => 25,
+        Tel(_, _) => 26,
+        Tem(_, _) => 27,
+        Jmp(_) => 28,
+        Fld(_, _) => 29,
+        Fmv(_, _) => 30,
+        Fad(_, _) => 31,
+        Fsb(_, _) => 32,
+        Fml(_, _) => 33,
+        Fdv(_, _) => 34,
+        Fee(_, _) => 35,
+        Fne(_, _) => 36,
+        Fll(_, _) => 37,
+        Fmm(_, _) => 38,
+        Fel(_, _) => 39,
+        Fem(_, _) => 40,
+        Itf(_, _) => 41,
+        Fti(_, _) => 42,
+        Fpr(_) => 43,
+        Alc(_, _) => 44,
+        Fre(_) => 45,
+        Lod(_, _) => 46,
+        Sto(_, _) => 47,
+        Sys(_) => 48,
+        HltR(_) => 49,
+        Set(_, _) => 50,
+        Snp => 51,
+    }
+}
+
+/// Assembles a program. In 32 bit mode, psh immediates are truncated to 32 bits.
+pub fn encode(program: &[Instructions], word_size: WordSize) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.push(word_size.bits());
+    out.extend_from_slice(&(program.len() as u32).to_le_bytes());
+
+    for instr in program {
+        out.push(opcode(*instr));
+        match *instr {
+            Psh(i) => match word_size {
+                WordSize::W32 => out.extend_from_slice(&(i as i32).to_le_bytes()),
+                WordSize::W64 => out.extend_from_slice(&i.to_le_bytes()),
+            },
+            Set(r, i) => {
+                out.push(r as u8);
+                match word_size {
+                    WordSize::W32 => out.extend_from_slice(&(i as i32).to_le_bytes()),
+                    WordSize::W64 => out.extend_from_slice(&i.to_le_bytes()),
+                }
+            }
+            Jmp(i) => out.extend_from_slice(&(i as u32).to_le_bytes()),
+            Sys(n) => out.extend_from_slice(&n.to_le_bytes()),
+            PshR(r) | PopR(r) | Drg(r) | Prt(r) | Fre(r) | HltR(r) => out.push(r as u8),
+            Alc(a, b)
+            | Lod(a, b)
+            | Sto(a, b)
+            | Add(a, b)
+            | Mul(a, b)
+            | Div(a, b)
+            | Sub(a, b)
+            | Mov(a, b)
+            | Tee(a, b)
+            | Tne(a, b)
+            | Tll(a, b)
+            | Tmm(a, b)
+            | Tel(a, b)
+            | Tem(a, b) => {
+                out.push(a as u8);
+                out.push(b as u8);
+            }
+            Fld(f, bits) => {
+                out.push(f as u8);
+                out.extend_from_slice(&bits.to_le_bytes());
+            }
+            Fmv(a, b)
+            | Fad(a, b)
+            | Fsb(a, b)
+            | Fml(a, b)
+            | Fdv(a, b)
+            | Fee(a, b)
+            | Fne(a, b)
+            | Fll(a, b)
+            | Fmm(a, b)
+            | Fel(a, b)
+            | Fem(a, b) => {
+                out.push(a as u8);
+                out.push(b as u8);
+            }
+            Itf(f, r) | Fti(r, f) => {
+                out.push(f as u8);
+                out.push(r as u8);
+            }
+            Fpr(f) => out.push(f as u8),
+            Pop | Dup | Swp | Ovr | Rot | AddS | SubS | MulS | DivS | Hlt | Dst | Dmp | Snp => (),
+        }
+    }
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], BytecodeError> {
+        if self.pos + n > self.bytes.len() {
+            return Err(BytecodeError::Truncated);
+        }
+        let taken = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn word(&mut self, word_size: WordSize) -> Result<i64, BytecodeError> {
+        Ok(match word_size {
+            WordSize::W32 => self.u32()? as i32 as i64,
+            WordSize::W64 => self.u64()? as i64,
+        })
+    }
+
+    fn u64(&mut self) -> Result<u64, BytecodeError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn float_register(&mut self) -> Result<FloatRegisters, BytecodeError> {
+        let raw = self.byte()?;
+        FloatRegisters::from_index(raw as usize).ok_or(BytecodeError::UnknownRegister(raw))
+    }
+
+    fn register(&mut self) -> Result<Registers, BytecodeError> {
+        let raw = self.byte()?;
+        Registers::from_index(raw as usize).ok_or(BytecodeError::UnknownRegister(raw))
+    }
+}
+
+/// Reads back a program assembled by `encode`, along with the word size it was assembled for.
+pub fn decode(bytes: &[u8]) -> Result<(WordSize, Vec<Instructions>), BytecodeError> {
+    if !is_bytecode(bytes) {
+        return Err(BytecodeError::BadMagic);
+    }
+    let mut reader = Reader {
+        bytes,
+        pos: MAGIC.len(),
+    };
+
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+    let bits = reader.byte()?;
+    let word_size = match bits {
+        32 => WordSize::W32,
+        64 => WordSize::W64,
+        _ => return Err(BytecodeError::BadWordSize(bits)),
+    };
+
+    let count = reader.u32()?;
+    let mut program = vec![];
+
+    for _ in 0..count {
+        let instr = match reader.byte()? {
+            0 => Psh(reader.word(word_size)?),
+            1 => Add(reader.register()?, reader.register()?),
+            2 => Mul(reader.register()?, reader.register()?),
+            3 => Div(reader.register()?, reader.register()?),
+            4 => Sub(reader.register()?, reader.register()?),
+            5 => Pop,
+            6 => PshR(reader.register()?),
+            7 => PopR(reader.register()?),
+            8 => Dup,
+            9 => Swp,
+            10 => Ovr,
+            11 => Rot,
+            12 => AddS,
+            13 => SubS,
+            14 => MulS,
+            15 => DivS,
+            16 => Mov(reader.register()?, reader.register()?),
+            17 => Hlt,
+            18 => Dst,
+            19 => Drg(reader.register()?),
+            20 => Dmp,
+            21 => Prt(reader.register()?),
+            22 => Tee(reader.register()?, reader.register()?),
+            23 => Tne(reader.register()?, reader.register()?),
+            24 => Tll(reader.register()?, reader.register()?),
+            25 => Tmm(reader.register()?, reader.register()?),
+            26 => Tel(reader.register()?, reader.register()?),
+            27 => Tem(reader.register()?, reader.register()?),
+            28 => Jmp(reader.u32()? as i32),
+            29 => Fld(reader.float_register()?, reader.u64()?),
+            30 => Fmv(reader.float_register()?, reader.float_register()?),
+            31 => Fad(reader.float_register()?, reader.float_register()?),
+            32 => Fsb(reader.float_register()?, reader.float_register()?),
+            33 => Fml(reader.float_register()?, reader.float_register()?),
+            34 => Fdv(reader.float_register()?, reader.float_register()?),
+            35 => Fee(reader.float_register()?, reader.float_register()?),
+            36 => Fne(reader.float_register()?, reader.float_register()?),
+            37 => Fll(reader.float_register()?, reader.float_register()?),
+            38 => Fmm(reader.float_register()?, reader.float_register()?),
+            39 => Fel(reader.float_register()?, reader.float_register()?),
+            40 => Fem(reader.float_register()?, reader.float_register()?),
+            41 => Itf(reader.float_register()?, reader.register()?),
+            42 => {
+                let f = reader.float_register()?;
+                Fti(reader.register()?, f)
+            }
+            43 => Fpr(reader.float_register()?),
+            44 => Alc(reader.register()?, reader.register()?),
+            45 => Fre(reader.register()?),
+            46 => Lod(reader.register()?, reader.register()?),
+            47 => Sto(reader.register()?, reader.register()?),
+            48 => Sys(reader.u32()?),
+            49 => HltR(reader.register()?),
+            50 => Set(reader.register()?, reader.word(word_size)?),
+            51 => Snp,
+            op => return Err(BytecodeError::UnknownOpcode(op)),
+        };
+        program.push(instr);
+    }
+
+    Ok((word_size, program))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FloatRegisters::*;
+    use crate::Registers::*;
+
+    fn every_instruction() -> Vec<Instructions> {
+        vec![
+            Psh(-7),
+            Add(A, B),
+            Mul(C, D),
+            Div(E, F),
+            Sub(Ip, Sp),
+            Pop,
+            PshR(St),
+            PopR(Eq),
+            Dup,
+            Swp,
+            Ovr,
+            Rot,
+            AddS,
+            SubS,
+            MulS,
+            DivS,
+            Mov(Of, A),
+            Dst,
+            Drg(B),
+            Dmp,
+            Prt(C),
+            Tee(A, B),
+            Tne(A, B),
+            Tll(A, B),
+            Tmm(A, B),
+            Tel(A, B),
+            Tem(A, B),
+            Jmp(12),
+            Fld(Fa, f64::NAN.to_bits()),
+            Fmv(Fb, Fc),
+            Fad(Fd, Fe),
+            Fsb(Ff, Fa),
+            Fml(Fa, Fb),
+            Fdv(Fa, Fb),
+            Fee(Fa, Fb),
+            Fne(Fa, Fb),
+            Fll(Fa, Fb),
+            Fmm(Fa, Fb),
+            Fel(Fa, Fb),
+            Fem(Fa, Fb),
+            Itf(Fc, D),
+            Fti(E, Fd),
+            Fpr(Ff),
+            Alc(A, B),
+            Fre(C),
+            Lod(D, E),
+            Sto(F, A),
+            Sys(7),
+            Set(C, -9),
+            Snp,
+            HltR(B),
+            Hlt,
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for word_size in [WordSize::W32, WordSize::W64].iter() {
+            let program = every_instruction();
+            let bytes = encode(&program, *word_size);
+
+            assert_eq!(bytes[5], word_size.bits());
+            assert_eq!(decode(&bytes), Ok((*word_size, program)));
+        }
+    }
+
+    #[test]
+    fn wide_immediates() {
+        let program = vec![Psh(i64::MIN), Psh(1 << 40), Hlt];
+
+        let bytes = encode(&program, WordSize::W64);
+        assert_eq!(decode(&bytes), Ok((WordSize::W64, program)));
+    }
+
+    #[test]
+    fn invalid_files() {
+        let bytes = encode(&every_instruction(), WordSize::W32);
+
+        assert_eq!(decode(b"psh 5"), Err(BytecodeError::BadMagic));
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 1]),
+            Err(BytecodeError::Truncated)
+        );
+
+        let mut bad_word = bytes.clone();
+        bad_word[5] = 16;
+        assert_eq!(decode(&bad_word), Err(BytecodeError::BadWordSize(16)));
+
+        let mut bad_opcode = bytes;
+        bad_opcode[10] = 200;
+        assert_eq!(decode(&bad_opcode), Err(BytecodeError::UnknownOpcode(200)));
+    }
+}
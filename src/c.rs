@@ -0,0 +1,622 @@
+use crate::analysis::has_computed_jumps;
+use crate::{Instructions, Instructions::*, OverflowMode, Registers, Registers::*, Vm, WordSize};
+use std::fmt::Write;
+
+// Everything the generated code calls, mirroring the VM. Errors print the same message as
+// `wlvm run` and exit with the same codes.
+const RUNTIME: &str = r#"
+#define EXIT_TRAP 70
+#define EXIT_FUEL 75
+
+enum { ADD, SUB, MUL, DIV };
+
+static int64_t stack[STACK_SIZE + 1];
+static int64_t memory[HEAP_SIZE + 1];
+static int64_t live[HEAP_SIZE + 1];  /* Start of the allocated block holding each word, 0 if none */
+static int64_t freed[HEAP_SIZE + 1]; /* Start of the freed block holding each word, 0 if none */
+static int64_t sizes[HEAP_SIZE + 1]; /* Size of the block starting at each word */
+
+static _Noreturn void fail(const char *error, int64_t ip) {
+    fflush(stdout);
+    fprintf(stderr, "Error: %s at instruction %" PRId64 "\n", error, ip);
+    exit(EXIT_TRAP);
+}
+
+static _Noreturn void out_of_fuel(int64_t ip) {
+    fflush(stdout);
+    fprintf(stderr, "Error: ERR_OUT_OF_FUEL at instruction %" PRId64 "\n", ip);
+    exit(EXIT_FUEL);
+}
+
+static _Noreturn void unknown_syscall(int64_t n, int64_t ip) {
+    fflush(stdout);
+    fprintf(stderr, "Error: ERR_UNKNOWN_SYSCALL %" PRId64 " at instruction %" PRId64 "\n", n, ip);
+    exit(EXIT_TRAP);
+}
+
+static int64_t narrow(int64_t value) {
+#if W32
+    return (int32_t)value;
+#else
+    return value;
+#endif
+}
+
+static int64_t arithmetic(int op, int64_t lhs, int64_t rhs, int64_t *of, int64_t ip) {
+    int64_t wrapped, saturated;
+    int overflowed;
+    lhs = narrow(lhs);
+    rhs = narrow(rhs);
+#if W32
+    /* The exact result of two 32 bit operands always fits in 64 bits */
+    int64_t exact = op == ADD ? lhs + rhs : op == SUB ? lhs - rhs : op == MUL ? lhs * rhs : lhs / rhs;
+    wrapped = narrow(exact);
+    overflowed = wrapped != exact;
+    saturated = exact < INT32_MIN ? INT32_MIN : exact > INT32_MAX ? INT32_MAX : exact;
+#else
+    switch (op) {
+    case ADD:
+        overflowed = __builtin_add_overflow(lhs, rhs, &wrapped);
+        saturated = rhs > 0 ? INT64_MAX : INT64_MIN;
+        break;
+    case SUB:
+        overflowed = __builtin_sub_overflow(lhs, rhs, &wrapped);
+        saturated = rhs < 0 ? INT64_MAX : INT64_MIN;
+        break;
+    case MUL:
+        overflowed = __builtin_mul_overflow(lhs, rhs, &wrapped);
+        saturated = (lhs < 0) != (rhs < 0) ? INT64_MIN : INT64_MAX;
+        break;
+    default:
+        overflowed = lhs == INT64_MIN && rhs == -1;
+        wrapped = overflowed ? INT64_MIN : lhs / rhs;
+        saturated = INT64_MAX;
+        break;
+    }
+    if (!overflowed) {
+        saturated = wrapped;
+    }
+#endif
+    if (overflowed && OVERFLOW_MODE == TRAPPING) {
+        fail("ERR_ARITHMETIC_OVERFLOW", ip);
+    }
+    *of = overflowed;
+    return OVERFLOW_MODE == SATURATING ? saturated : wrapped;
+}
+
+static void push(int64_t *sp, int64_t *st, int64_t value, int64_t ip) {
+    if (*sp + 1 >= STACK_SIZE) {
+        fail("ERR_STACK_OVERFLOW", ip);
+    }
+    stack[++*sp] = value;
+    *st = value;
+}
+
+static int64_t peek(int64_t sp, int64_t depth, int64_t ip) {
+    if (sp - depth < 0) {
+        fail("ERR_STACK_UNDERFLOW", ip);
+    }
+    return stack[sp - depth];
+}
+
+static int64_t pop(int64_t *sp, int64_t *st, int64_t ip) {
+    int64_t popped = peek(*sp, 0, ip);
+    --*sp;
+    *st = *sp >= 0 ? stack[*sp] : 0;
+    return popped;
+}
+
+static void set_sp(int64_t *sp, int64_t *st, int64_t value, int64_t ip) {
+    if (value < -1 || value >= STACK_SIZE) {
+        fail("ERR_INVALID_STACK_POINTER", ip);
+    }
+    *sp = value;
+    *st = value >= 0 ? stack[value] : 0;
+}
+
+static void stack_arithmetic(int op, int64_t *sp, int64_t *st, int64_t *of, int64_t ip) {
+    int64_t rhs = peek(*sp, 0, ip);
+    int64_t lhs = peek(*sp, 1, ip);
+    int64_t result = arithmetic(op, lhs, rhs, of, ip);
+    pop(sp, st, ip);
+    pop(sp, st, ip);
+    push(sp, st, result, ip);
+}
+
+/* First fit, freed blocks overlapping the new one are forgotten */
+static int64_t alloc_words(int64_t size, int64_t ip) {
+    if (size <= 0) {
+        fail("ERR_INVALID_ALLOCATION_SIZE", ip);
+    }
+    int64_t start = 1;
+    for (;;) {
+        if (size > HEAP_SIZE - start) {
+            fail("ERR_OUT_OF_MEMORY", ip);
+        }
+        int64_t word = start;
+        while (word < start + size && !live[word]) {
+            word++;
+        }
+        if (word == start + size) {
+            break;
+        }
+        start = word + sizes[word];
+    }
+    for (int64_t word = start; word < start + size; word++) {
+        if (freed[word]) {
+            int64_t block = freed[word];
+            for (int64_t w = block; w < block + sizes[block]; w++) {
+                freed[w] = 0;
+            }
+        }
+    }
+    for (int64_t word = start; word < start + size; word++) {
+        memory[word] = 0;
+        live[word] = start;
+    }
+    sizes[start] = size;
+    return start;
+}
+
+static void free_words(int64_t ptr, int64_t ip) {
+    int inside = ptr >= 1 && ptr < HEAP_SIZE;
+    if (inside && live[ptr] == ptr) {
+        for (int64_t word = ptr; word < ptr + sizes[ptr]; word++) {
+            live[word] = 0;
+            freed[word] = ptr;
+        }
+    } else if (inside && freed[ptr] == ptr) {
+        fail("ERR_DOUBLE_FREE", ip);
+    } else {
+        fail("ERR_INVALID_FREE", ip);
+    }
+}
+
+static int64_t check(int64_t addr, int64_t ip) {
+    if (addr >= 0 && addr < HEAP_SIZE) {
+        if (live[addr]) {
+            return addr;
+        }
+        if (freed[addr]) {
+            fail("ERR_USE_AFTER_FREE", ip);
+        }
+    }
+    fail("ERR_OUT_OF_BOUNDS", ip);
+}
+
+static _Noreturn void halt(int64_t exit_code) {
+    int64_t blocks = 0, words = 0;
+    for (int64_t word = 1; word < HEAP_SIZE; word++) {
+        if (live[word] == word) {
+            blocks++;
+            words += sizes[word];
+        }
+    }
+    if (blocks) {
+        fflush(stdout);
+        fprintf(stderr, "Leak : %" PRId64 " block(s) of %" PRId64 " word(s) still allocated\n",
+                blocks, words);
+        for (int64_t word = 1; word < HEAP_SIZE; word++) {
+            if (live[word] == word) {
+                fprintf(stderr, "\t%" PRId64 " word(s) at %" PRId64 "\n", sizes[word], word);
+            }
+        }
+    }
+    exit((int)exit_code);
+}
+
+static int64_t sys_write(int64_t fd, int64_t addr, int64_t len, int64_t ip) {
+    len = len > 0 ? len : 0;
+    for (int64_t word = addr; word < addr + len; word++) {
+        check(word, ip);
+    }
+    FILE *file = fd == 1 ? stdout : fd == 2 ? stderr : NULL;
+    if (!file) {
+        return -1;
+    }
+    for (int64_t word = addr; word < addr + len; word++) {
+        if (fputc((unsigned char)memory[word], file) == EOF) {
+            return -1;
+        }
+    }
+    return fflush(file) == 0 ? len : -1;
+}
+
+static int64_t sys_read(int64_t fd, int64_t addr, int64_t len, int64_t ip) {
+    len = len > 0 ? len : 0;
+    for (int64_t word = addr; word < addr + len; word++) {
+        check(word, ip);
+    }
+    if (fd != 0) {
+        return -1;
+    }
+    unsigned char *bytes = malloc(len + 1);
+    ssize_t n = read(0, bytes, len);
+    for (ssize_t i = 0; i < n; i++) {
+        memory[addr + i] = bytes[i];
+    }
+    free(bytes);
+    return n;
+}
+
+static void sys_time(int64_t *secs, int64_t *nanos) {
+    struct timespec now;
+    clock_gettime(CLOCK_REALTIME, &now);
+    *secs = now.tv_sec;
+    *nanos = now.tv_nsec;
+}
+
+static void print_char(int64_t value) {
+    if (value >= 0 && value < 256) {
+        /* Printed as the unicode code point, in UTF-8 */
+        if (value < 128) {
+            putchar((int)value);
+        } else {
+            putchar(0xC0 | (int)(value >> 6));
+            putchar(0x80 | (int)(value & 0x3F));
+        }
+        fflush(stdout);
+    }
+}
+
+/* Shortest digits reading back as the same double, without exponent */
+static void print_float(double x) {
+    if (isnan(x)) {
+        fputs("NaN", stdout);
+        return;
+    }
+    if (signbit(x)) {
+        putchar('-');
+        x = -x;
+    }
+    if (isinf(x)) {
+        fputs("inf", stdout);
+        return;
+    }
+    if (x == 0) {
+        putchar('0');
+        return;
+    }
+    char buffer[40];
+    for (int precision = 0; precision <= 16; precision++) {
+        snprintf(buffer, sizeof buffer, "%.*e", precision, x);
+        if (strtod(buffer, NULL) == x) {
+            break;
+        }
+    }
+    char digits[20];
+    int n = 0;
+    char *c = buffer;
+    for (; *c != 'e'; c++) {
+        if (*c != '.') {
+            digits[n++] = *c;
+        }
+    }
+    int exponent = atoi(c + 1);
+    if (exponent >= n - 1) {
+        fwrite(digits, 1, n, stdout);
+        for (int i = 0; i < exponent - (n - 1); i++) {
+            putchar('0');
+        }
+    } else if (exponent >= 0) {
+        fwrite(digits, 1, exponent + 1, stdout);
+        putchar('.');
+        fwrite(digits + exponent + 1, 1, n - exponent - 1, stdout);
+    } else {
+        fputs("0.", stdout);
+        for (int i = 0; i < -exponent - 1; i++) {
+            putchar('0');
+        }
+        fwrite(digits, 1, n, stdout);
+    }
+}
+
+static double from_bits(uint64_t bits) {
+    double x;
+    memcpy(&x, &bits, sizeof x);
+    return x;
+}
+
+/* NaN gives 0, out of range values saturate to the word bounds */
+static int64_t float_to_int(double x) {
+    if (isnan(x)) {
+        return 0;
+    }
+#if W32
+    if (x >= 2147483647.0) {
+        return INT32_MAX;
+    }
+    if (x <= -2147483648.0) {
+        return INT32_MIN;
+    }
+    return (int32_t)x;
+#else
+    if (x >= 9223372036854775807.0) {
+        return INT64_MAX;
+    }
+    if (x <= -9223372036854775808.0) {
+        return INT64_MIN;
+    }
+    return (int64_t)x;
+#endif
+}
+
+static void dump(const int64_t regs[11], const double fregs[6]) {
+    static const char *names[] = {"A", "B", "C", "D", "E", "F", "Ip", "Sp", "St", "Eq", "Of"};
+    static const char *float_names[] = {"Fa", "Fb", "Fc", "Fd", "Fe", "Ff"};
+    printf("[");
+    for (int i = 0; i < 11; i++) {
+        printf("%s: %" PRId64 ", ", names[i], regs[i]);
+    }
+    printf("]\n[");
+    for (int i = 0; i < 6; i++) {
+        printf("%s: ", float_names[i]);
+        print_float(fregs[i]);
+        printf(", ");
+    }
+    printf("]\n\n");
+    printf("Stack : [%" PRId64 ", ", stack[0]);
+    for (int i = 1; i < STACK_SIZE; i++) {
+        printf(i == STACK_SIZE - 1 ? "%" PRId64 "]\n" : "%" PRId64 ", ", stack[i]);
+    }
+}
+"#;
+
+/// C source of a standalone program behaving like `program` run on `vm`, with the same word
+/// size, overflow mode, stack and heap sizes and fuel. Registers are locals, jumps are gotos
+/// and the checks of the VM are kept, errors exiting with the codes of `wlvm run`.
+///
+/// Syscalls other than the standard ones fail with ERR_UNKNOWN_SYSCALL. The code needs a C11
+/// compiler with `__builtin_*_overflow` (gcc or clang) and a POSIX system.
+pub fn compile(program: &[Instructions], vm: &Vm) -> String {
+    let computed_jumps = has_computed_jumps(program);
+    let len = program.len();
+    let mut targets = vec![computed_jumps; len + 1];
+    for instr in program {
+        if let Jmp(target) = *instr {
+            if target >= 0 && target as usize <= len {
+                targets[target as usize] = true;
+            }
+        }
+    }
+
+    let mut c = String::from("/* Generated by wlvm */\n#define _POSIX_C_SOURCE 200809L\n");
+    for header in &[
+        "inttypes.h",
+        "math.h",
+        "stdio.h",
+        "stdlib.h",
+        "string.h",
+        "time.h",
+        "unistd.h",
+    ] {
+        writeln!(c, "#include <{}>", header).unwrap();
+    }
+    writeln!(c).unwrap();
+    writeln!(c, "#define STACK_SIZE {}", vm.stack.len()).unwrap();
+    writeln!(c, "#define HEAP_SIZE {}", vm.heap.memory.len()).unwrap();
+    writeln!(c, "#define W32 {}", (vm.word_size == WordSize::W32) as u8).unwrap();
+    writeln!(
+        c,
+        "#define WRAPPING 0\n#define SATURATING 1\n#define TRAPPING 2"
+    )
+    .unwrap();
+    let overflow = match vm.overflow {
+        OverflowMode::Wrapping => "WRAPPING",
+        OverflowMode::Saturating => "SATURATING",
+        OverflowMode::Trapping => "TRAPPING",
+    };
+    writeln!(c, "#define OVERFLOW_MODE {}", overflow).unwrap();
+    // The runtime, registers and labels are there whether the program uses them or not
+    for warning in &["function", "variable", "label"] {
+        writeln!(c, "#pragma GCC diagnostic ignored \"-Wunused-{}\"", warning).unwrap();
+    }
+    c += RUNTIME;
+
+    c += "\nint main(void) {\n";
+    c += "    int64_t a = 0, b = 0, c = 0, d = 0, e = 0, f = 0, sp = -1, st = 0, eq = 0, of = 0;\n";
+    c += "    double fa = 0, fb = 0, fc = 0, fd = 0, fe = 0, ff = 0;\n";
+    if computed_jumps {
+        c += "    int64_t target;\n";
+    }
+    if let Some(fuel) = vm.fuel {
+        writeln!(c, "    uint64_t fuel = UINT64_C({});", fuel).unwrap();
+    }
+    c += "\n";
+
+    for (ip, instr) in program.iter().enumerate() {
+        if targets[ip] {
+            writeln!(c, "L{}:", ip).unwrap();
+        }
+        writeln!(c, "    /* {} */", instr).unwrap();
+        if vm.fuel.is_some() {
+            writeln!(c, "    if (fuel-- == 0) out_of_fuel({});", ip).unwrap();
+        }
+        for line in instruction(*instr, ip as i64, len, vm.word_size).lines() {
+            writeln!(c, "    {}", line).unwrap();
+        }
+    }
+    // Running past the last instruction
+    writeln!(c, "L{}:", len).unwrap();
+    writeln!(c, "    fail(\"ERR_INVALID_JUMP\", {});", len).unwrap();
+
+    if computed_jumps {
+        c += "\ndispatch:\n    switch (target) {\n";
+        for ip in 0..=len {
+            writeln!(c, "    case {}: goto L{};", ip, ip).unwrap();
+        }
+        c += "    default: fail(\"ERR_INVALID_JUMP\", target);\n    }\n";
+    }
+    c += "}\n";
+    c
+}
+
+fn literal(value: i64) -> String {
+    match value {
+        i64::MIN => String::from("INT64_MIN"),
+        value => value.to_string(),
+    }
+}
+
+fn narrow(value: i64, word_size: WordSize) -> i64 {
+    match word_size {
+        WordSize::W32 => value as i32 as i64,
+        WordSize::W64 => value,
+    }
+}
+
+// Expression reading the register, ip being the current instruction
+fn read(reg: Registers, ip: i64) -> String {
+    match reg {
+        Ip => ip.to_string(),
+        reg => reg.to_string(),
+    }
+}
+
+// Statement writing the value to the register, as `Vm::write_register`
+fn write(reg: Registers, value: &str, ip: i64) -> String {
+    match reg {
+        St => format!("fail(\"ERR_READ_ONLY_REGISTER\", {});", ip),
+        Sp => format!("set_sp(&sp, &st, {}, {});", value, ip),
+        Ip => format!(
+            "if ({v} < 0) fail(\"ERR_INVALID_JUMP\", {ip});\ntarget = {v};\ngoto dispatch;",
+            v = value,
+            ip = ip
+        ),
+        reg => format!("{} = {};", reg, value),
+    }
+}
+
+// Statements running the instruction. Each one is on its own line.
+fn instruction(instr: Instructions, ip: i64, len: usize, word_size: WordSize) -> String {
+    let r = |reg: Registers| read(reg, ip);
+    let fail = |error: &str| format!("fail(\"{}\", {});", error, ip);
+    // Evaluates the value before writing it, the write may fail or jump
+    let assign = |reg: Registers, value: String| match reg {
+        Ip | Sp | St => format!(
+            "{{\nint64_t value = {};\n{}\n}}",
+            value,
+            write(reg, "value", ip)
+        ),
+        reg => write(reg, &value, ip),
+    };
+    let arithmetic = |op: &str, a: Registers, b: Registers| {
+        let value = format!("arithmetic({}, {}, {}, &of, {})", op, r(a), r(b), ip);
+        match a {
+            St => fail("ERR_READ_ONLY_REGISTER"),
+            a => assign(a, value),
+        }
+    };
+    let test = |op: &str, a: String, b: String| format!("eq = {} {} {};", a, op, b);
+
+    match instr {
+        Psh(i) => format!("push(&sp, &st, {}, {});", literal(narrow(i, word_size)), ip),
+        PshR(reg) => format!("push(&sp, &st, {}, {});", r(reg), ip),
+        Pop => format!("pop(&sp, &st, {});", ip),
+        PopR(St) => fail("ERR_READ_ONLY_REGISTER"),
+        PopR(reg) => assign(reg, format!("pop(&sp, &st, {})", ip)),
+        Dup => format!("push(&sp, &st, peek(sp, 0, {0}), {0});", ip),
+        Ovr => format!("push(&sp, &st, peek(sp, 1, {0}), {0});", ip),
+        Swp => format!(
+            "peek(sp, 1, {});\n{{\nint64_t top = stack[sp];\nstack[sp] = stack[sp - 1];\n\
+             stack[sp - 1] = top;\n}}\nst = stack[sp];",
+            ip
+        ),
+        Rot => format!(
+            "peek(sp, 2, {});\n{{\nint64_t third = stack[sp - 2];\nstack[sp - 2] = stack[sp - 1];\n\
+             stack[sp - 1] = stack[sp];\nstack[sp] = third;\n}}\nst = stack[sp];",
+            ip
+        ),
+        AddS => format!("stack_arithmetic(ADD, &sp, &st, &of, {});", ip),
+        SubS => format!("stack_arithmetic(SUB, &sp, &st, &of, {});", ip),
+        MulS => format!("stack_arithmetic(MUL, &sp, &st, &of, {});", ip),
+        DivS => format!(
+            "if (peek(sp, 0, {0}) == 0) fail(\"ERR_DIVISION_BY_ZERO\", {0});\n\
+             stack_arithmetic(DIV, &sp, &st, &of, {0});",
+            ip
+        ),
+        Add(a, b) => arithmetic("ADD", a, b),
+        Sub(a, b) => arithmetic("SUB", a, b),
+        Mul(a, b) => arithmetic("MUL", a, b),
+        Div(a, b) => format!(
+            "if ({} == 0) {}\n{}",
+            r(b),
+            fail("ERR_DIVISION_BY_ZERO"),
+            arithmetic("DIV", a, b)
+        ),
+        Mov(a, b) => assign(a, r(b)),
+        Set(reg, i) => assign(reg, literal(narrow(i, word_size))),
+        Hlt => String::from("halt(0);"),
+        HltR(reg) => format!("halt({});", r(reg)),
+        Dst => String::from(
+            "for (int64_t i = 0; i <= sp; i++) {\nprintf(\"[%\" PRId64 \"]\\n\", stack[i]);\n}",
+        ),
+        Drg(reg) => format!("printf(\"[%\" PRId64 \"]\\n\", (int64_t){});", r(reg)),
+        Dmp => format!(
+            "{{\nint64_t regs[] = {{a, b, c, d, e, f, {}, sp, st, eq, of}};\n\
+             double fregs[] = {{fa, fb, fc, fd, fe, ff}};\ndump(regs, fregs);\n}}",
+            ip
+        ),
+        Prt(reg) => format!("print_char({});", r(reg)),
+        Tee(a, b) => test("==", r(a), r(b)),
+        Tne(a, b) => test("!=", r(a), r(b)),
+        Tll(a, b) => test("<", r(a), r(b)),
+        Tmm(a, b) => test(">", r(a), r(b)),
+        Tel(a, b) => test("<=", r(a), r(b)),
+        Tem(a, b) => test(">=", r(a), r(b)),
+        Jmp(target) if target < 0 => format!("if (eq == 1) {}", fail("ERR_INVALID_JUMP")),
+        Jmp(target) if target as usize > len => {
+            format!("if (eq == 1) fail(\"ERR_INVALID_JUMP\", {});", target)
+        }
+        Jmp(target) => format!("if (eq == 1) goto L{};", target),
+        Fld(reg, bits) => format!("{} = from_bits(UINT64_C({:#x}));", reg, bits),
+        Fmv(a, b) => format!("{} = {};", a, b),
+        Fad(a, b) => format!("{0} = {0} + {1};", a, b),
+        Fsb(a, b) => format!("{0} = {0} - {1};", a, b),
+        Fml(a, b) => format!("{0} = {0} * {1};", a, b),
+        Fdv(a, b) => format!("{0} = {0} / {1};", a, b),
+        Fee(a, b) => test("==", a.to_string(), b.to_string()),
+        Fne(a, b) => test("!=", a.to_string(), b.to_string()),
+        Fll(a, b) => test("<", a.to_string(), b.to_string()),
+        Fmm(a, b) => test(">", a.to_string(), b.to_string()),
+        Fel(a, b) => test("<=", a.to_string(), b.to_string()),
+        Fem(a, b) => test(">=", a.to_string(), b.to_string()),
+        Itf(f, reg) => format!("{} = (double){};", f, r(reg)),
+        Fti(reg, f) => assign(reg, format!("float_to_int({})", f)),
+        Fpr(f) => format!("putchar('[');\nprint_float({});\nputs(\"]\");", f),
+        Alc(_, St) => fail("ERR_READ_ONLY_REGISTER"),
+        Alc(size, dest) => assign(dest, format!("alloc_words({}, {})", r(size), ip)),
+        Fre(ptr) => format!("free_words({}, {});", r(ptr), ip),
+        Lod(dest, ptr) => assign(dest, format!("memory[check({}, {})]", r(ptr), ip)),
+        Sto(ptr, src) => format!("memory[check({}, {})] = {};", r(ptr), ip, r(src)),
+        Sys(0) => String::from("halt(a);"),
+        Sys(1) => format!("a = sys_write(a, b, c, {});", ip),
+        Sys(2) => format!("a = sys_read(a, b, c, {});", ip),
+        Sys(3) => String::from("sys_time(&a, &b);"),
+        Sys(n) => format!("unknown_syscall({}, {});", n, ip),
+        Snp => String::from("/* snp : snapshots are only taken by the VM */"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generates() {
+        let vm = Vm::new();
+        let c = compile(&[Set(A, 1), Tee(A, B), Jmp(0), Mov(Sp, A), Hlt], &vm);
+        assert!(c.contains("#define STACK_SIZE 255\n"));
+        assert!(c.contains("#define W32 1\n"));
+        assert!(c.contains("L0:\n    /* set a 1 */\n    a = 1;\n"));
+        assert!(c.contains("    if (eq == 1) goto L0;\n"));
+        assert!(c.contains("    set_sp(&sp, &st, value, 3);\n"));
+        assert!(c.contains("L5:\n    fail(\"ERR_INVALID_JUMP\", 5);\n"));
+        assert!(!c.contains("dispatch"));
+
+        let c = compile(&[Psh(2), PopR(Ip), Hlt], &vm);
+        assert!(c.contains("    int64_t value = pop(&sp, &st, 1);\n"));
+        assert!(c.contains("    goto dispatch;\n"));
+        assert!(c.contains("    case 2: goto L2;\n"));
+    }
+}
@@ -0,0 +1,160 @@
+use crate::analysis::writes;
+use crate::{Instructions, Instructions::*, Registers::*};
+
+/// A straight sequence of instructions, only entered from its first one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,           // Exclusive
+    pub taken: Option<usize>, // Block jumped to by the last instruction
+    pub next: Option<usize>,  // Block reached by falling through
+    pub invalid_jump: bool,   // The last instruction jumps outside of the program
+    pub computed_jump: bool,  // The last instruction writes to ip
+}
+
+/// Control flow graph of a program. Blocks are sorted by their first instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+}
+
+impl Cfg {
+    /// Splits the program at jump targets and after jumps, writes to ip and halts.
+    pub fn new(program: &[Instructions]) -> Cfg {
+        let mut leaders = vec![false; program.len() + 1];
+        leaders[0] = true;
+        for (ip, instr) in program.iter().enumerate() {
+            if let Jmp(target) = *instr {
+                if target >= 0 && (target as usize) < program.len() {
+                    leaders[target as usize] = true;
+                }
+            }
+            if ends_block(*instr) {
+                leaders[ip + 1] = true;
+            }
+        }
+
+        let starts = (0..program.len())
+            .filter(|&ip| leaders[ip])
+            .collect::<Vec<usize>>();
+        let block_at = |ip: usize| starts.binary_search(&ip).ok();
+
+        let mut blocks = vec![];
+        for (i, &start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(program.len());
+            let last = program[end - 1];
+            let falls = !matches!(last, Hlt | HltR(_)) && !writes(last).contains(&Ip);
+            let (taken, invalid_jump) = match last {
+                Jmp(target) if target >= 0 && (target as usize) < program.len() => {
+                    (block_at(target as usize), false)
+                }
+                Jmp(_) => (None, true),
+                _ => (None, false),
+            };
+            blocks.push(Block {
+                start,
+                end,
+                taken,
+                next: if falls { block_at(end) } else { None },
+                invalid_jump,
+                computed_jump: writes(last).contains(&Ip),
+            });
+        }
+        Cfg { blocks }
+    }
+
+    /// Index of the block containing the instruction.
+    pub fn block_of(&self, ip: usize) -> usize {
+        match self.blocks.binary_search_by_key(&ip, |b| b.start) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        }
+    }
+
+    /// Graphviz graph of the blocks, with their instructions. Taken jumps are solid edges and
+    /// fall throughs dashed ones.
+    pub fn to_dot(&self, program: &[Instructions]) -> String {
+        let mut dot = String::from("digraph wlvm {\n");
+        dot += "    node [shape=box, fontname=\"monospace\"];\n";
+        if !self.blocks.is_empty() {
+            dot += "    start [shape=point];\n";
+            dot += "    start -> b0;\n";
+        }
+        if self.blocks.iter().any(|b| b.invalid_jump) {
+            dot += "    invalid [label=\"outside of the program\", color=red];\n";
+        }
+        if self.blocks.iter().any(|b| b.computed_jump) {
+            dot += "    computed [label=\"unknown (write to ip)\", shape=diamond];\n";
+        }
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut label = format!("block {} : {}..{}\\l", i, block.start, block.end - 1);
+            for (ip, instr) in program[block.start..block.end].iter().enumerate() {
+                label += &format!("{:>4} | {}\\l", block.start + ip, instr);
+            }
+            dot += &format!("    b{} [label=\"{}\"];\n", i, label);
+
+            if let Some(taken) = block.taken {
+                dot += &format!("    b{} -> b{} [label=\"jmp\"];\n", i, taken);
+            }
+            if block.invalid_jump {
+                dot += &format!("    b{} -> invalid [label=\"jmp\", color=red];\n", i);
+            }
+            if block.computed_jump {
+                dot += &format!("    b{} -> computed [style=dotted];\n", i);
+            }
+            if let Some(next) = block.next {
+                dot += &format!("    b{} -> b{} [style=dashed];\n", i, next);
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+}
+
+fn ends_block(instr: Instructions) -> bool {
+    matches!(instr, Jmp(_) | Hlt | HltR(_)) || writes(instr).contains(&Ip)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blocks() {
+        // 0: psh 1, 1: pop a, 2: tee a b, 3: jmp 1, 4: drg a, 5: hlt
+        let program = [Psh(1), PopR(A), Tee(A, B), Jmp(1), Drg(A), Hlt];
+        let cfg = Cfg::new(&program);
+
+        let spans = cfg
+            .blocks
+            .iter()
+            .map(|b| (b.start, b.end, b.taken, b.next))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            vec![
+                (0, 1, None, Some(1)),
+                (1, 4, Some(1), Some(2)),
+                (4, 6, None, None)
+            ]
+        );
+        assert_eq!(cfg.block_of(3), 1);
+        assert_eq!(cfg.block_of(5), 2);
+    }
+
+    #[test]
+    fn dot() {
+        let program = [Psh(2), PopR(Ip), Jmp(9), Hlt];
+        let dot = Cfg::new(&program).to_dot(&program);
+
+        assert!(dot.starts_with("digraph wlvm {\n"));
+        assert!(
+            dot.contains("    b0 [label=\"block 0 : 0..1\\l   0 | psh 2\\l   1 | pop ip\\l\"];\n")
+        );
+        assert!(dot.contains("    b0 -> computed [style=dotted];\n"));
+        assert!(dot.contains("    b1 -> invalid [label=\"jmp\", color=red];\n"));
+        assert!(dot.contains("    b1 -> b2 [style=dashed];\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+}
@@ -0,0 +1,230 @@
+//! Line and branch coverage of programs, in the lcov format read by `genhtml` and most coverage
+//! viewers. Each `jmp` is a branch block numbered by its instruction, branch 0 being the jump
+//! and branch 1 the fall through.
+
+use crate::profile::Profile;
+use crate::{Instructions, Instructions::*};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Coverage of one source file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileCoverage {
+    pub lines: BTreeMap<usize, u64>, // Times each line with an instruction ran
+    // Times each branch was taken, by line, block and branch. None if never evaluated.
+    pub branches: BTreeMap<(usize, usize, usize), Option<u64>>,
+}
+
+/// Coverage of source files, summed over the runs added to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+    pub files: BTreeMap<String, FileCoverage>,
+}
+
+fn add(total: &mut Option<u64>, count: Option<u64>) {
+    if let Some(count) = count {
+        *total = Some(total.unwrap_or(0) + count);
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Adds a run of the program of `file`, profiled in `profile`. `lines` gives the source line
+    /// of each instruction, instructions without one being left out.
+    pub fn add(
+        &mut self,
+        file: &str,
+        program: &[Instructions],
+        lines: &[Option<usize>],
+        profile: &Profile,
+    ) {
+        let coverage = self.files.entry(file.to_string()).or_default();
+        for (ip, instr) in program.iter().enumerate() {
+            let line = match lines.get(ip).copied().flatten() {
+                Some(line) => line,
+                None => continue,
+            };
+            let count = profile.counts[ip];
+            *coverage.lines.entry(line).or_insert(0) += count;
+            if let Jmp(_) = instr {
+                let run = Some(count).filter(|&count| count > 0);
+                let taken = profile.taken[ip];
+                add(
+                    coverage.branches.entry((line, ip, 0)).or_insert(None),
+                    run.map(|_| taken),
+                );
+                add(
+                    coverage.branches.entry((line, ip, 1)).or_insert(None),
+                    run.map(|count| count - taken),
+                );
+            }
+        }
+    }
+
+    /// Adds the runs of another coverage.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (file, other) in &other.files {
+            let coverage = self.files.entry(file.clone()).or_default();
+            for (line, count) in &other.lines {
+                *coverage.lines.entry(*line).or_insert(0) += count;
+            }
+            for (branch, count) in &other.branches {
+                add(coverage.branches.entry(*branch).or_insert(None), *count);
+            }
+        }
+    }
+
+    /// Reads an lcov report, such as one written by `to_lcov`. Functions and summaries are
+    /// ignored, the ones written by `to_lcov` being computed from the lines and branches.
+    pub fn parse(lcov: &str) -> Result<Coverage, String> {
+        let mut coverage = Coverage::new();
+        let mut file: Option<FileCoverage> = None;
+        let mut name = String::new();
+        for (i, line) in lcov.lines().enumerate() {
+            let invalid = || format!("invalid lcov report at line {}", i + 1);
+            let numbers = |fields: &str, n: usize| -> Result<Vec<Option<u64>>, String> {
+                let numbers = fields
+                    .split(',')
+                    .take(n)
+                    .map(|field| match field {
+                        "-" => Ok(None),
+                        field => field.parse().map(Some).map_err(|_| invalid()),
+                    })
+                    .collect::<Result<Vec<Option<u64>>, String>>()?;
+                match numbers.len() == n && numbers[..n - 1].iter().all(Option::is_some) {
+                    true => Ok(numbers),
+                    false => Err(invalid()),
+                }
+            };
+            let line = line.trim();
+            if let Some(path) = line.strip_prefix("SF:") {
+                if file.is_some() {
+                    return Err(invalid());
+                }
+                file = Some(FileCoverage::default());
+                name = path.to_string();
+            } else if line == "end_of_record" {
+                let ended = file.take().ok_or_else(invalid)?;
+                let mut single = Coverage::new();
+                single.files.insert(name.clone(), ended);
+                coverage.merge(&single);
+            } else if let Some(fields) = line.strip_prefix("DA:") {
+                let numbers = numbers(fields, 2)?;
+                let count = numbers[1].ok_or_else(invalid)?;
+                let lines = &mut file.as_mut().ok_or_else(invalid)?.lines;
+                *lines.entry(numbers[0].unwrap() as usize).or_insert(0) += count;
+            } else if let Some(fields) = line.strip_prefix("BRDA:") {
+                let numbers = numbers(fields, 4)?;
+                let branch = (
+                    numbers[0].unwrap() as usize,
+                    numbers[1].unwrap() as usize,
+                    numbers[2].unwrap() as usize,
+                );
+                let branches = &mut file.as_mut().ok_or_else(invalid)?.branches;
+                add(branches.entry(branch).or_insert(None), numbers[3]);
+            }
+        }
+        match file {
+            Some(_) => Err(String::from("invalid lcov report: missing end_of_record")),
+            None => Ok(coverage),
+        }
+    }
+
+    /// The lcov report, with a record per file.
+    pub fn to_lcov(&self) -> String {
+        let mut lcov = String::new();
+        for (file, coverage) in &self.files {
+            lcov += "TN:\n";
+            let _ = writeln!(lcov, "SF:{}", file);
+            for (&(line, block, branch), count) in &coverage.branches {
+                let taken = count.map_or(String::from("-"), |count| count.to_string());
+                let _ = writeln!(lcov, "BRDA:{},{},{},{}", line, block, branch, taken);
+            }
+            let branches = coverage.branches.values();
+            let _ = writeln!(lcov, "BRF:{}", branches.len());
+            let hit = branches.filter(|count| count.unwrap_or(0) > 0).count();
+            let _ = writeln!(lcov, "BRH:{}", hit);
+            for (line, count) in &coverage.lines {
+                let _ = writeln!(lcov, "DA:{},{}", line, count);
+            }
+            let _ = writeln!(lcov, "LF:{}", coverage.lines.len());
+            let hit = coverage.lines.values().filter(|&&count| count > 0).count();
+            let _ = writeln!(lcov, "LH:{}", hit);
+            lcov += "end_of_record\n";
+        }
+        lcov
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engine::Decoded;
+    use crate::parser::{parse_with_lines, ParseOptions};
+    use crate::Vm;
+
+    // Prints 2 then 1, or only 1 if a, set by the first line, is 0
+    const PROGRAM: &str = "set b 0\ntee a b\njmp 6\nset c 2\ndrg c\n; one\nset c 1\ndrg c";
+
+    fn covered(set_a: &str) -> Coverage {
+        let source = format!("{}\n{}", set_a, PROGRAM);
+        let (program, lines) = parse_with_lines(&source, ParseOptions::default()).unwrap();
+        let mut profile = Profile::new(&program);
+        let mut vm = Vm::new();
+        vm.output = Box::new(std::io::sink());
+        vm.run_profiled(&Decoded::new(&program), &mut profile)
+            .unwrap();
+        let mut coverage = Coverage::new();
+        coverage.add("test.vm", &program, &lines, &profile);
+        coverage
+    }
+
+    #[test]
+    fn lcov() {
+        let coverage = covered("set a 0");
+        assert_eq!(
+            coverage.to_lcov(),
+            "TN:\nSF:test.vm\n\
+             BRDA:4,3,0,1\nBRDA:4,3,1,0\nBRF:2\nBRH:1\n\
+             DA:1,1\nDA:2,1\nDA:3,1\nDA:4,1\nDA:5,0\nDA:6,0\nDA:8,1\nDA:9,1\n\
+             LF:8\nLH:6\nend_of_record\n"
+        );
+        assert_eq!(Coverage::parse(&coverage.to_lcov()), Ok(coverage));
+    }
+
+    #[test]
+    fn merges() {
+        let mut coverage = covered("set a 0");
+        coverage.merge(&covered("set a 1"));
+        let file = &coverage.files["test.vm"];
+        assert_eq!(file.lines[&5], 1);
+        assert_eq!(file.lines[&9], 2);
+        assert_eq!(file.branches[&(4, 3, 0)], Some(1));
+        assert_eq!(file.branches[&(4, 3, 1)], Some(1));
+
+        // Branches never evaluated
+        let never = "TN:\nSF:test.vm\nBRDA:4,3,0,-\nBRDA:4,3,1,-\nDA:4,0\nend_of_record\n";
+        let mut coverage = Coverage::parse(never).unwrap();
+        assert_eq!(coverage.files["test.vm"].branches[&(4, 3, 0)], None);
+        assert!(coverage.to_lcov().contains("BRDA:4,3,1,-\nBRF:2\nBRH:0\n"));
+        coverage.merge(&covered("set a 1"));
+        assert_eq!(coverage.files["test.vm"].branches[&(4, 3, 0)], Some(0));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            Coverage::parse("SF:a.vm\nDA:1\nend_of_record"),
+            Err(String::from("invalid lcov report at line 2"))
+        );
+        assert_eq!(
+            Coverage::parse("DA:1,1"),
+            Err(String::from("invalid lcov report at line 1"))
+        );
+        assert!(Coverage::parse("SF:a.vm\nDA:1,1").is_err());
+        assert_eq!(Coverage::parse(""), Ok(Coverage::new()));
+    }
+}
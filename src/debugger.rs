@@ -0,0 +1,456 @@
+use crate::analysis::writes;
+use crate::heap::Heap;
+use crate::parser::parse_register;
+use crate::{FloatRegisters, Instructions, Instructions::*, Registers, Registers::*, Vm, VmError};
+use std::collections::{BTreeSet, VecDeque};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Write};
+
+/// Default size of the undo log, in bytes.
+pub const HISTORY_LIMIT: usize = 64 << 20;
+
+const HELP: &str = "\
+step [n]      (s)  : Runs n instructions (default: 1)
+continue      (c)  : Runs until a breakpoint or the end of the program
+rstep [n]     (rs) : Undoes n instructions (default: 1)
+rcontinue     (rc) : Runs backwards until a breakpoint or the oldest step recorded
+rwrite <reg>  (rw) : Runs backwards until before the last write to the register
+goto <step>   (g)  : Runs forwards or backwards to the step
+break <n>     (b)  : Toggles a breakpoint on instruction n
+registers     (r)  : Prints the registers
+stack              : Prints the live stack
+list          (l)  : Prints the instructions around ip
+history            : Prints the current step and the oldest one recorded
+quit          (q)  : Stops debugging
+An empty line repeats the last command. Running backwards doesn't take back the output";
+
+// State before a step, enough to undo it
+struct Undo {
+    regs: [i64; NumOfRegisters as usize],
+    fregs: [f64; FloatRegisters::NumOfFloatRegisters as usize],
+    running: bool,
+    exit_code: i64,
+    fuel: Option<u64>,
+    written: u64,
+    stack: Vec<(usize, i64)>, // Slots the step may write, with their value
+    memory: Memory,
+}
+
+enum Memory {
+    Unchanged,
+    Word(usize, i64),
+    Whole(Heap), // Saved by the instructions allocating or freeing, and by syscalls
+}
+
+impl Undo {
+    fn bytes(&self) -> usize {
+        let heap = match &self.memory {
+            Memory::Whole(heap) => heap.bytes(),
+            _ => 0,
+        };
+        std::mem::size_of::<Undo>() + self.stack.len() * std::mem::size_of::<(usize, i64)>() + heap
+    }
+}
+
+/// Interactive debugger reading its commands from `input`. The program output still goes to
+/// stdout.
+pub struct Debugger<'a> {
+    pub vm: &'a mut Vm,
+    pub history_limit: usize, // Bytes the undo log may use, the oldest steps being forgotten first
+    program: &'a [Instructions],
+    breakpoints: BTreeSet<i64>,
+    outcome: Option<Result<(), VmError>>, // Set once the program stopped
+    steps: u64,                           // Number of steps run, including the one that failed
+    history: VecDeque<Undo>,              // Undo entries of the last steps, the latest last
+    history_bytes: usize,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(vm: &'a mut Vm, program: &'a [Instructions]) -> Debugger<'a> {
+        Debugger {
+            vm,
+            history_limit: HISTORY_LIMIT,
+            program,
+            breakpoints: BTreeSet::new(),
+            outcome: None,
+            steps: 0,
+            history: VecDeque::new(),
+            history_bytes: 0,
+        }
+    }
+
+    /// Runs the session until `quit` or the end of the input, and returns how the program
+    /// stopped, `Ok` if it is still running.
+    pub fn session<R: BufRead, W: Write>(
+        &mut self,
+        input: R,
+        mut out: W,
+    ) -> io::Result<Result<(), VmError>> {
+        let mut last = String::from("step");
+        self.current(&mut out)?;
+        write!(out, "(wlvm) ")?;
+        out.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            let line = match line.trim() {
+                "" => last.clone(),
+                l => l.to_string(),
+            };
+            let words = line.split_whitespace().collect::<Vec<&str>>();
+
+            match words[0] {
+                "step" | "s" => match words.get(1).map(|n| n.parse::<usize>()) {
+                    None => self.step(1, true, &mut out)?,
+                    Some(Ok(n)) => self.step(n, true, &mut out)?,
+                    Some(Err(_)) => writeln!(out, "Invalid count `{}`", words[1])?,
+                },
+                "continue" | "c" => self.step(usize::MAX, true, &mut out)?,
+                "rstep" | "rs" => match words.get(1).map(|n| n.parse::<usize>()) {
+                    None => self.back(1, true, None, &mut out)?,
+                    Some(Ok(n)) => self.back(n, true, None, &mut out)?,
+                    Some(Err(_)) => writeln!(out, "Invalid count `{}`", words[1])?,
+                },
+                "rcontinue" | "rc" => self.back(usize::MAX, true, None, &mut out)?,
+                "rwrite" | "rw" => match words.get(1).and_then(|r| parse_register(r)) {
+                    Some(reg) => self.back(usize::MAX, true, Some(reg), &mut out)?,
+                    None => writeln!(out, "Usage : rwrite <register>")?,
+                },
+                "goto" | "g" => match words.get(1).map(|n| n.parse::<u64>()) {
+                    Some(Ok(n)) => self.goto(n, &mut out)?,
+                    _ => writeln!(out, "Usage : goto <step>")?,
+                },
+                "history" => writeln!(
+                    out,
+                    "At step {}, the oldest step recorded is {} ({} bytes)",
+                    self.steps,
+                    self.oldest(),
+                    self.history_bytes
+                )?,
+                "break" | "b" => match words.get(1).map(|n| n.parse::<i64>()) {
+                    Some(Ok(n)) if n >= 0 && (n as usize) < self.program.len() => {
+                        if self.breakpoints.remove(&n) {
+                            writeln!(out, "Removed breakpoint at {}", n)?;
+                        } else {
+                            self.breakpoints.insert(n);
+                            writeln!(out, "Breakpoint set at {}", n)?;
+                        }
+                    }
+                    _ => writeln!(out, "Usage : break <instruction>")?,
+                },
+                "registers" | "r" => {
+                    for i in 0..NumOfRegisters as usize {
+                        let reg = Registers::from_index(i).unwrap();
+                        writeln!(out, "{:>2} = {}", reg, self.vm.regs[i])?;
+                    }
+                }
+                "stack" => writeln!(out, "{:?}", self.vm.live_stack())?,
+                "list" | "l" => self.list(&mut out)?,
+                "quit" | "q" => break,
+                "help" | "h" => writeln!(out, "{}", HELP)?,
+                cmd => writeln!(out, "Unknown command `{}`, try `help`", cmd)?,
+            }
+            last = line;
+            write!(out, "(wlvm) ")?;
+            out.flush()?;
+        }
+        writeln!(out)?;
+
+        Ok(self.outcome.unwrap_or(Ok(())))
+    }
+
+    // Runs up to `count` instructions, stopping before breakpoints if `breakpoints`
+    fn step<W: Write>(&mut self, count: usize, breakpoints: bool, out: &mut W) -> io::Result<()> {
+        if let Some(outcome) = self.outcome {
+            match outcome {
+                Ok(()) => writeln!(out, "The program is not running")?,
+                Err(e) => writeln!(out, "The program stopped with {}", e)?,
+            }
+            return Ok(());
+        }
+
+        for i in 0..count {
+            let ip = self.vm.regs[Ip as usize];
+            if breakpoints && i > 0 && self.breakpoints.contains(&ip) {
+                writeln!(out, "Breakpoint at {}", ip)?;
+                break;
+            }
+            self.record();
+            self.steps += 1;
+            if let Err(e) = self.vm.step(self.program) {
+                writeln!(out, "Error: {}", e)?;
+                self.outcome = Some(Err(e));
+                return Ok(());
+            }
+            if !self.vm.running {
+                writeln!(out, "The program exited with code {}", self.vm.exit_code)?;
+                self.outcome = Some(Ok(()));
+                return Ok(());
+            }
+        }
+        self.current(out)
+    }
+
+    // Undoes up to `count` steps, stopping at breakpoints if `breakpoints`, or with `reg` once
+    // the step that wrote it was undone
+    fn back<W: Write>(
+        &mut self,
+        count: usize,
+        breakpoints: bool,
+        reg: Option<Registers>,
+        out: &mut W,
+    ) -> io::Result<()> {
+        for i in 0..count {
+            let undo = match self.history.pop_back() {
+                Some(undo) => undo,
+                None if self.steps == 0 => {
+                    writeln!(out, "At the start of the program")?;
+                    break;
+                }
+                None => {
+                    writeln!(out, "Step {} is the oldest recorded", self.steps)?;
+                    break;
+                }
+            };
+            let failed = matches!(self.outcome, Some(Err(_)));
+            let after = self.vm.regs;
+            self.history_bytes -= undo.bytes();
+            self.restore(undo);
+            self.steps -= 1;
+            self.outcome = None;
+
+            let ip = self.vm.regs[Ip as usize];
+            if let Some(reg) = reg {
+                let wrote = !failed
+                    && self
+                        .instruction(ip)
+                        .is_some_and(|i| writes(i).contains(&reg));
+                if wrote || after[reg as usize] != self.vm.regs[reg as usize] {
+                    writeln!(out, "{} written at step {}", reg, self.steps)?;
+                    break;
+                }
+            } else if breakpoints && i + 1 < count && self.breakpoints.contains(&ip) {
+                writeln!(out, "Breakpoint at {}", ip)?;
+                break;
+            }
+        }
+        self.current(out)
+    }
+
+    // Runs forwards or backwards to the step
+    fn goto<W: Write>(&mut self, step: u64, out: &mut W) -> io::Result<()> {
+        if step >= self.steps {
+            let count = usize::try_from(step - self.steps).unwrap_or(usize::MAX);
+            return match count {
+                0 => self.current(out),
+                count => self.step(count, false, out),
+            };
+        }
+        if step < self.oldest() {
+            return writeln!(
+                out,
+                "Step {} is not recorded anymore, the oldest is {}",
+                step,
+                self.oldest()
+            );
+        }
+        self.back((self.steps - step) as usize, false, None, out)
+    }
+
+    fn oldest(&self) -> u64 {
+        self.steps - self.history.len() as u64
+    }
+
+    fn instruction(&self, ip: i64) -> Option<Instructions> {
+        self.program.get(ip as usize).filter(|_| ip >= 0).copied()
+    }
+
+    // Saves what the next step may change, forgetting the oldest steps beyond the limit
+    fn record(&mut self) {
+        if self.history_limit == 0 {
+            return;
+        }
+        let vm = &*self.vm;
+        let instr = self.instruction(vm.regs[Ip as usize]);
+        let sp = vm.regs[Sp as usize];
+        // Stack instructions write at most the two slots below the top and the one above it
+        let slots = match instr {
+            Some(Sys(_)) => 0..vm.stack.len(),
+            _ => (sp - 2).max(0) as usize..((sp + 2).max(0) as usize).min(vm.stack.len()),
+        };
+        let memory = match instr {
+            Some(Sto(ptr, _)) => {
+                let addr = vm.regs[ptr as usize];
+                match vm.heap.memory.get(addr as usize) {
+                    Some(&word) if addr >= 0 => Memory::Word(addr as usize, word),
+                    _ => Memory::Unchanged,
+                }
+            }
+            Some(Alc(..)) | Some(Fre(_)) | Some(Sys(_)) => Memory::Whole(vm.heap.clone()),
+            _ => Memory::Unchanged,
+        };
+        let undo = Undo {
+            regs: vm.regs,
+            fregs: vm.fregs,
+            running: vm.running,
+            exit_code: vm.exit_code,
+            fuel: vm.fuel,
+            written: vm.written,
+            stack: slots.map(|i| (i, vm.stack[i])).collect(),
+            memory,
+        };
+
+        self.history_bytes += undo.bytes();
+        self.history.push_back(undo);
+        while self.history_bytes > self.history_limit {
+            let oldest = self.history.pop_front().unwrap();
+            self.history_bytes -= oldest.bytes();
+        }
+    }
+
+    fn restore(&mut self, undo: Undo) {
+        let vm = &mut *self.vm;
+        vm.regs = undo.regs;
+        vm.fregs = undo.fregs;
+        vm.running = undo.running;
+        vm.exit_code = undo.exit_code;
+        vm.fuel = undo.fuel;
+        vm.written = undo.written;
+        for (i, value) in undo.stack {
+            vm.stack[i] = value;
+        }
+        match undo.memory {
+            Memory::Unchanged => {}
+            Memory::Word(addr, word) => vm.heap.memory[addr] = word,
+            Memory::Whole(heap) => vm.heap = heap,
+        }
+    }
+
+    // Prints the next instruction to run
+    fn current<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let ip = self.vm.regs[Ip as usize];
+        match self.program.get(ip as usize) {
+            Some(instr) if ip >= 0 => writeln!(out, "{:>4} | {}", ip, instr),
+            _ => writeln!(out, "{:>4} | <outside of the program>", ip),
+        }
+    }
+
+    fn list<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let ip = self.vm.regs[Ip as usize];
+        for (i, instr) in self.program.iter().enumerate() {
+            let i = i as i64;
+            if (i - ip).abs() > 5 {
+                continue;
+            }
+            let marker = match (i == ip, self.breakpoints.contains(&i)) {
+                (true, _) => "->",
+                (false, true) => " *",
+                (false, false) => "  ",
+            };
+            writeln!(out, "{} {:>4} | {}", marker, i, instr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn session(program: &[Instructions], commands: &str) -> (Vm, Result<(), VmError>, String) {
+        let mut vm = Vm::new();
+        let mut out = vec![];
+        let outcome = Debugger::new(&mut vm, program)
+            .session(commands.as_bytes(), &mut out)
+            .unwrap();
+        (vm, outcome, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn steps_and_breakpoints() {
+        let program = [Psh(1), Psh(2), Psh(3), Psh(4), Hlt];
+
+        let (vm, outcome, out) = session(&program, "step\n\nb 3\nc\nstack\nq\n");
+        assert_eq!(outcome, Ok(()));
+        assert_eq!(vm.live_stack(), &[1, 2, 3]);
+        assert!(out.contains("Breakpoint at 3"));
+        assert!(out.contains("   3 | psh 4"));
+
+        let (vm, outcome, out) = session(&program, "c\ns\n");
+        assert_eq!(outcome, Ok(()));
+        assert!(!vm.running);
+        assert!(out.contains("The program exited with code 0"));
+        assert!(out.contains("The program is not running"));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let (_, outcome, out) = session(&[Pop, Hlt], "s 5\nr\n");
+        assert_eq!(outcome, Err(VmError::StackUnderflow(0)));
+        assert!(out.contains("Error: ERR_STACK_UNDERFLOW at instruction 0"));
+        assert!(out.contains("sp = -1"));
+    }
+
+    #[test]
+    fn steps_backwards() {
+        let program = [Psh(1), Psh(2), AddS, Set(A, 5), PopR(B), Set(A, 7), Hlt];
+
+        let (vm, outcome, out) = session(&program, "s 6\nrw a\nr\nrs 2\nstack\n");
+        assert_eq!(outcome, Ok(()));
+        assert!(out.contains("a written at step 5\n   5 | set a 7"));
+        assert!(out.contains(" a = 5\n"));
+        assert_eq!(vm.regs[Ip as usize], 3);
+        assert_eq!(vm.live_stack(), &[3]);
+
+        let (vm, _, out) = session(&program, "g 1\nstack\ng 7\nrs\nrc\nhistory\n");
+        assert!(out.contains("[1]\n"));
+        assert!(out.contains("The program exited with code 0"));
+        assert!(out.contains("At the start of the program"));
+        assert!(out.contains("At step 0, the oldest step recorded is 0 (0 bytes)"));
+        assert_eq!(vm.live_stack(), &[] as &[i64]);
+        assert!(vm.running);
+
+        let (vm, outcome, _) = session(&program, "b 4\nc\nc\nrc\n");
+        assert_eq!(outcome, Ok(()));
+        assert_eq!(vm.regs[Ip as usize], 4);
+
+        // goto goes through breakpoints both ways
+        let (vm, _, out) = session(&program, "s 5\nb 3\ng 1\nstack\ng 5\n");
+        assert!(!out.contains("Breakpoint at"));
+        assert!(out.contains("   1 | psh 2\n(wlvm) [1]\n"));
+        assert_eq!(vm.regs[Ip as usize], 5);
+    }
+
+    #[test]
+    fn undoes_errors_and_memory() {
+        let (vm, outcome, out) = session(&[Set(A, 0), Div(A, A), Hlt], "s 5\nrs\nrw a\n");
+        assert_eq!(outcome, Ok(()));
+        assert!(out.contains("Error: ERR_DIVISION_BY_ZERO"));
+        assert!(out.contains("a written at step 0"));
+        assert_eq!(vm.regs[Ip as usize], 0);
+
+        let program = [Set(A, 2), Alc(A, B), Set(C, 9), Sto(B, C), Hlt];
+        let (vm, _, _) = session(&program, "s 4\nrs\n");
+        assert_eq!(vm.heap.memory[1], 0);
+        assert_eq!(vm.heap.leaks(), [(1, 2)]);
+        let (vm, _, _) = session(&program, "s 4\ng 1\n");
+        assert!(vm.heap.leaks().is_empty());
+    }
+
+    #[test]
+    fn bounded_history() {
+        let program = [Set(A, 1), Set(A, 2), Set(A, 3), Set(A, 4), Set(A, 5), Hlt];
+        let mut vm = Vm::new();
+        let mut debugger = Debugger::new(&mut vm, &program);
+        debugger.history_limit = 2 * (std::mem::size_of::<Undo>() + 16);
+        let mut out = vec![];
+        let outcome = debugger
+            .session("s 5\nrs 5\ng 1\ng 4\n".as_bytes(), &mut out)
+            .unwrap();
+        assert_eq!(outcome, Ok(()));
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Step 3 is the oldest recorded"));
+        assert!(out.contains("Step 1 is not recorded anymore, the oldest is 3"));
+        assert_eq!(vm.regs[A as usize], 4);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{FloatRegisters, Instructions, Instructions::*, Registers};
+use std::fmt;
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&format!("{:?}", self).to_lowercase())
+    }
+}
+
+impl fmt::Display for FloatRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&format!("{:?}", self).to_lowercase())
+    }
+}
+
+/// Instructions are displayed with the syntax accepted by the parser.
+impl fmt::Display for Instructions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Psh(i) => write!(f, "psh {}", i),
+            PshR(r) => write!(f, "psh {}", r),
+            Pop => write!(f, "pop"),
+            PopR(r) => write!(f, "pop {}", r),
+            Dup => write!(f, "dup"),
+            Swp => write!(f, "swp"),
+            Ovr => write!(f, "ovr"),
+            Rot => write!(f, "rot"),
+            AddS => write!(f, "add"),
+            SubS => write!(f, "sub"),
+            MulS => write!(f, "mul"),
+            DivS => write!(f, "div"),
+            Add(a, b) => write!(f, "add {} {}", a, b),
+            Sub(a, b) => write!(f, "sub {} {}", a, b),
+            Mul(a, b) => write!(f, "mul {} {}", a, b),
+            Div(a, b) => write!(f, "div {} {}", a, b),
+            Mov(a, b) => write!(f, "mov {} {}", a, b),
+            Set(r, i) => write!(f, "set {} {}", r, i),
+            Hlt => write!(f, "hlt"),
+            HltR(r) => write!(f, "hlt {}", r),
+            Dst => write!(f, "dst"),
+            Drg(r) => write!(f, "drg {}", r),
+            Dmp => write!(f, "dmp"),
+            Prt(r) => write!(f, "prt {}", r),
+            Tee(a, b) => write!(f, "tee {} {}", a, b),
+            Tne(a, b) => write!(f, "tne {} {}", a, b),
+            Tll(a, b) => write!(f, "tll {} {}", a, b),
+            Tmm(a, b) => write!(f, "tmm {} {}", a, b),
+            Tel(a, b) => write!(f, "tel {} {}", a, b),
+            Tem(a, b) => write!(f, "tem {} {}", a, b),
+            Jmp(i) => write!(f, "jmp {}", i),
+            Fld(r, bits) => write!(f, "fld {} {:?}", r, f64::from_bits(bits)),
+            Fmv(a, b) => write!(f, "fmv {} {}", a, b),
+            Fad(a, b) => write!(f, "fad {} {}", a, b),
+            Fsb(a, b) => write!(f, "fsb {} {}", a, b),
+            Fml(a, b) => write!(f, "fml {} {}", a, b),
+            Fdv(a, b) => write!(f, "fdv {} {}", a, b),
+            Fee(a, b) => write!(f, "fee {} {}", a, b),
+            Fne(a, b) => write!(f, "fne {} {}", a, b),
+            Fll(a, b) => write!(f, "fll {} {}", a, b),
+            Fmm(a, b) => write!(f, "fmm {} {}", a, b),
+            Fel(a, b) => write!(f, "fel {} {}", a, b),
+            Fem(a, b) => write!(f, "fem {} {}", a, b),
+            Itf(a, b) => write!(f, "itf {} {}", a, b),
+            Fti(a, b) => write!(f, "fti {} {}", a, b),
+            Fpr(r) => write!(f, "fpr {}", r),
+            Alc(a, b) => write!(f, "alc {} {}", a, b),
+            Fre(r) => write!(f, "fre {}", r),
+            Lod(a, b) => write!(f, "lod {} {}", a, b),
+            Sto(a, b) => write!(f, "sto {} {}", a, b),
+            Sys(n) => write!(f, "sys {}", n),
+            Snp => write!(f, "snp"),
+        }
+    }
+}
+
+/// Source code of a program, each line is commented with the index of its instruction.
+pub fn disassemble(program: &[Instructions]) -> String {
+    let width = program
+        .iter()
+        .map(|i| i.to_string().len())
+        .max()
+        .unwrap_or(0);
+    program
+        .iter()
+        .enumerate()
+        .map(|(i, instr)| format!("{:w$} ; {}\n", instr.to_string(), i, w = width))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::{parse_source, ParseOptions};
+    use crate::FloatRegisters::*;
+    use crate::Registers::*;
+
+    #[test]
+    fn reassembles() {
+        let program = vec![
+            Psh(-3),
+            PshR(Of),
+            PopR(A),
+            AddS,
+            Mov(Sp, B),
+            Set(E, -12),
+            Jmp(2),
+            Fld(Fa, (-0.5f64).to_bits()),
+            Fld(Fb, f64::INFINITY.to_bits()),
+            Fti(C, Fb),
+            Itf(Fc, D),
+            Sto(E, F),
+            Sys(3),
+            Snp,
+            HltR(A),
+        ];
+
+        let source = disassemble(&program);
+        let options = ParseOptions::default();
+        assert_eq!(parse_source(&source, options).unwrap(), program);
+    }
+}
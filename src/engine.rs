@@ -0,0 +1,252 @@
+use crate::{Instructions, Instructions::*, Registers, Registers::*, Vm, VmError};
+
+// Instructions with their registers resolved to indices. Only the instructions that can't
+// jump, halt, print or write ip, sp or st get their own operation, the others keep going
+// through `Vm::eval`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Op {
+    Psh(i64),
+    PshR(usize),
+    Pop,
+    PopR(usize),
+    Dup,
+    Add(usize, usize),
+    Sub(usize, usize),
+    Mul(usize, usize),
+    Div(usize, usize),
+    AddS,
+    SubS,
+    MulS,
+    DivS,
+    Mov(usize, usize),
+    Set(usize, i64),
+    Tee(usize, usize),
+    Tne(usize, usize),
+    Tll(usize, usize),
+    Tmm(usize, usize),
+    Tel(usize, usize),
+    Tem(usize, usize),
+    Jmp(i64),
+    Other(Instructions),
+}
+
+/// A program decoded once for `Vm::run_decoded`, which runs it faster than `Vm::run`.
+#[derive(Clone, Debug)]
+pub struct Decoded {
+    program: Vec<Instructions>, // Run by the traced loop
+    ops: Vec<Op>,
+}
+
+impl Decoded {
+    pub fn new(program: &[Instructions]) -> Decoded {
+        Decoded {
+            program: program.to_vec(),
+            ops: program.iter().map(|instr| decode(*instr)).collect(),
+        }
+    }
+
+    pub(crate) fn instruction(&self, ip: usize) -> Option<Instructions> {
+        self.program.get(ip).copied()
+    }
+}
+
+// Registers written by the fast operations : writes to ip, sp and st have side effects
+fn plain(reg: Registers) -> bool {
+    !matches!(reg, Ip | Sp | St)
+}
+
+fn decode(instr: Instructions) -> Op {
+    let r = |reg: Registers| reg as usize;
+    match instr {
+        Psh(i) => Op::Psh(i),
+        PshR(reg) => Op::PshR(r(reg)),
+        Pop => Op::Pop,
+        PopR(reg) if plain(reg) => Op::PopR(r(reg)),
+        Dup => Op::Dup,
+        Add(a, b) if plain(a) => Op::Add(r(a), r(b)),
+        Sub(a, b) if plain(a) => Op::Sub(r(a), r(b)),
+        Mul(a, b) if plain(a) => Op::Mul(r(a), r(b)),
+        Div(a, b) if plain(a) => Op::Div(r(a), r(b)),
+        AddS => Op::AddS,
+        SubS => Op::SubS,
+        MulS => Op::MulS,
+        DivS => Op::DivS,
+        Mov(a, b) if plain(a) => Op::Mov(r(a), r(b)),
+        Set(reg, i) if plain(reg) => Op::Set(r(reg), i),
+        Tee(a, b) => Op::Tee(r(a), r(b)),
+        Tne(a, b) => Op::Tne(r(a), r(b)),
+        Tll(a, b) => Op::Tll(r(a), r(b)),
+        Tmm(a, b) => Op::Tmm(r(a), r(b)),
+        Tel(a, b) => Op::Tel(r(a), r(b)),
+        Tem(a, b) => Op::Tem(r(a), r(b)),
+        Jmp(i) => Op::Jmp(i as i64),
+        instr => Op::Other(instr),
+    }
+}
+
+impl Vm {
+    /// Same as `run`, with the details printed by a separate loop so that the untraced one
+    /// never checks for them.
+    pub fn run_decoded(&mut self, program: &Decoded) -> Result<(), VmError> {
+        if self.details {
+            self.run(&program.program)
+        } else {
+            while self.running {
+                self.step_decoded(program)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Same as `step` without printing the details.
+    #[inline(always)]
+    pub(crate) fn step_decoded(&mut self, program: &Decoded) -> Result<(), VmError> {
+        let ops = &program.ops;
+        let ip = self.regs[Ip as usize];
+        if ip < 0 || ip as usize >= ops.len() {
+            return Err(VmError::InvalidJump(ip));
+        }
+        if let Some(fuel) = self.fuel {
+            if fuel == 0 {
+                return Err(VmError::OutOfFuel(ip));
+            }
+            self.fuel = Some(fuel - 1);
+        }
+
+        match ops[ip as usize] {
+            Op::Psh(i) => {
+                let value = self.narrow(i);
+                self.push(value)?
+            }
+            Op::PshR(reg) => self.push(self.regs[reg])?,
+            Op::Pop => {
+                self.pop()?;
+            }
+            Op::PopR(reg) => self.regs[reg] = self.pop()?,
+            Op::Dup => self.push(self.peek(0)?)?,
+            Op::Add(a, b) => {
+                let (lhs, rhs) = (self.regs[a], self.regs[b]);
+                self.regs[a] =
+                    self.arithmetic(lhs, rhs, i64::overflowing_add, i64::saturating_add)?;
+            }
+            Op::Sub(a, b) => {
+                let (lhs, rhs) = (self.regs[a], self.regs[b]);
+                self.regs[a] =
+                    self.arithmetic(lhs, rhs, i64::overflowing_sub, i64::saturating_sub)?;
+            }
+            Op::Mul(a, b) => {
+                let (lhs, rhs) = (self.regs[a], self.regs[b]);
+                self.regs[a] =
+                    self.arithmetic(lhs, rhs, i64::overflowing_mul, i64::saturating_mul)?;
+            }
+            Op::Div(a, b) => {
+                let (lhs, rhs) = (self.regs[a], self.regs[b]);
+                if rhs == 0 {
+                    return Err(VmError::DivisionByZero(ip));
+                }
+                self.regs[a] =
+                    self.arithmetic(lhs, rhs, i64::overflowing_div, i64::saturating_div)?;
+            }
+            Op::AddS => self.stack_arithmetic(i64::overflowing_add, i64::saturating_add)?,
+            Op::SubS => self.stack_arithmetic(i64::overflowing_sub, i64::saturating_sub)?,
+            Op::MulS => self.stack_arithmetic(i64::overflowing_mul, i64::saturating_mul)?,
+            Op::DivS => {
+                if self.peek(0)? == 0 {
+                    return Err(VmError::DivisionByZero(ip));
+                }
+                self.stack_arithmetic(i64::overflowing_div, i64::saturating_div)?
+            }
+            Op::Mov(a, b) => self.regs[a] = self.regs[b],
+            Op::Set(reg, i) => self.regs[reg] = self.narrow(i),
+            Op::Tee(a, b) => self.regs[Eq as usize] = (self.regs[a] == self.regs[b]) as i64,
+            Op::Tne(a, b) => self.regs[Eq as usize] = (self.regs[a] != self.regs[b]) as i64,
+            Op::Tll(a, b) => self.regs[Eq as usize] = (self.regs[a] < self.regs[b]) as i64,
+            Op::Tmm(a, b) => self.regs[Eq as usize] = (self.regs[a] > self.regs[b]) as i64,
+            Op::Tel(a, b) => self.regs[Eq as usize] = (self.regs[a] <= self.regs[b]) as i64,
+            Op::Tem(a, b) => self.regs[Eq as usize] = (self.regs[a] >= self.regs[b]) as i64,
+            Op::Jmp(target) => {
+                if self.regs[Eq as usize] == 1 {
+                    self.jump(target)?
+                }
+            }
+            Op::Other(instr) => self.eval(instr)?,
+        }
+        self.regs[Ip as usize] += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::optimize::test::{observe_with, program};
+    use proptest::prelude::*;
+
+    #[test]
+    fn decodes() {
+        let program = [
+            Psh(3),
+            PopR(A),
+            PopR(Sp),
+            Mov(Ip, A),
+            Set(Of, 1),
+            Jmp(-1),
+            Hlt,
+        ];
+        let ops = Decoded::new(&program).ops;
+        assert_eq!(
+            ops,
+            vec![
+                Op::Psh(3),
+                Op::PopR(0),
+                Op::Other(PopR(Sp)),
+                Op::Other(Mov(Ip, A)),
+                Op::Set(10, 1),
+                Op::Jmp(-1),
+                Op::Other(Hlt)
+            ]
+        );
+    }
+
+    #[test]
+    fn loops() {
+        // Counts a down from 1000, then pushes its 3 last values
+        let program = [
+            Set(A, 1000),
+            Set(B, 1),
+            Set(C, 3),
+            Sub(A, B),
+            Tll(C, A),
+            Jmp(3),
+            PshR(A),
+            Sub(A, B),
+            Tel(B, A),
+            Jmp(6),
+            HltR(C),
+        ];
+        let mut vm = Vm::new();
+        vm.fuel = Some(5000);
+        vm.run_decoded(&Decoded::new(&program)).unwrap();
+        assert_eq!(vm.live_stack(), &[3, 2, 1]);
+        assert_eq!(vm.exit_code, 3);
+        let mut reference = Vm::new();
+        reference.fuel = Some(5000);
+        reference.run(&program).unwrap();
+        assert_eq!(vm.fuel, reference.fuel);
+
+        let mut vm = Vm::new();
+        vm.fuel = Some(100);
+        let error = vm.run_decoded(&Decoded::new(&program));
+        assert_eq!(error, Err(VmError::OutOfFuel(4)));
+    }
+
+    proptest! {
+        #[test]
+        fn same_behaviour(program in program()) {
+            let decoded = observe_with(&program, |vm, program| {
+                vm.run_decoded(&Decoded::new(program))
+            });
+            prop_assert_eq!(decoded, observe_with(&program, Vm::run));
+        }
+    }
+}
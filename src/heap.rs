@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub const HEAP_SIZE: usize = 4096;
+
+/// Word addressed memory handed out by `alc`. Address 0 is never allocated so it can be
+/// used as a null pointer.
+#[derive(Clone)]
+pub struct Heap {
+    pub memory: Vec<i64>,
+    live: BTreeMap<usize, usize>,  // start -> size of allocated blocks
+    freed: BTreeMap<usize, usize>, // start -> size of freed blocks not allocated again
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeapError {
+    InvalidSize,
+    OutOfMemory,
+    DoubleFree,
+    InvalidFree,
+    UseAfterFree,
+    OutOfBounds,
+}
+
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeapError::InvalidSize => write!(f, "ERR_INVALID_ALLOCATION_SIZE"),
+            HeapError::OutOfMemory => write!(f, "ERR_OUT_OF_MEMORY"),
+            HeapError::DoubleFree => write!(f, "ERR_DOUBLE_FREE"),
+            HeapError::InvalidFree => write!(f, "ERR_INVALID_FREE"),
+            HeapError::UseAfterFree => write!(f, "ERR_USE_AFTER_FREE"),
+            HeapError::OutOfBounds => write!(f, "ERR_OUT_OF_BOUNDS"),
+        }
+    }
+}
+
+impl Heap {
+    pub fn new(size: usize) -> Heap {
+        Heap {
+            memory: vec![0; size],
+            live: BTreeMap::new(),
+            freed: BTreeMap::new(),
+        }
+    }
+
+    /// Allocates `size` zeroed words with first fit, returning the address of the first one.
+    pub fn alloc(&mut self, size: i64) -> Result<usize, HeapError> {
+        if size <= 0 {
+            return Err(HeapError::InvalidSize);
+        }
+        let size = size as usize;
+
+        let mut start = 1;
+        for (&block, &block_size) in &self.live {
+            if block - start >= size {
+                break;
+            }
+            start = block + block_size;
+        }
+        if start + size > self.memory.len() {
+            return Err(HeapError::OutOfMemory);
+        }
+
+        // The new block may reuse freed memory, which is not freed anymore
+        let reused = self
+            .freed
+            .range(..start + size)
+            .filter(|(&block, &block_size)| block + block_size > start)
+            .map(|(&block, _)| block)
+            .collect::<Vec<usize>>();
+        for block in reused {
+            self.freed.remove(&block);
+        }
+
+        for word in &mut self.memory[start..start + size] {
+            *word = 0;
+        }
+        self.live.insert(start, size);
+        Ok(start)
+    }
+
+    pub fn free(&mut self, ptr: i64) -> Result<(), HeapError> {
+        let ptr = ptr as usize;
+        match self.live.remove(&ptr) {
+            Some(size) => {
+                self.freed.insert(ptr, size);
+                Ok(())
+            }
+            None if self.freed.contains_key(&ptr) => Err(HeapError::DoubleFree),
+            None => Err(HeapError::InvalidFree),
+        }
+    }
+
+    // Checks that `addr` is inside an allocated block
+    fn check(&self, addr: i64) -> Result<usize, HeapError> {
+        let inside = |blocks: &BTreeMap<usize, usize>| {
+            addr >= 0
+                && blocks
+                    .range(..=addr as usize)
+                    .next_back()
+                    .is_some_and(|(&block, &size)| (addr as usize) < block + size)
+        };
+
+        if inside(&self.live) {
+            Ok(addr as usize)
+        } else if inside(&self.freed) {
+            Err(HeapError::UseAfterFree)
+        } else {
+            Err(HeapError::OutOfBounds)
+        }
+    }
+
+    pub fn load(&self, addr: i64) -> Result<i64, HeapError> {
+        Ok(self.memory[self.check(addr)?])
+    }
+
+    pub fn store(&mut self, addr: i64, value: i64) -> Result<(), HeapError> {
+        let addr = self.check(addr)?;
+        self.memory[addr] = value;
+        Ok(())
+    }
+
+    /// Blocks still allocated, as (address, size) pairs.
+    pub fn leaks(&self) -> Vec<(usize, usize)> {
+        self.live.iter().map(|(&a, &s)| (a, s)).collect()
+    }
+
+    // Freed blocks not allocated again, as (address, size) pairs
+    pub(crate) fn freed(&self) -> Vec<(usize, usize)> {
+        self.freed.iter().map(|(&a, &s)| (a, s)).collect()
+    }
+
+    // Approximate number of bytes used by the heap
+    pub(crate) fn bytes(&self) -> usize {
+        let block = std::mem::size_of::<(usize, usize)>();
+        self.memory.len() * std::mem::size_of::<i64>()
+            + (self.live.len() + self.freed.len()) * block
+    }
+
+    // Heap with the given blocks, None if one of them doesn't fit in the memory
+    pub(crate) fn from_parts(
+        memory: Vec<i64>,
+        live: &[(usize, usize)],
+        freed: &[(usize, usize)],
+    ) -> Option<Heap> {
+        let fits = |&(start, size): &(usize, usize)| {
+            start > 0
+                && size > 0
+                && start
+                    .checked_add(size)
+                    .is_some_and(|end| end <= memory.len())
+        };
+        if !live.iter().chain(freed).all(fits) {
+            return None;
+        }
+        Some(Heap {
+            live: live.iter().copied().collect(),
+            freed: freed.iter().copied().collect(),
+            memory,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn first_fit() {
+        let mut heap = Heap::new(16);
+
+        let a = heap.alloc(4).unwrap();
+        let b = heap.alloc(4).unwrap();
+        let c = heap.alloc(4).unwrap();
+        assert_eq!((a, b, c), (1, 5, 9));
+
+        heap.free(b as i64).unwrap();
+        assert_eq!(heap.alloc(2), Ok(5));
+        assert_eq!(heap.alloc(2), Ok(7));
+        assert_eq!(heap.alloc(4), Err(HeapError::OutOfMemory));
+        assert_eq!(heap.alloc(3), Ok(13));
+    }
+
+    #[test]
+    fn memory_is_zeroed() {
+        let mut heap = Heap::new(8);
+
+        let a = heap.alloc(2).unwrap() as i64;
+        heap.store(a + 1, 42).unwrap();
+        heap.free(a).unwrap();
+        let b = heap.alloc(2).unwrap() as i64;
+        assert_eq!(heap.load(b + 1), Ok(0));
+    }
+
+    #[test]
+    fn detects_misuse() {
+        let mut heap = Heap::new(8);
+
+        assert_eq!(heap.alloc(0), Err(HeapError::InvalidSize));
+        let a = heap.alloc(2).unwrap() as i64;
+        assert_eq!(heap.load(a + 2), Err(HeapError::OutOfBounds));
+        assert_eq!(heap.load(0), Err(HeapError::OutOfBounds));
+        assert_eq!(heap.load(-1), Err(HeapError::OutOfBounds));
+        assert_eq!(heap.free(a + 1), Err(HeapError::InvalidFree));
+
+        heap.free(a).unwrap();
+        assert_eq!(heap.load(a + 1), Err(HeapError::UseAfterFree));
+        assert_eq!(heap.store(a, 1), Err(HeapError::UseAfterFree));
+        assert_eq!(heap.free(a), Err(HeapError::DoubleFree));
+        assert!(heap.leaks().is_empty());
+    }
+
+    #[test]
+    fn leaks() {
+        let mut heap = Heap::new(8);
+
+        let a = heap.alloc(1).unwrap();
+        let b = heap.alloc(3).unwrap();
+        heap.free(a as i64).unwrap();
+        assert_eq!(heap.leaks(), vec![(b, 3)]);
+    }
+}
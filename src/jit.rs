@@ -0,0 +1,459 @@
+use crate::cfg::Cfg;
+use crate::engine::Decoded;
+use crate::{Instructions, Instructions::*, OverflowMode, Registers, Registers::*};
+use crate::{Vm, VmError, WordSize};
+use std::io;
+
+// Compiled code is called with the address of the registers in rdi and of the number of
+// instructions it may still run in rsi, and returns the next instruction to interpret in rax.
+type Native = extern "sysv64" fn(*mut i64, *mut i64) -> i64;
+
+/// Basic blocks compiled to x86-64 for `Vm::run_jit`. Only register moves, `add`, `sub`,
+/// `mul`, tests and jmp are compiled, everything else (the stack, the heap, I/O, divisions
+/// and writes to ip, sp or st) is left to the interpreter.
+///
+/// Each block starting with compiled instructions gets an entry point. Blocks run entirely
+/// in native code jump straight to the next compiled block, so hot loops never leave it.
+pub struct Jit {
+    code: Option<Code>,
+    entries: Vec<Option<usize>>, // Offset of the code of the block starting at each ip
+    word_size: WordSize,
+    decoded: Decoded, // Runs what isn't compiled
+}
+
+impl Jit {
+    pub fn compile(program: &[Instructions], word_size: WordSize) -> io::Result<Jit> {
+        let cfg = Cfg::new(program);
+        let mut asm = Assembler::default();
+        let entries_labels = cfg
+            .blocks
+            .iter()
+            .map(|block| {
+                let compiled = compiled_prefix(&program[block.start..block.end]);
+                if compiled == 0 {
+                    None
+                } else {
+                    Some(asm.label())
+                }
+            })
+            .collect::<Vec<Option<usize>>>();
+
+        for (i, block) in cfg.blocks.iter().enumerate() {
+            let entry = match entries_labels[i] {
+                Some(label) => label,
+                None => continue,
+            };
+            let len = compiled_prefix(&program[block.start..block.end]);
+            let bail = asm.label();
+            // Target of a jump, as a native jump when compiled and as a return otherwise
+            let exit = |asm: &mut Assembler, block: Option<usize>, ip: i64| match block
+                .and_then(|b| entries_labels[b])
+            {
+                Some(label) => asm.jmp(label),
+                None => asm.ret_with(ip),
+            };
+
+            asm.bind(entry);
+            asm.sub_budget(len as i32);
+            asm.jl(bail);
+            for instr in &program[block.start..block.start + len] {
+                asm.instruction(*instr, word_size);
+            }
+            let end = (block.start + len) as i64;
+            if block.start + len < block.end {
+                asm.ret_with(end);
+            } else if let Jmp(target) = program[block.end - 1] {
+                let not_taken = asm.label();
+                asm.cmp_register(Eq, 1);
+                asm.jne(not_taken);
+                exit(&mut asm, block.taken, target as i64);
+                asm.bind(not_taken);
+                exit(&mut asm, block.next, end);
+            } else {
+                exit(&mut asm, block.next, end);
+            }
+
+            // Not enough instructions left to run the block, the interpreter runs it
+            asm.bind(bail);
+            asm.add_budget(len as i32);
+            asm.ret_with(block.start as i64);
+        }
+
+        let mut entries = vec![None; program.len()];
+        for (block, label) in cfg.blocks.iter().zip(&entries_labels) {
+            entries[block.start] = label.map(|l| asm.labels[l].unwrap());
+        }
+        let code = if asm.code.is_empty() {
+            None
+        } else {
+            Some(Code::new(&asm.finish())?)
+        };
+        Ok(Jit {
+            code,
+            entries,
+            word_size,
+            decoded: Decoded::new(program),
+        })
+    }
+
+    /// Number of blocks with native code.
+    pub fn entries(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    fn entry(&self, ip: i64) -> Option<Native> {
+        if ip < 0 {
+            return None;
+        }
+        let offset = (*self.entries.get(ip as usize)?)?;
+        let code = self.code.as_ref()?;
+        // The offset is the start of a function following the `Native` convention
+        Some(unsafe { std::mem::transmute::<*const u8, Native>(code.ptr.add(offset)) })
+    }
+}
+
+impl Vm {
+    /// Same as `run`, running the blocks compiled by `jit` natively. Falls back to the
+    /// interpreter for the whole program when printing details, when the overflow mode isn't
+    /// wrapping or when the word size isn't the one the program was compiled for.
+    pub fn run_jit(&mut self, program: &[Instructions], jit: &Jit) -> Result<(), VmError> {
+        if self.details
+            || self.overflow != OverflowMode::Wrapping
+            || self.word_size != jit.word_size
+        {
+            return self.run(program);
+        }
+        while self.running {
+            let ip = self.regs[Ip as usize];
+            if let Some(native) = jit.entry(ip) {
+                let mut budget = self
+                    .fuel
+                    .map_or(i64::MAX, |f| f.min(i64::MAX as u64) as i64);
+                let next = native(self.regs.as_mut_ptr(), &mut budget);
+                if self.fuel.is_some() {
+                    self.fuel = Some(budget as u64);
+                }
+                self.regs[Ip as usize] = next;
+                if next != ip {
+                    continue;
+                }
+                // Bailed out before running anything
+            }
+            self.step_decoded(&jit.decoded)?;
+        }
+        Ok(())
+    }
+}
+
+// Whether the instruction is compiled. Compiled code doesn't update ip, so it can't be read.
+fn compiles(instr: Instructions) -> bool {
+    let plain = |reg: Registers| !matches!(reg, Ip | Sp | St);
+    match instr {
+        Set(reg, _) => plain(reg),
+        Mov(a, b) | Add(a, b) | Sub(a, b) | Mul(a, b) => plain(a) && b != Ip,
+        Tee(a, b) | Tne(a, b) | Tll(a, b) | Tmm(a, b) | Tel(a, b) | Tem(a, b) => a != Ip && b != Ip,
+        Jmp(target) => target >= 0,
+        _ => false,
+    }
+}
+
+fn compiled_prefix(block: &[Instructions]) -> usize {
+    block.iter().take_while(|i| compiles(**i)).count()
+}
+
+// Executable memory holding the code
+struct Code {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl Code {
+    fn new(bytes: &[u8]) -> io::Result<Code> {
+        unsafe {
+            let ptr = libc::mmap(
+                std::ptr::null_mut(),
+                bytes.len(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr as *mut u8, bytes.len());
+            if libc::mprotect(ptr, bytes.len(), libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                let error = io::Error::last_os_error();
+                libc::munmap(ptr, bytes.len());
+                return Err(error);
+            }
+            Ok(Code {
+                ptr: ptr as *const u8,
+                len: bytes.len(),
+            })
+        }
+    }
+}
+
+impl Drop for Code {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+// Condition codes of jcc / setcc
+const OVERFLOW: u8 = 0x0;
+const EQUAL: u8 = 0x4;
+const NOT_EQUAL: u8 = 0x5;
+const LESS: u8 = 0xC;
+const GREATER_OR_EQUAL: u8 = 0xD;
+const LESS_OR_EQUAL: u8 = 0xE;
+const GREATER: u8 = 0xF;
+
+// Encodes the few instructions needed, registers being addressed as [rdi + 8 * index]
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, usize)>, // rel32 to patch with the label
+}
+
+impl Assembler {
+    fn label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn bind(&mut self, label: usize) {
+        self.labels[label] = Some(self.code.len());
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn rel32(&mut self, label: usize) {
+        self.fixups.push((self.code.len(), label));
+        self.emit(&[0; 4]);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for &(at, label) in &self.fixups {
+            let target = self.labels[label].unwrap() as i64;
+            let rel = (target - (at as i64 + 4)) as i32;
+            self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        self.code
+    }
+
+    fn jmp(&mut self, label: usize) {
+        self.emit(&[0xE9]);
+        self.rel32(label);
+    }
+
+    fn jcc(&mut self, condition: u8, label: usize) {
+        self.emit(&[0x0F, 0x80 | condition]);
+        self.rel32(label);
+    }
+
+    fn jl(&mut self, label: usize) {
+        self.jcc(LESS, label)
+    }
+
+    fn jne(&mut self, label: usize) {
+        self.jcc(NOT_EQUAL, label)
+    }
+
+    // mov rax, ip ; ret
+    fn ret_with(&mut self, ip: i64) {
+        self.emit(&[0x48, 0xB8]);
+        self.emit(&ip.to_le_bytes());
+        self.emit(&[0xC3]);
+    }
+
+    // sub qword [rsi], n
+    fn sub_budget(&mut self, n: i32) {
+        self.emit(&[0x48, 0x81, 0x2E]);
+        self.emit(&n.to_le_bytes());
+    }
+
+    // add qword [rsi], n
+    fn add_budget(&mut self, n: i32) {
+        self.emit(&[0x48, 0x81, 0x06]);
+        self.emit(&n.to_le_bytes());
+    }
+
+    // cmp qword [rdi + reg], n
+    fn cmp_register(&mut self, reg: Registers, n: i8) {
+        self.emit(&[0x48, 0x83, 0x7F, offset(reg), n as u8]);
+    }
+
+    // <op> rax, [rdi + reg] with a ModRM byte using rax, or eax without the REX.W prefix
+    fn rax_op(&mut self, wide: bool, opcode: &[u8], reg: Registers) {
+        if wide {
+            self.emit(&[0x48]);
+        }
+        self.emit(opcode);
+        self.emit(&[0x47, offset(reg)]);
+    }
+
+    fn load(&mut self, reg: Registers) {
+        self.rax_op(true, &[0x8B], reg)
+    }
+
+    fn store(&mut self, reg: Registers) {
+        self.rax_op(true, &[0x89], reg)
+    }
+
+    // setcc cl ; movzx ecx, cl ; mov [rdi + reg], rcx
+    fn store_condition(&mut self, condition: u8, reg: Registers) {
+        self.emit(&[0x0F, 0x90 | condition, 0xC1, 0x0F, 0xB6, 0xC9]);
+        self.emit(&[0x48, 0x89, 0x4F, offset(reg)]);
+    }
+
+    fn instruction(&mut self, instr: Instructions, word_size: WordSize) {
+        let wide = word_size == WordSize::W64;
+        match instr {
+            Set(reg, i) => {
+                let value = match word_size {
+                    WordSize::W32 => i as i32 as i64,
+                    WordSize::W64 => i,
+                };
+                self.emit(&[0x48, 0xB8]);
+                self.emit(&value.to_le_bytes());
+                self.store(reg);
+            }
+            Mov(a, b) => {
+                self.load(b);
+                self.store(a);
+            }
+            Add(a, b) | Sub(a, b) | Mul(a, b) => {
+                // The overflow flag of the 32 bit operations is the one of the i32 bounds
+                let opcode: &[u8] = match instr {
+                    Add(..) => &[0x03],
+                    Sub(..) => &[0x2B],
+                    _ => &[0x0F, 0xAF],
+                };
+                self.rax_op(wide, &[0x8B], a);
+                self.rax_op(wide, opcode, b);
+                // of is written before the result, which may go to of
+                self.store_condition(OVERFLOW, Of);
+                if !wide {
+                    self.emit(&[0x48, 0x63, 0xC0]); // movsxd rax, eax
+                }
+                self.store(a);
+            }
+            Tee(a, b) | Tne(a, b) | Tll(a, b) | Tmm(a, b) | Tel(a, b) | Tem(a, b) => {
+                let condition = match instr {
+                    Tee(..) => EQUAL,
+                    Tne(..) => NOT_EQUAL,
+                    Tll(..) => LESS,
+                    Tmm(..) => GREATER,
+                    Tel(..) => LESS_OR_EQUAL,
+                    _ => GREATER_OR_EQUAL,
+                };
+                self.load(a);
+                self.rax_op(true, &[0x3B], b); // cmp rax, [rdi + b]
+                self.store_condition(condition, Eq);
+            }
+            Jmp(_) => (), // Compiled with the end of its block
+            instr => unreachable!("{} is not compiled", instr),
+        }
+    }
+}
+
+fn offset(reg: Registers) -> u8 {
+    reg as u8 * 8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::optimize::test::{observe_with, program};
+    use proptest::prelude::*;
+
+    fn run(program: &[Instructions], word_size: WordSize, fuel: Option<u64>) -> Vm {
+        let jit = Jit::compile(program, word_size).unwrap();
+        let mut vm = Vm::new();
+        vm.word_size = word_size;
+        vm.fuel = fuel;
+        vm.run_jit(program, &jit).unwrap();
+        vm
+    }
+
+    #[test]
+    fn arithmetic() {
+        let program = [
+            Set(A, i32::MAX as i64),
+            Set(B, 2),
+            Mul(A, B),
+            Mov(C, Of),
+            Sub(B, A),
+            Tmm(B, A),
+            HltR(A),
+        ];
+        assert_eq!(Jit::compile(&program, WordSize::W32).unwrap().entries(), 1);
+
+        let vm = run(&program, WordSize::W32, None);
+        assert_eq!(&vm.regs[..3], &[-2, 4, 1]);
+        assert_eq!((vm.regs[Eq as usize], vm.regs[Of as usize]), (1, 0));
+
+        let vm = run(&program, WordSize::W64, None);
+        let max = i32::MAX as i64 * 2;
+        assert_eq!(&vm.regs[..3], &[max, 2 - max, 0]);
+        assert_eq!(vm.exit_code, max);
+    }
+
+    #[test]
+    fn loops_and_fuel() {
+        // Counts a down from 1000, printing nothing but pushing the last value
+        let program = [
+            Set(A, 1000),
+            Set(B, 1),
+            Sub(A, B),
+            Tne(A, B),
+            Jmp(2),
+            PshR(A),
+            Hlt,
+        ];
+        let vm = run(&program, WordSize::W32, Some(10_000));
+        assert_eq!(vm.live_stack(), &[1]);
+
+        let mut reference = Vm::new();
+        reference.fuel = Some(10_000);
+        reference.run(&program).unwrap();
+        assert_eq!(vm.fuel, reference.fuel);
+
+        // Blocks that can't run entirely with the fuel left are interpreted
+        let jit = Jit::compile(&program, WordSize::W32).unwrap();
+        let mut vm = Vm::new();
+        vm.fuel = Some(100);
+        let mut reference = Vm::new();
+        reference.fuel = Some(100);
+        assert_eq!(vm.run_jit(&program, &jit), reference.run(&program));
+        assert_eq!(vm.regs, reference.regs);
+    }
+
+    proptest! {
+        #[test]
+        fn same_behaviour(program in program(), wide: bool, fuel in prop::option::of(0..200u64)) {
+            let word_size = if wide { WordSize::W64 } else { WordSize::W32 };
+            let setup = move |vm: &mut Vm| {
+                vm.word_size = word_size;
+                vm.fuel = fuel;
+            };
+            let jit = Jit::compile(&program, word_size).unwrap();
+            let native = observe_with(&program, |vm, program| {
+                setup(vm);
+                vm.run_jit(program, &jit)
+            });
+            let interpreted = observe_with(&program, |vm, program| {
+                setup(vm);
+                vm.run(program)
+            });
+            prop_assert_eq!(native, interpreted);
+        }
+    }
+}
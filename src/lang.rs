@@ -0,0 +1,270 @@
+//! A small structured language compiling to wlvm instructions :
+//!
+//! ```text
+//! // Prints the first 10 squares
+//! let i = 1;
+//! while i <= 10 {
+//!     print i * i;
+//!     i = i + 1;
+//! }
+//! ```
+//!
+//! Values are integers of the word size of the VM, which literals must fit in. Variables are declared once with `let`,
+//! before being used, and live until the end of the program. Conditions are true when not 0,
+//! comparisons giving 0 or 1. `print` prints its value like `drg`.
+
+mod codegen;
+mod lexer;
+mod parser;
+
+use crate::{Instructions, WordSize};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub line: usize,
+    pub text: String, // Source of the line
+    pub message: String,
+}
+
+impl Error {
+    fn new<S: Into<String>>(line: usize, message: S) -> Error {
+        Error {
+            line,
+            text: String::new(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} | {}", self.line, self.text)?;
+        writeln!(f, "^^^^^^^^^^^^^^^^^^^^")?;
+        write!(f, "Error : {}", self.message)
+    }
+}
+
+/// Compiles the source to a program ending with `hlt`, for a VM of the word size.
+pub fn compile(source: &str, word_size: WordSize) -> Result<Vec<Instructions>, Error> {
+    lexer::lex(source)
+        .and_then(|tokens| parser::parse(tokens, word_size))
+        .and_then(|program| codegen::generate(&program))
+        .map_err(|mut e| {
+            e.text = source
+                .lines()
+                .nth(e.line - 1)
+                .unwrap_or("")
+                .trim()
+                .to_string();
+            e
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::optimize::optimize;
+    use crate::{Instructions::*, Registers::*, Vm, VmError, WordSize, STACK_SIZE};
+    use proptest::prelude::*;
+
+    // Values printed by the program
+    fn printed(program: &[Instructions], vm: &mut Vm) -> Result<Vec<i64>, VmError> {
+        let mut printed = vec![];
+        while vm.running {
+            if let Some(Drg(reg)) = program.get(vm.regs[Ip as usize] as usize) {
+                printed.push(vm.regs[*reg as usize]);
+            }
+            vm.step(program)?;
+        }
+        Ok(printed)
+    }
+
+    fn run(source: &str) -> Vec<i64> {
+        let program = compile(source, WordSize::W32).unwrap();
+        printed(&program, &mut Vm::new()).unwrap()
+    }
+
+    #[test]
+    fn compiles() {
+        let program = compile(
+            "let x = 3;\nwhile x > 0 {\n  print x;\n  x = x - 1;\n}",
+            WordSize::W32,
+        )
+        .unwrap();
+        assert_eq!(
+            program,
+            vec![
+                Set(B, 3),
+                Mov(A, B),
+                Set(B, 0), // 2
+                Tel(A, B),
+                Jmp(12),
+                Drg(A),
+                Mov(B, A),
+                Set(C, 1),
+                Sub(B, C),
+                Mov(A, B),
+                Set(Eq, 1),
+                Jmp(2),
+                Hlt,
+            ]
+        );
+    }
+
+    #[test]
+    fn runs() {
+        let squares = "// Prints the first 10 squares\nlet i = 1;\nwhile i <= 10 {\n  \
+                       print i * i;\n  i = i + 1;\n}";
+        assert_eq!(run(squares), [1, 4, 9, 16, 25, 36, 49, 64, 81, 100]);
+
+        let branches = "let x = 7;\nif x == 7 { print 1; } else { print 2; }\n\
+                        if x - 7 { print 3; } else if x / 2 == 3 { print 4; } else { print 5; }\n\
+                        if x < 0 { print 6; }\nprint -x * (2 + 3) - -1;\nprint x >= 7;";
+        assert_eq!(run(branches), [1, 4, -34, 1]);
+
+        // Euclid, with more variables than registers
+        let gcd = "let a = 1071; let b = 462; let c = 0; let d = 0; let e = 0; let f = 0;\n\
+                   while b != 0 {\n  let t = a - a / b * b;\n  a = b;\n  b = t;\n  \
+                   c = c + 1; d = d + 2; e = e + 3; f = f + 4;\n}\nprint a; print c; print f;";
+        assert_eq!(run(gcd), [21, 3, 12]);
+    }
+
+    #[test]
+    fn spills() {
+        // More variables than registers, all alive, in a deep expression
+        let mut source = String::new();
+        for i in 0..12 {
+            source += &format!("let v{} = {};\n", i, i + 1);
+        }
+        source += "print v0 - (v1 - (v2 - (v3 - (v4 - (v5 - (v6 - (v7 - (v8 - (v9 - (v10 - \
+                   v11))))))))));\n";
+        source += "v11 = v0 * 100;\nv3 = v11 + v10;\nprint v3;\nprint v11 == 100;";
+        let program = compile(&source, WordSize::W32).unwrap();
+        assert!(program.contains(&PopR(Eq)));
+        assert!(program.contains(&Mov(Sp, Eq)));
+        assert_eq!(printed(&program, &mut Vm::new()), Ok(vec![-6, 111, 1]));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source: &str| compile(source, WordSize::W32).unwrap_err().to_string();
+        assert_eq!(
+            error("let x = 1;\nprint y;"),
+            "2 | print y;\n^^^^^^^^^^^^^^^^^^^^\nError : y is not declared"
+        );
+        assert!(error("let x = 1;\nlet x = 2;").ends_with("Error : x is already declared"));
+        assert!(error("x = 1;").ends_with("Error : x is not declared"));
+        assert!(error("let x = x;").ends_with("Error : x is not declared"));
+        assert!(error("let x = 1").ends_with("Error : expected `;`"));
+        assert!(error("let x = 1 < 2 < 3;").ends_with("Error : expected `;`, found `<`"));
+        assert!(error("while 1 { print 1;").ends_with("Error : expected `}`"));
+        assert!(error("print (1;").ends_with("Error : expected `)`, found `;`"));
+        assert!(error("else { }").ends_with("Error : expected a statement, found `else`"));
+        assert!(error("let 1 = 1;").ends_with("Error : expected a variable name, found `1`"));
+        assert_eq!(
+            error("let x = 1;\nprint x + 3000000000;"),
+            "2 | print x + 3000000000;\n^^^^^^^^^^^^^^^^^^^^\n\
+             Error : 3000000000 does not fit in a 32 bit word, use `--word-size 64`"
+        );
+        assert!(error("print -2147483649;")
+            .ends_with("-2147483649 does not fit in a 32 bit word, use `--word-size 64`"));
+        assert_eq!(run("print -2147483648;"), [-2147483648]);
+        let program = compile("print 3000000000;", WordSize::W64).unwrap();
+        assert_eq!(program, vec![Set(A, 3000000000), Drg(A), Hlt]);
+    }
+
+    #[test]
+    fn traps() {
+        let program = compile("let x = 0;\nprint 1 / x;", WordSize::W32).unwrap();
+        let mut vm = Vm::new();
+        assert!(matches!(
+            printed(&program, &mut vm),
+            Err(VmError::DivisionByZero(_))
+        ));
+    }
+
+    #[derive(Clone, Debug)]
+    enum Tree {
+        Int(i8),
+        Var(usize),
+        Neg(Box<Tree>),
+        Binary(usize, Box<Tree>, Box<Tree>),
+    }
+
+    const OPS: [&str; 10] = ["+", "-", "*", "/", "==", "!=", "<", ">", "<=", ">="];
+
+    fn tree() -> impl Strategy<Value = Tree> {
+        let leaf = prop_oneof![
+            any::<i8>().prop_map(Tree::Int),
+            (0..8usize).prop_map(Tree::Var),
+        ];
+        leaf.prop_recursive(6, 64, 2, |inner| {
+            prop_oneof![
+                inner.clone().prop_map(|t| Tree::Neg(Box::new(t))),
+                (0..OPS.len(), inner.clone(), inner).prop_map(|(op, l, r)| Tree::Binary(
+                    op,
+                    Box::new(l),
+                    Box::new(r)
+                )),
+            ]
+        })
+    }
+
+    fn text(tree: &Tree) -> String {
+        match tree {
+            Tree::Int(i) => format!("({})", i),
+            Tree::Var(v) => format!("v{}", v),
+            Tree::Neg(t) => format!("-{}", text(t)),
+            Tree::Binary(op, l, r) => format!("({} {} {})", text(l), OPS[*op], text(r)),
+        }
+    }
+
+    // Value with 32 bit wrapping arithmetic, None on division by 0 or overflow
+    fn eval(tree: &Tree, vars: &[i64]) -> Option<i64> {
+        let value = match tree {
+            Tree::Int(i) => *i as i64,
+            Tree::Var(v) => vars[*v],
+            Tree::Neg(t) => (eval(t, vars)? as i32).wrapping_neg() as i64,
+            Tree::Binary(op, l, r) => {
+                let (l, r) = (eval(l, vars)? as i32, eval(r, vars)? as i32);
+                match OPS[*op] {
+                    "+" => l.wrapping_add(r) as i64,
+                    "-" => l.wrapping_sub(r) as i64,
+                    "*" => l.wrapping_mul(r) as i64,
+                    "/" => l.checked_div(r)? as i64,
+                    "==" => (l == r) as i64,
+                    "!=" => (l != r) as i64,
+                    "<" => (l < r) as i64,
+                    ">" => (l > r) as i64,
+                    "<=" => (l <= r) as i64,
+                    _ => (l >= r) as i64,
+                }
+            }
+        };
+        Some(value)
+    }
+
+    proptest! {
+        #[test]
+        fn evaluates(trees in prop::collection::vec(tree(), 1..4), vars in prop::array::uniform8(any::<i16>())) {
+            let vars: Vec<i64> = vars.iter().map(|v| *v as i64).collect();
+            let mut source = String::new();
+            for (i, v) in vars.iter().enumerate() {
+                source += &format!("let v{} = {};\n", i, v);
+            }
+            let mut expected = vec![];
+            for tree in &trees {
+                match eval(tree, &vars) {
+                    Some(value) => expected.push(value),
+                    None => break,
+                }
+                source += &format!("print {};\n", text(tree));
+            }
+            let program = compile(&source, WordSize::W32).unwrap();
+            prop_assert_eq!(printed(&program, &mut Vm::new()), Ok(expected.clone()));
+            let optimized = optimize(&program, WordSize::W32, STACK_SIZE, |_, _| ());
+            prop_assert_eq!(printed(&optimized, &mut Vm::new()), Ok(expected));
+        }
+    }
+}
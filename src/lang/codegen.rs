@@ -0,0 +1,365 @@
+use super::parser::{Expr, Op, Stmt};
+use super::Error;
+use crate::{Instructions, Instructions::*, Registers, Registers::*};
+use std::collections::HashMap;
+
+// Registers kept for the variables used most, the others hold temporaries
+const VARIABLE_REGISTERS: usize = 4;
+const REGISTERS: [Registers; 6] = [A, B, C, D, E, F];
+
+// Where a variable lives
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Home {
+    Register(Registers),
+    Slot(i64), // Index in the stack, below the spilled temporaries
+}
+
+// A register holding the value of an operand
+#[derive(Copy, Clone, Debug)]
+struct Operand {
+    reg: Registers,
+    temporary: bool, // To be freed once used
+}
+
+struct Codegen {
+    code: Vec<Instructions>,
+    homes: HashMap<String, Home>,
+    free: Vec<Registers>, // Temporaries not holding anything
+    depth: i64,           // Number of values on the stack
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, usize)>, // Jmp at the index, to the label
+}
+
+/// Generates the instructions of the program.
+///
+/// Eq and Of are scratch registers : Eq holds test results and the operands spilled to the
+/// stack, Of is the zero conditions are compared to.
+pub fn generate(program: &[Stmt]) -> Result<Vec<Instructions>, Error> {
+    let mut weights = HashMap::new();
+    let mut order = vec![];
+    declarations(program, 0, &mut weights, &mut order)?;
+
+    // The variables used the most, counting uses in loops more, get registers
+    let mut ranked = order.clone();
+    ranked.sort_by_key(|name| std::cmp::Reverse(weights[name]));
+    let in_registers = ranked.len().min(VARIABLE_REGISTERS);
+    let mut homes = HashMap::new();
+    for (i, name) in ranked.iter().enumerate() {
+        let home = match i < in_registers {
+            true => Home::Register(REGISTERS[i]),
+            false => Home::Slot((i - in_registers) as i64),
+        };
+        homes.insert(name.clone(), home);
+    }
+    let slots = ranked.len() - in_registers;
+
+    let mut gen = Codegen {
+        code: vec![],
+        homes,
+        free: REGISTERS[in_registers..].iter().rev().copied().collect(),
+        depth: 0,
+        labels: vec![],
+        fixups: vec![],
+    };
+    for _ in 0..slots {
+        gen.code.push(Psh(0));
+        gen.depth += 1;
+    }
+    gen.block(program);
+    gen.code.push(Hlt);
+
+    for (ip, label) in gen.fixups {
+        let target = gen.labels[label].unwrap();
+        gen.code[ip] = Jmp(target as i32);
+    }
+    Ok(gen.code)
+}
+
+// Checks that variables are declared once, before being used, and weights their uses
+fn declarations(
+    block: &[Stmt],
+    loops: u32,
+    weights: &mut HashMap<String, u64>,
+    order: &mut Vec<String>,
+) -> Result<(), Error> {
+    let weight = 10u64.saturating_pow(loops);
+    for stmt in block {
+        match stmt {
+            Stmt::Let(name, e, line) => {
+                uses(e, weight, weights)?;
+                if weights.contains_key(name) {
+                    return Err(Error::new(*line, format!("{} is already declared", name)));
+                }
+                weights.insert(name.clone(), weight);
+                order.push(name.clone());
+            }
+            Stmt::Assign(name, e, line) => {
+                uses(e, weight, weights)?;
+                uses(&Expr::Var(name.clone(), *line), weight, weights)?;
+            }
+            Stmt::Print(e) => uses(e, weight, weights)?,
+            Stmt::If(condition, then, otherwise) => {
+                uses(condition, weight, weights)?;
+                declarations(then, loops, weights, order)?;
+                declarations(otherwise, loops, weights, order)?;
+            }
+            Stmt::While(condition, body) => {
+                uses(condition, weight * 10, weights)?;
+                declarations(body, loops + 1, weights, order)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn uses(e: &Expr, weight: u64, weights: &mut HashMap<String, u64>) -> Result<(), Error> {
+    match e {
+        Expr::Int(_) => Ok(()),
+        Expr::Var(name, line) => match weights.get_mut(name) {
+            Some(w) => {
+                *w = w.saturating_add(weight);
+                Ok(())
+            }
+            None => Err(Error::new(*line, format!("{} is not declared", name))),
+        },
+        Expr::Neg(e) => uses(e, weight, weights),
+        Expr::Binary(_, lhs, rhs) => {
+            uses(lhs, weight, weights)?;
+            uses(rhs, weight, weights)
+        }
+    }
+}
+
+// Test setting eq to the result of the comparison, and to its opposite
+fn tests(op: Op, lhs: Registers, rhs: Registers) -> (Instructions, Instructions) {
+    match op {
+        Op::Eq => (Tee(lhs, rhs), Tne(lhs, rhs)),
+        Op::Ne => (Tne(lhs, rhs), Tee(lhs, rhs)),
+        Op::Lt => (Tll(lhs, rhs), Tem(lhs, rhs)),
+        Op::Gt => (Tmm(lhs, rhs), Tel(lhs, rhs)),
+        Op::Le => (Tel(lhs, rhs), Tmm(lhs, rhs)),
+        Op::Ge => (Tem(lhs, rhs), Tll(lhs, rhs)),
+        _ => unreachable!(),
+    }
+}
+
+fn arithmetic(op: Op, lhs: Registers, rhs: Registers) -> Instructions {
+    match op {
+        Op::Add => Add(lhs, rhs),
+        Op::Sub => Sub(lhs, rhs),
+        Op::Mul => Mul(lhs, rhs),
+        Op::Div => Div(lhs, rhs),
+        _ => unreachable!(),
+    }
+}
+
+impl Codegen {
+    fn label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn place(&mut self, label: usize) {
+        self.labels[label] = Some(self.code.len());
+    }
+
+    // Jumps to the label if eq is 1
+    fn jump_if(&mut self, label: usize) {
+        self.fixups.push((self.code.len(), label));
+        self.code.push(Jmp(0));
+    }
+
+    fn jump(&mut self, label: usize) {
+        self.code.push(Set(Eq, 1));
+        self.jump_if(label);
+    }
+
+    fn alloc(&mut self) -> Registers {
+        self.free.pop().expect("no free temporary")
+    }
+
+    fn release(&mut self, operand: Operand) {
+        if operand.temporary {
+            self.free.push(operand.reg);
+        }
+    }
+
+    // Moves sp to `sp`, then back to the top once `access` ran
+    fn at_slot(&mut self, sp: i64, access: Instructions) {
+        self.code.push(Set(Eq, sp));
+        self.code.push(Mov(Sp, Eq));
+        self.code.push(access);
+        self.code.push(Set(Eq, self.depth - 1));
+        self.code.push(Mov(Sp, Eq));
+    }
+
+    fn load(&mut self, slot: i64, dest: Registers) {
+        if slot == self.depth - 1 {
+            self.code.push(Mov(dest, St));
+        } else {
+            self.at_slot(slot, Mov(dest, St));
+        }
+    }
+
+    fn store(&mut self, slot: i64, src: Registers) {
+        if slot == self.depth - 1 {
+            self.code.push(Pop);
+            self.code.push(PshR(src));
+        } else {
+            self.at_slot(slot - 1, PshR(src));
+        }
+    }
+
+    fn block(&mut self, block: &[Stmt]) {
+        for stmt in block {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Let(name, e, _) | Stmt::Assign(name, e, _) => {
+                let value = self.temporary(e);
+                match self.homes[name] {
+                    Home::Register(reg) => self.code.push(Mov(reg, value)),
+                    Home::Slot(slot) => self.store(slot, value),
+                }
+                self.free.push(value);
+            }
+            Stmt::Print(e) => {
+                let value = self.operand(e);
+                self.code.push(Drg(value.reg));
+                self.release(value);
+            }
+            Stmt::If(condition, then, otherwise) => {
+                let (other, end) = (self.label(), self.label());
+                self.jump_unless(condition, other);
+                self.block(then);
+                if !otherwise.is_empty() {
+                    self.jump(end);
+                }
+                self.place(other);
+                self.block(otherwise);
+                self.place(end);
+            }
+            Stmt::While(condition, body) => {
+                let (start, end) = (self.label(), self.label());
+                self.place(start);
+                self.jump_unless(condition, end);
+                self.block(body);
+                self.jump(start);
+                self.place(end);
+            }
+        }
+    }
+
+    fn jump_unless(&mut self, condition: &Expr, label: usize) {
+        match condition {
+            Expr::Binary(op, lhs, rhs) if op.is_comparison() => {
+                let (lhs, rhs) = self.operands(lhs, rhs, false);
+                self.code.push(tests(*op, lhs.reg, rhs.reg).1);
+                self.release(lhs);
+                self.release(rhs);
+            }
+            condition => {
+                let value = self.operand(condition);
+                self.code.push(Set(Of, 0));
+                self.code.push(Tee(value.reg, Of));
+                self.release(value);
+            }
+        }
+        self.jump_if(label);
+    }
+
+    // Register holding the value, variables in registers being read in place
+    fn operand(&mut self, e: &Expr) -> Operand {
+        if let Expr::Var(name, _) = e {
+            if let Home::Register(reg) = self.homes[name] {
+                return Operand {
+                    reg,
+                    temporary: false,
+                };
+            }
+        }
+        Operand {
+            reg: self.temporary(e),
+            temporary: true,
+        }
+    }
+
+    // Evaluates both operands. With no temporary left for the right one, the left one goes
+    // on the stack and comes back in eq. Unless `destructive`, the left one may be a variable.
+    fn operands(&mut self, lhs: &Expr, rhs: &Expr, destructive: bool) -> (Operand, Operand) {
+        let lhs = match destructive {
+            true => Operand {
+                reg: self.temporary(lhs),
+                temporary: true,
+            },
+            false => self.operand(lhs),
+        };
+        let in_place =
+            matches!(rhs, Expr::Var(name, _) if matches!(self.homes[name], Home::Register(_)));
+        if in_place || !self.free.is_empty() {
+            return (lhs, self.operand(rhs));
+        }
+
+        self.code.push(PshR(lhs.reg));
+        self.depth += 1;
+        self.release(lhs);
+        let rhs = self.operand(rhs);
+        self.code.push(PopR(Eq));
+        self.depth -= 1;
+        let lhs = Operand {
+            reg: Eq,
+            temporary: false,
+        };
+        (lhs, rhs)
+    }
+
+    // Evaluates the expression into a new temporary
+    fn temporary(&mut self, e: &Expr) -> Registers {
+        match e {
+            Expr::Int(i) => {
+                let reg = self.alloc();
+                self.code.push(Set(reg, *i));
+                reg
+            }
+            Expr::Var(name, _) => {
+                let reg = self.alloc();
+                match self.homes[name] {
+                    Home::Register(var) => self.code.push(Mov(reg, var)),
+                    Home::Slot(slot) => self.load(slot, reg),
+                }
+                reg
+            }
+            Expr::Neg(e) => {
+                let reg = self.temporary(e);
+                self.code.push(Set(Eq, 0));
+                self.code.push(Sub(Eq, reg));
+                self.code.push(Mov(reg, Eq));
+                reg
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = self.operands(lhs, rhs, !op.is_comparison());
+                let instr = match op.is_comparison() {
+                    true => tests(*op, lhs.reg, rhs.reg).0,
+                    false => arithmetic(*op, lhs.reg, rhs.reg),
+                };
+                self.code.push(instr);
+                // The result goes in a temporary operand, eq being a register like the others
+                let result = match (lhs.temporary, rhs.temporary) {
+                    (true, _) => {
+                        self.release(rhs);
+                        lhs.reg
+                    }
+                    (false, true) => rhs.reg,
+                    (false, false) => self.alloc(),
+                };
+                if op.is_comparison() || lhs.reg == Eq {
+                    self.code.push(Mov(result, Eq));
+                }
+                result
+            }
+        }
+    }
+}
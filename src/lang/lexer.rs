@@ -0,0 +1,131 @@
+use super::Error;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Int(i64),
+    Ident(String),
+    Let,
+    If,
+    Else,
+    While,
+    Print,
+    Punct(&'static str), // Operators, brackets and ;
+    Eof,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub kind: Kind,
+    pub line: usize,
+}
+
+// Longest first, so that `<=` isn't read as `<` followed by `=`
+const PUNCTS: &[&str] = &[
+    "==", "!=", "<=", ">=", "+", "-", "*", "/", "=", "<", ">", "(", ")", "{", "}", ";",
+];
+
+/// Splits the source into tokens, ending with `Eof`. Comments start with `//`.
+pub fn lex(source: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut rest = source;
+    loop {
+        let trimmed = rest.trim_start_matches(|c: char| c.is_whitespace() && c != '\n');
+        rest = trimmed;
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => break,
+        };
+        if c == '\n' {
+            line += 1;
+            rest = &rest[1..];
+        } else if rest.starts_with("//") {
+            rest = rest.find('\n').map_or("", |end| &rest[end..]);
+        } else if c.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let value = rest[..end].parse().map_err(|_| {
+                Error::new(line, format!("{} is not a valid integer", &rest[..end]))
+            })?;
+            tokens.push(Token {
+                kind: Kind::Int(value),
+                line,
+            });
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let kind = match &rest[..end] {
+                "let" => Kind::Let,
+                "if" => Kind::If,
+                "else" => Kind::Else,
+                "while" => Kind::While,
+                "print" => Kind::Print,
+                name => Kind::Ident(name.to_string()),
+            };
+            tokens.push(Token { kind, line });
+            rest = &rest[end..];
+        } else {
+            let punct = PUNCTS
+                .iter()
+                .find(|p| rest.starts_with(*p))
+                .ok_or_else(|| Error::new(line, format!("unexpected character `{}`", c)))?;
+            tokens.push(Token {
+                kind: Kind::Punct(punct),
+                line,
+            });
+            rest = &rest[punct.len()..];
+        }
+    }
+    tokens.push(Token {
+        kind: Kind::Eof,
+        line,
+    });
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lexes() {
+        let tokens = lex("let x = 10; // ten\nwhile x>=0 {\n  x = x-1;\n}").unwrap();
+        let kinds: Vec<(Kind, usize)> = tokens.into_iter().map(|t| (t.kind, t.line)).collect();
+        let ident = |name: &str| Kind::Ident(name.to_string());
+        assert_eq!(
+            kinds,
+            vec![
+                (Kind::Let, 1),
+                (ident("x"), 1),
+                (Kind::Punct("="), 1),
+                (Kind::Int(10), 1),
+                (Kind::Punct(";"), 1),
+                (Kind::While, 2),
+                (ident("x"), 2),
+                (Kind::Punct(">="), 2),
+                (Kind::Int(0), 2),
+                (Kind::Punct("{"), 2),
+                (ident("x"), 3),
+                (Kind::Punct("="), 3),
+                (ident("x"), 3),
+                (Kind::Punct("-"), 3),
+                (Kind::Int(1), 3),
+                (Kind::Punct(";"), 3),
+                (Kind::Punct("}"), 4),
+                (Kind::Eof, 4),
+            ]
+        );
+
+        assert_eq!(
+            lex("x = 1 % 2;"),
+            Err(Error::new(1, "unexpected character `%`"))
+        );
+        assert_eq!(
+            lex("\nx = 12a;"),
+            Err(Error::new(2, "12a is not a valid integer"))
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use super::lexer::{Kind, Token};
+use super::Error;
+use crate::WordSize;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl Op {
+    pub fn is_comparison(self) -> bool {
+        !matches!(self, Op::Add | Op::Sub | Op::Mul | Op::Div)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Int(i64),
+    Var(String, usize), // Name and line
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stmt {
+    Let(String, Expr, usize), // Declares the variable, at the line
+    Assign(String, Expr, usize),
+    Print(Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>), // Condition, then and else blocks
+    While(Expr, Vec<Stmt>),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+    word_size: WordSize, // Of the integer literals
+}
+
+/// Parses the statements of a program, whose integers must fit in `word_size`.
+pub fn parse(tokens: Vec<Token>, word_size: WordSize) -> Result<Vec<Stmt>, Error> {
+    let mut parser = Parser {
+        tokens,
+        next: 0,
+        word_size,
+    };
+    let mut program = vec![];
+    while parser.peek() != &Kind::Eof {
+        program.push(parser.statement()?);
+    }
+    Ok(program)
+}
+
+impl Parser {
+    fn peek(&self) -> &Kind {
+        &self.tokens[self.next].kind
+    }
+
+    fn line(&self) -> usize {
+        self.tokens[self.next].line
+    }
+
+    fn advance(&mut self) -> Kind {
+        let kind = self.tokens[self.next].kind.clone();
+        if kind != Kind::Eof {
+            self.next += 1;
+        }
+        kind
+    }
+
+    // Error about the next token, expected to be `what`
+    fn unexpected<T>(&self, what: &str) -> Result<T, Error> {
+        let found = match self.peek() {
+            Kind::Int(i) => i.to_string(),
+            Kind::Ident(name) => name.clone(),
+            Kind::Let => String::from("let"),
+            Kind::If => String::from("if"),
+            Kind::Else => String::from("else"),
+            Kind::While => String::from("while"),
+            Kind::Print => String::from("print"),
+            Kind::Punct(p) => p.to_string(),
+            Kind::Eof => return Err(Error::new(self.line(), format!("expected {}", what))),
+        };
+        Err(Error::new(
+            self.line(),
+            format!("expected {}, found `{}`", what, found),
+        ))
+    }
+
+    fn eat(&mut self, punct: &'static str) -> bool {
+        let found = self.peek() == &Kind::Punct(punct);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn expect(&mut self, punct: &'static str) -> Result<(), Error> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            self.unexpected(&format!("`{}`", punct))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, Error> {
+        match self.peek().clone() {
+            Kind::Ident(name) => {
+                self.advance();
+                Ok(name)
+            }
+            _ => self.unexpected("a variable name"),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Stmt, Error> {
+        let line = self.line();
+        let stmt = match self.peek() {
+            Kind::Let => {
+                self.advance();
+                let name = self.name()?;
+                self.expect("=")?;
+                Stmt::Let(name, self.expression()?, line)
+            }
+            Kind::Ident(_) => {
+                let name = self.name()?;
+                self.expect("=")?;
+                Stmt::Assign(name, self.expression()?, line)
+            }
+            Kind::Print => {
+                self.advance();
+                Stmt::Print(self.expression()?)
+            }
+            Kind::If => return self.if_statement(),
+            Kind::While => {
+                self.advance();
+                let condition = self.expression()?;
+                return Ok(Stmt::While(condition, self.block()?));
+            }
+            _ => return self.unexpected("a statement"),
+        };
+        self.expect(";")?;
+        Ok(stmt)
+    }
+
+    // if <expr> { ... } [else { ... } | else if ...]
+    fn if_statement(&mut self) -> Result<Stmt, Error> {
+        self.advance();
+        let condition = self.expression()?;
+        let then = self.block()?;
+        let otherwise = if self.peek() == &Kind::Else {
+            self.advance();
+            if self.peek() == &Kind::If {
+                vec![self.if_statement()?]
+            } else {
+                self.block()?
+            }
+        } else {
+            vec![]
+        };
+        Ok(Stmt::If(condition, then, otherwise))
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, Error> {
+        self.expect("{")?;
+        let mut statements = vec![];
+        while !self.eat("}") {
+            if self.peek() == &Kind::Eof {
+                return self.unexpected("`}`");
+            }
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    // Comparisons don't chain : `a < b < c` is an error
+    fn expression(&mut self) -> Result<Expr, Error> {
+        let lhs = self.additive()?;
+        let op = match self.peek() {
+            Kind::Punct("==") => Op::Eq,
+            Kind::Punct("!=") => Op::Ne,
+            Kind::Punct("<") => Op::Lt,
+            Kind::Punct(">") => Op::Gt,
+            Kind::Punct("<=") => Op::Le,
+            Kind::Punct(">=") => Op::Ge,
+            _ => return Ok(lhs),
+        };
+        self.advance();
+        let rhs = self.additive()?;
+        Ok(Expr::Binary(op, Box::new(lhs), Box::new(rhs)))
+    }
+
+    fn additive(&mut self) -> Result<Expr, Error> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Kind::Punct("+") => Op::Add,
+                Kind::Punct("-") => Op::Sub,
+                _ => return Ok(lhs),
+            };
+            self.advance();
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, Error> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Kind::Punct("*") => Op::Mul,
+                Kind::Punct("/") => Op::Div,
+                _ => return Ok(lhs),
+            };
+            self.advance();
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    // Unary expression, negated literals being checked once negated
+    fn unary(&mut self) -> Result<Expr, Error> {
+        let line = self.line();
+        match self.negation()? {
+            Expr::Int(i) if self.word_size == WordSize::W32 && i as i32 as i64 != i => {
+                Err(Error::new(
+                    line,
+                    format!("{} does not fit in a 32 bit word, use `--word-size 64`", i),
+                ))
+            }
+            e => Ok(e),
+        }
+    }
+
+    fn negation(&mut self) -> Result<Expr, Error> {
+        if self.eat("-") {
+            return Ok(match self.negation()? {
+                Expr::Int(i) => Expr::Int(i.wrapping_neg()),
+                e => Expr::Neg(Box::new(e)),
+            });
+        }
+        let line = self.line();
+        match self.peek().clone() {
+            Kind::Int(i) => {
+                self.advance();
+                Ok(Expr::Int(i))
+            }
+            Kind::Ident(name) => {
+                self.advance();
+                Ok(Expr::Var(name, line))
+            }
+            Kind::Punct("(") => {
+                self.advance();
+                let e = self.expression()?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            _ => self.unexpected("an expression"),
+        }
+    }
+}
@@ -0,0 +1,1722 @@
+use crate::FloatRegisters::*;
+use crate::Instructions::*;
+use crate::Registers::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+pub mod analysis;
+pub mod bytecode;
+pub mod c;
+pub mod cfg;
+pub mod coverage;
+pub mod debugger;
+pub mod disasm;
+pub mod engine;
+pub mod heap;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
+pub mod lang;
+pub mod optimize;
+pub mod parser;
+pub mod profile;
+pub mod snapshot;
+pub mod syscall;
+pub mod trace;
+pub mod wat;
+
+pub const STACK_SIZE: usize = 255;
+
+use heap::*;
+use syscall::*;
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Instructions {
+    Psh(i64),
+    Add(Registers, Registers),
+    Mul(Registers, Registers),
+    Div(Registers, Registers),
+    Sub(Registers, Registers),
+    Pop,
+    PshR(Registers), // Pushes the register's content
+    PopR(Registers), // Pops the stack into the register
+    Dup,             // a -> a a
+    Swp,             // a b -> b a
+    Ovr,             // a b -> a b a
+    Rot,             // a b c -> b c a
+    AddS,            // Stack variants: pop b, pop a, push a op b
+    SubS,
+    MulS,
+    DivS,
+    Mov(Registers, Registers),
+    Set(Registers, i64), // Sets the register to the integer
+    Hlt,
+    HltR(Registers), // Stops with the register's content as exit code
+    Dst,
+    Drg(Registers),
+    Dmp,
+    Prt(Registers), // Prints the ascii letter corresponding of the register's content
+    Tee(Registers, Registers), // ==
+    Tne(Registers, Registers), // !=
+    Tll(Registers, Registers), // <
+    Tmm(Registers, Registers), // >
+    Tel(Registers, Registers), // <=
+    Tem(Registers, Registers), // >=
+    Jmp(i32),       // Jump to line if Eq is true
+    Fld(FloatRegisters, u64), // Loads an f64, stored as its bits
+    Fmv(FloatRegisters, FloatRegisters),
+    Fad(FloatRegisters, FloatRegisters),
+    Fsb(FloatRegisters, FloatRegisters),
+    Fml(FloatRegisters, FloatRegisters),
+    Fdv(FloatRegisters, FloatRegisters),
+    Fee(FloatRegisters, FloatRegisters), // ==
+    Fne(FloatRegisters, FloatRegisters), // !=
+    Fll(FloatRegisters, FloatRegisters), // <
+    Fmm(FloatRegisters, FloatRegisters), // >
+    Fel(FloatRegisters, FloatRegisters), // <=
+    Fem(FloatRegisters, FloatRegisters), // >=
+    Itf(FloatRegisters, Registers),      // Integer to float
+    Fti(Registers, FloatRegisters),      // Float to integer, rounding towards zero
+    Fpr(FloatRegisters),                 // Prints the float register's content
+    Alc(Registers, Registers),           // Allocates <size> words, address in <dest>
+    Fre(Registers),                      // Frees the block at the address
+    Lod(Registers, Registers),           // <dest> = memory[<address>]
+    Sto(Registers, Registers),           // memory[<address>] = <src>
+    Sys(u32),                            // Calls the host function registered with this number
+    Snp,                                 // Calls the handler set with `Vm::on_snapshot`
+}
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Registers {
+    A = 0,
+    B = 1,
+    C = 2,
+    D = 3,
+    E = 4,
+    F = 5,
+    Ip = 6,
+    Sp = 7,
+    St = 8,
+    Eq = 9,
+    Of = 10, // Set to 1 when the last add/sub/mul/div overflowed
+    NumOfRegisters = 11,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FloatRegisters {
+    Fa = 0,
+    Fb = 1,
+    Fc = 2,
+    Fd = 3,
+    Fe = 4,
+    Ff = 5,
+    NumOfFloatRegisters = 6,
+}
+
+impl FloatRegisters {
+    pub fn from_index(index: usize) -> Option<FloatRegisters> {
+        [Fa, Fb, Fc, Fd, Fe, Ff].get(index).copied()
+    }
+}
+
+impl Registers {
+    pub fn from_index(index: usize) -> Option<Registers> {
+        [A, B, C, D, E, F, Ip, Sp, St, Eq, Of].get(index).copied()
+    }
+}
+
+/// What `add`, `sub`, `mul` and `div` do when the result does not fit in a register.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OverflowMode {
+    Wrapping,   // Two's complement wrap around
+    Saturating, // Clamp to the smallest / biggest word
+    Trapping,   // Stop the program with ERR_ARITHMETIC_OVERFLOW
+}
+
+impl OverflowMode {
+    pub fn from_name(name: &str) -> Option<OverflowMode> {
+        match name {
+            "wrap" | "wrapping" => Some(OverflowMode::Wrapping),
+            "saturate" | "saturating" => Some(OverflowMode::Saturating),
+            "trap" | "trapping" => Some(OverflowMode::Trapping),
+            _ => None,
+        }
+    }
+}
+
+/// Width of registers, stack slots and immediates. Values are always stored as i64, the
+/// 32 bit mode wraps, saturates or traps at the i32 bounds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum WordSize {
+    #[default]
+    W32,
+    W64,
+}
+
+impl WordSize {
+    pub fn from_bits(bits: &str) -> Option<WordSize> {
+        match bits {
+            "32" => Some(WordSize::W32),
+            "64" => Some(WordSize::W64),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u8 {
+        match self {
+            WordSize::W32 => 32,
+            WordSize::W64 => 64,
+        }
+    }
+}
+
+/// Errors raised while running a program. Each variant holds the value of Ip when it happened.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+    ArithmeticOverflow(i64),
+    DivisionByZero(i64),
+    StackOverflow(i64),
+    StackUnderflow(i64),
+    ReadOnlyRegister(i64),
+    InvalidStackPointer(i64),
+    InvalidJump(i64),
+    Memory(HeapError, i64),
+    UnknownSyscall(u32, i64),
+    OutOfFuel(i64),
+    Io(i64), // The output failed
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::ArithmeticOverflow(ip) => {
+                write!(f, "ERR_ARITHMETIC_OVERFLOW at instruction {}", ip)
+            }
+            VmError::DivisionByZero(ip) => write!(f, "ERR_DIVISION_BY_ZERO at instruction {}", ip),
+            VmError::StackOverflow(ip) => write!(f, "ERR_STACK_OVERFLOW at instruction {}", ip),
+            VmError::StackUnderflow(ip) => write!(f, "ERR_STACK_UNDERFLOW at instruction {}", ip),
+            VmError::ReadOnlyRegister(ip) => {
+                write!(f, "ERR_READ_ONLY_REGISTER at instruction {}", ip)
+            }
+            VmError::InvalidStackPointer(ip) => {
+                write!(f, "ERR_INVALID_STACK_POINTER at instruction {}", ip)
+            }
+            VmError::InvalidJump(ip) => write!(f, "ERR_INVALID_JUMP at instruction {}", ip),
+            VmError::Memory(e, ip) => write!(f, "{} at instruction {}", e, ip),
+            VmError::UnknownSyscall(n, ip) => {
+                write!(f, "ERR_UNKNOWN_SYSCALL {} at instruction {}", n, ip)
+            }
+            VmError::OutOfFuel(ip) => write!(f, "ERR_OUT_OF_FUEL at instruction {}", ip),
+            VmError::Io(ip) => write!(f, "ERR_IO at instruction {}", ip),
+        }
+    }
+}
+
+fn reg_name(reg: i32) -> &'static str {
+    match reg {
+        0 => "A",
+        1 => "B",
+        2 => "C",
+        3 => "D",
+        4 => "E",
+        5 => "F",
+        6 => "Ip",
+        7 => "Sp",
+        8 => "St",
+        9 => "Eq",
+        10 => "Of",
+        _ => "_ ",
+    }
+}
+
+fn fetch(program: &[Instructions], ip: usize) -> Instructions {
+    program[ip]
+}
+
+pub fn dump(
+    out: &mut dyn Write,
+    stack: &[i64],
+    regs: &[i64; NumOfRegisters as usize],
+    fregs: &[f64; NumOfFloatRegisters as usize],
+) -> io::Result<()> {
+    write!(out, "[")?;
+    for (i, reg) in regs.iter().enumerate() {
+        write!(out, "{}: {}, ", reg_name(i as i32), reg)?;
+    }
+    writeln!(out, "]")?;
+    write!(out, "[")?;
+    for (i, reg) in fregs.iter().enumerate() {
+        write!(
+            out,
+            "{:?}: {}, ",
+            FloatRegisters::from_index(i).unwrap(),
+            reg
+        )?;
+    }
+    writeln!(out, "]")?;
+    writeln!(out)?;
+    write!(out, "Stack : [")?;
+    for (i, value) in stack.iter().enumerate() {
+        if i > 0 {
+            write!(out, ", ")?;
+        }
+        write!(out, "{}", value)?;
+    }
+    writeln!(out, "]")
+}
+
+/// The stack grows upwards from slot 0 :
+/// - Sp is the index of the topmost value, -1 when the stack is empty
+/// - St is the topmost value, 0 when the stack is empty
+/// - Slots above Sp are dead and may hold stale values
+///
+/// Pushing onto a full stack or popping/reading an empty one is an error.
+pub struct Vm {
+    pub stack: Vec<i64>,
+    pub regs: [i64; NumOfRegisters as usize],
+    pub fregs: [f64; NumOfFloatRegisters as usize],
+    pub heap: Heap,
+    pub running: bool,
+    pub details: bool,
+    pub overflow: OverflowMode,
+    pub word_size: WordSize,
+    pub exit_code: i64,
+    pub fuel: Option<u64>, // Number of instructions left to run, unlimited if None
+    pub output: Box<dyn Write>, // Where the program prints, stdout by default, failing with Io
+    pub written: u64,      // Number of bytes printed
+    syscalls: HashMap<u32, Syscall>,
+    snapshot: Option<snapshot::Handler>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm::with_stack_size(STACK_SIZE)
+    }
+
+    pub fn with_stack_size(stack_size: usize) -> Vm {
+        let (stack, regs, running) = setup_environment(stack_size);
+
+        let mut vm = Vm {
+            stack,
+            regs,
+            fregs: [0.0; NumOfFloatRegisters as usize],
+            heap: Heap::new(HEAP_SIZE),
+            running,
+            details: false,
+            overflow: OverflowMode::Wrapping,
+            word_size: WordSize::W32,
+            exit_code: 0,
+            fuel: None,
+            output: Box::new(io::stdout()),
+            written: 0,
+            syscalls: HashMap::new(),
+            snapshot: None,
+        };
+        syscall::register_standard(&mut vm);
+        vm
+    }
+
+    /// Makes `sys <n>` call `handler`, replacing the previous handler of `n` if any.
+    pub fn register_syscall<F>(&mut self, n: u32, handler: F)
+    where
+        F: Fn(&mut Vm) -> Result<(), VmError> + 'static,
+    {
+        self.syscalls.insert(n, std::rc::Rc::new(handler));
+    }
+
+    /// Makes `snp` call `handler`, with ip already on the next instruction so that a snapshot
+    /// taken by the handler resumes after `snp`. Without handler, `snp` does nothing.
+    pub fn on_snapshot<F>(&mut self, handler: F)
+    where
+        F: Fn(&Vm) + 'static,
+    {
+        self.snapshot = Some(std::rc::Rc::new(handler));
+    }
+
+    // Writes to the output, counting the bytes written
+    pub(crate) fn print(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    // Prints for the instruction at ip, failing if the output does
+    fn print_at_ip(&mut self, bytes: &[u8]) -> Result<(), VmError> {
+        let ip = self.regs[Ip as usize];
+        self.print(bytes).map_err(|_| VmError::Io(ip))
+    }
+
+    pub fn run(&mut self, program: &[Instructions]) -> Result<(), VmError> {
+        // Runs until Hlt or the first error
+        while self.running {
+            self.step(program)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the instruction at Ip.
+    pub fn step(&mut self, program: &[Instructions]) -> Result<(), VmError> {
+        let ip = self.regs[Ip as usize];
+        if ip < 0 || ip as usize >= program.len() {
+            return Err(VmError::InvalidJump(ip));
+        }
+        if let Some(fuel) = self.fuel {
+            if fuel == 0 {
+                return Err(VmError::OutOfFuel(ip));
+            }
+            self.fuel = Some(fuel - 1);
+        }
+        let instr = fetch(program, ip as usize);
+        self.eval(instr)?;
+        self.regs[Ip as usize] += 1;
+        Ok(())
+    }
+
+    // Computes `lhs op rhs` following the overflow mode, and records the overflow in Of.
+    fn arithmetic(
+        &mut self,
+        lhs: i64,
+        rhs: i64,
+        overflowing: fn(i64, i64) -> (i64, bool),
+        saturating: fn(i64, i64) -> i64,
+    ) -> Result<i64, VmError> {
+        let (lhs, rhs) = (self.narrow(lhs), self.narrow(rhs));
+        let (wrapped, overflowed, saturated) = match self.word_size {
+            WordSize::W64 => {
+                let (wrapped, overflowed) = overflowing(lhs, rhs);
+                (wrapped, overflowed, saturating(lhs, rhs))
+            }
+            WordSize::W32 => {
+                // The exact result of two 32 bit operands always fits in 64 bits
+                let (exact, _) = overflowing(lhs, rhs);
+                let wrapped = self.narrow(exact);
+                let saturated = exact.clamp(i32::MIN as i64, i32::MAX as i64);
+                (wrapped, wrapped != exact, saturated)
+            }
+        };
+
+        if overflowed && self.overflow == OverflowMode::Trapping {
+            return Err(VmError::ArithmeticOverflow(self.regs[Ip as usize]));
+        }
+
+        self.regs[Of as usize] = overflowed as i64;
+        Ok(match self.overflow {
+            OverflowMode::Saturating => saturated,
+            _ => wrapped,
+        })
+    }
+
+    // Wraps `value` to the word size
+    fn narrow(&self, value: i64) -> i64 {
+        match self.word_size {
+            WordSize::W32 => value as i32 as i64,
+            WordSize::W64 => value,
+        }
+    }
+
+    fn register_arithmetic(
+        &mut self,
+        a: Registers,
+        b: Registers,
+        overflowing: fn(i64, i64) -> (i64, bool),
+        saturating: fn(i64, i64) -> i64,
+    ) -> Result<(), VmError> {
+        self.writable(a)?;
+        let (lhs, rhs) = (self.regs[a as usize], self.regs[b as usize]);
+        let result = self.arithmetic(lhs, rhs, overflowing, saturating)?;
+        self.write_register(a, result)
+    }
+
+    fn writable(&self, reg: Registers) -> Result<(), VmError> {
+        if reg == St {
+            return Err(VmError::ReadOnlyRegister(self.regs[Ip as usize]));
+        }
+        Ok(())
+    }
+
+    /// Writes a register from a program instruction :
+    /// - St is read only
+    /// - Sp has to stay within the stack, and St follows the new top
+    /// - Writing Ip is the same as jumping to the written value
+    pub fn write_register(&mut self, reg: Registers, value: i64) -> Result<(), VmError> {
+        self.writable(reg)?;
+        match reg {
+            Sp => {
+                if value < -1 || value >= self.stack.len() as i64 {
+                    return Err(VmError::InvalidStackPointer(self.regs[Ip as usize]));
+                }
+                self.regs[Sp as usize] = value;
+                self.regs[St as usize] = self.peek(0).unwrap_or(0);
+            }
+            Ip => self.jump(value)?,
+            _ => self.regs[reg as usize] = value,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn report_leaks(&self) {
+        let leaks = self.heap.leaks();
+        if leaks.is_empty() {
+            return;
+        }
+        let words: usize = leaks.iter().map(|(_, size)| size).sum();
+        eprintln!(
+            "Leak : {} block(s) of {} word(s) still allocated",
+            leaks.len(),
+            words
+        );
+        for (addr, size) in leaks {
+            eprintln!("\t{} word(s) at {}", size, addr);
+        }
+    }
+
+    // Float operations follow IEEE 754 : no overflow or division by zero errors
+    fn float_arithmetic(
+        &mut self,
+        a: FloatRegisters,
+        b: FloatRegisters,
+        symbol: &str,
+        op: fn(f64, f64) -> f64,
+    ) {
+        let (lhs, rhs) = (self.fregs[a as usize], self.fregs[b as usize]);
+        if self.details {
+            println!("{} {} {}", lhs, symbol, rhs);
+        }
+        self.fregs[a as usize] = op(lhs, rhs);
+    }
+
+    // Comparisons involving NaN are false, except !=
+    fn float_test(
+        &mut self,
+        a: FloatRegisters,
+        b: FloatRegisters,
+        symbol: &str,
+        test: fn(f64, f64) -> bool,
+    ) {
+        let (lhs, rhs) = (self.fregs[a as usize], self.fregs[b as usize]);
+        if self.details {
+            println!("{} {} {}", lhs, symbol, rhs);
+        }
+        self.regs[Eq as usize] = test(lhs, rhs) as i64;
+    }
+
+    // Makes `target` the next instruction to run
+    fn jump(&mut self, target: i64) -> Result<(), VmError> {
+        if target < 0 {
+            return Err(VmError::InvalidJump(self.regs[Ip as usize]));
+        }
+        self.regs[Ip as usize] = target - 1;
+        Ok(())
+    }
+
+    // Replaces the two topmost values by `second op top`. The stack is left untouched on error.
+    fn stack_arithmetic(
+        &mut self,
+        overflowing: fn(i64, i64) -> (i64, bool),
+        saturating: fn(i64, i64) -> i64,
+    ) -> Result<(), VmError> {
+        let rhs = self.peek(0)?;
+        let lhs = self.peek(1)?;
+        let result = self.arithmetic(lhs, rhs, overflowing, saturating)?;
+
+        self.pop()?;
+        self.pop()?;
+        self.push(result)
+    }
+
+    fn push(&mut self, value: i64) -> Result<(), VmError> {
+        if (self.regs[Sp as usize] + 1) as usize >= self.stack.len() {
+            return Err(VmError::StackOverflow(self.regs[Ip as usize]));
+        }
+        self.regs[Sp as usize] += 1;
+        self.stack[self.regs[Sp as usize] as usize] = value;
+        self.regs[St as usize] = value;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i64, VmError> {
+        let popped = self.peek(0)?;
+
+        self.regs[Sp as usize] -= 1;
+        self.regs[St as usize] = self.peek(0).unwrap_or(0);
+        Ok(popped)
+    }
+
+    /// The values currently on the stack, from bottom to top.
+    pub fn live_stack(&self) -> &[i64] {
+        let len = (self.regs[Sp as usize] + 1).max(0) as usize;
+        &self.stack[..len.min(self.stack.len())]
+    }
+
+    // Reads the value `depth` slots below the top of the stack.
+    fn peek(&self, depth: i64) -> Result<i64, VmError> {
+        let sp = self.regs[Sp as usize] - depth;
+        if sp < 0 {
+            return Err(VmError::StackUnderflow(self.regs[Ip as usize]));
+        }
+        Ok(self.stack[sp as usize])
+    }
+
+    pub fn eval(&mut self, instr: Instructions) -> Result<(), VmError> {
+        let details = self.details;
+        let stack = &mut self.stack;
+        let regs = &mut self.regs;
+
+        // Instrucion Pointer : regs[6]
+        // Stack Pointer : regs[7]
+
+        if details {
+            print!("{} - ", regs[6]);
+        }
+
+        match instr {
+            Dmp => {
+                let mut text = vec![];
+                dump(&mut text, stack, regs, &self.fregs).unwrap();
+                self.print_at_ip(&text)?;
+            }
+            Prt(reg) => {
+                if (0..256).contains(&regs[reg as usize]) {
+                    let letter = (regs[reg as usize] as u8 as char).to_string();
+                    self.print_at_ip(letter.as_bytes())?;
+                    let ip = self.regs[Ip as usize];
+                    self.output.flush().map_err(|_| VmError::Io(ip))?;
+                }
+            }
+            Tee(a, b) => {
+                if details {
+                    println!("{} == {}", regs[a as usize], regs[b as usize]);
+                }
+                regs[Eq as usize] = (regs[a as usize] == regs[b as usize]) as i64;
+            }
+            Tne(a, b) => {
+                if details {
+                    println!("{} != {}", regs[a as usize], regs[b as usize]);
+                }
+                regs[Eq as usize] = (regs[a as usize] != regs[b as usize]) as i64;
+            }
+            Tll(a, b) => {
+                if details {
+                    println!("{} < {}", regs[a as usize], regs[b as usize]);
+                }
+                regs[Eq as usize] = (regs[a as usize] < regs[b as usize]) as i64;
+            }
+            Tmm(a, b) => {
+                if details {
+                    println!("{} > {}", regs[a as usize], regs[b as usize]);
+                }
+                regs[Eq as usize] = (regs[a as usize] > regs[b as usize]) as i64;
+            }
+            Tel(a, b) => {
+                if details {
+                    println!("{}  <= {}", regs[a as usize], regs[b as usize]);
+                }
+                regs[Eq as usize] = (regs[a as usize] <= regs[b as usize]) as i64;
+            }
+            Tem(a, b) => {
+                if details {
+                    println!("{}  >= {}", regs[a as usize], regs[b as usize]);
+                }
+                regs[Eq as usize] = (regs[a as usize] >= regs[b as usize]) as i64;
+            }
+            Jmp(i) => {
+                if details {
+                    println!("Jumped to {}", i);
+                }
+                if regs[Eq as usize] == 1 {
+                    if details {
+                        println!("Goto {}", i);
+                    }
+                    return self.jump(i as i64);
+                } else if details {
+                    println!("None");
+                }
+            }
+            Hlt | HltR(_) => {
+                if let HltR(reg) = instr {
+                    self.exit_code = regs[reg as usize];
+                }
+                if details {
+                    println!("Quit");
+                }
+                self.running = false;
+                self.report_leaks();
+            }
+            Psh(i) => {
+                if details {
+                    println!("-> {}", i);
+                }
+                let value = self.narrow(i);
+                return self.push(value);
+            }
+            PshR(reg) => {
+                if details {
+                    println!("-> {}", regs[reg as usize]);
+                }
+                let value = regs[reg as usize];
+                return self.push(value);
+            }
+            PopR(reg) => {
+                self.writable(reg)?;
+                let popped = self.pop()?;
+                if details {
+                    println!("<- {}", popped);
+                }
+                return self.write_register(reg, popped);
+            }
+            Dup => {
+                let top = self.peek(0)?;
+                if details {
+                    println!("-> {}", top);
+                }
+                return self.push(top);
+            }
+            Ovr => {
+                let second = self.peek(1)?;
+                if details {
+                    println!("-> {}", second);
+                }
+                return self.push(second);
+            }
+            Swp => {
+                self.peek(1)?;
+                let sp = self.regs[Sp as usize] as usize;
+                self.stack.swap(sp - 1, sp);
+                self.regs[St as usize] = self.stack[sp];
+                if details {
+                    println!("{} <-> {}", self.stack[sp - 1], self.stack[sp]);
+                }
+            }
+            Rot => {
+                self.peek(2)?;
+                let sp = self.regs[Sp as usize] as usize;
+                self.stack[sp - 2..=sp].rotate_left(1);
+                self.regs[St as usize] = self.stack[sp];
+                if details {
+                    println!("-> {}", self.stack[sp]);
+                }
+            }
+            Pop => {
+                let popped = self.pop()?;
+                if details {
+                    println!("<- {}", popped);
+                }
+            }
+            Add(a, b) => {
+                if details {
+                    println!("{} + {}", regs[a as usize], regs[b as usize]);
+                }
+                return self.register_arithmetic(a, b, i64::overflowing_add, i64::saturating_add);
+            }
+            Sub(a, b) => {
+                if details {
+                    println!("{} - {}", regs[a as usize], regs[b as usize]);
+                }
+                return self.register_arithmetic(a, b, i64::overflowing_sub, i64::saturating_sub);
+            }
+            Mul(a, b) => {
+                if details {
+                    println!("{} * {}", regs[a as usize], regs[b as usize]);
+                }
+                return self.register_arithmetic(a, b, i64::overflowing_mul, i64::saturating_mul);
+            }
+            Div(a, b) => {
+                if details {
+                    println!("{} / {}", regs[a as usize], regs[b as usize]);
+                }
+                if regs[b as usize] == 0 {
+                    return Err(VmError::DivisionByZero(regs[Ip as usize]));
+                }
+                return self.register_arithmetic(a, b, i64::overflowing_div, i64::saturating_div);
+            }
+            AddS => {
+                if details {
+                    println!("{} + {}", self.peek(1)?, self.peek(0)?);
+                }
+                return self.stack_arithmetic(i64::overflowing_add, i64::saturating_add);
+            }
+            SubS => {
+                if details {
+                    println!("{} - {}", self.peek(1)?, self.peek(0)?);
+                }
+                return self.stack_arithmetic(i64::overflowing_sub, i64::saturating_sub);
+            }
+            MulS => {
+                if details {
+                    println!("{} * {}", self.peek(1)?, self.peek(0)?);
+                }
+                return self.stack_arithmetic(i64::overflowing_mul, i64::saturating_mul);
+            }
+            DivS => {
+                if details {
+                    println!("{} / {}", self.peek(1)?, self.peek(0)?);
+                }
+                if self.peek(0)? == 0 {
+                    return Err(VmError::DivisionByZero(self.regs[Ip as usize]));
+                }
+                return self.stack_arithmetic(i64::overflowing_div, i64::saturating_div);
+            }
+            Mov(a, b) => {
+                if details {
+                    println!("{} <-| {}", reg_name(a as i32), reg_name(b as i32));
+                }
+                let value = regs[b as usize];
+                return self.write_register(a, value);
+            }
+            Set(reg, i) => {
+                if details {
+                    println!("{} <- {}", reg_name(reg as i32), i);
+                }
+                let value = self.narrow(i);
+                return self.write_register(reg, value);
+            }
+            Drg(reg) => {
+                let text = format!("[{}]\n", regs[reg as usize]);
+                self.print_at_ip(text.as_bytes())?;
+            }
+            Fld(f, bits) => {
+                self.fregs[f as usize] = f64::from_bits(bits);
+                if details {
+                    println!("{:?} <- {}", f, self.fregs[f as usize]);
+                }
+            }
+            Fmv(a, b) => {
+                if details {
+                    println!("{:?} <-| {:?}", a, b);
+                }
+                self.fregs[a as usize] = self.fregs[b as usize];
+            }
+            Fad(a, b) => self.float_arithmetic(a, b, "+", |x, y| x + y),
+            Fsb(a, b) => self.float_arithmetic(a, b, "-", |x, y| x - y),
+            Fml(a, b) => self.float_arithmetic(a, b, "*", |x, y| x * y),
+            Fdv(a, b) => self.float_arithmetic(a, b, "/", |x, y| x / y),
+            Fee(a, b) => self.float_test(a, b, "==", |x, y| x == y),
+            Fne(a, b) => self.float_test(a, b, "!=", |x, y| x != y),
+            Fll(a, b) => self.float_test(a, b, "<", |x, y| x < y),
+            Fmm(a, b) => self.float_test(a, b, ">", |x, y| x > y),
+            Fel(a, b) => self.float_test(a, b, "<=", |x, y| x <= y),
+            Fem(a, b) => self.float_test(a, b, ">=", |x, y| x >= y),
+            Itf(f, reg) => {
+                self.fregs[f as usize] = regs[reg as usize] as f64;
+                if details {
+                    println!("{:?} <- {}", f, self.fregs[f as usize]);
+                }
+            }
+            Fti(reg, f) => {
+                // NaN gives 0, out of range values saturate to the word bounds
+                let value = match self.word_size {
+                    WordSize::W32 => self.fregs[f as usize] as i32 as i64,
+                    WordSize::W64 => self.fregs[f as usize] as i64,
+                };
+                if details {
+                    println!("{} <- {}", reg_name(reg as i32), value);
+                }
+                return self.write_register(reg, value);
+            }
+            Fpr(f) => {
+                let text = format!("[{}]\n", self.fregs[f as usize]);
+                self.print_at_ip(text.as_bytes())?;
+            }
+            Alc(size, dest) => {
+                self.writable(dest)?;
+                let (size, ip) = (self.regs[size as usize], self.regs[Ip as usize]);
+                let addr = self.heap.alloc(size).map_err(|e| VmError::Memory(e, ip))?;
+                if details {
+                    println!("{} words at {}", size, addr);
+                }
+                return self.write_register(dest, addr as i64);
+            }
+            Fre(ptr) => {
+                if details {
+                    println!("Free {}", regs[ptr as usize]);
+                }
+                self.heap
+                    .free(regs[ptr as usize])
+                    .map_err(|e| VmError::Memory(e, regs[Ip as usize]))?;
+            }
+            Lod(dest, ptr) => {
+                let value = self
+                    .heap
+                    .load(regs[ptr as usize])
+                    .map_err(|e| VmError::Memory(e, regs[Ip as usize]))?;
+                if details {
+                    println!("{} <- [{}]", value, regs[ptr as usize]);
+                }
+                return self.write_register(dest, value);
+            }
+            Sto(ptr, src) => {
+                if details {
+                    println!("[{}] <- {}", regs[ptr as usize], regs[src as usize]);
+                }
+                self.heap
+                    .store(regs[ptr as usize], regs[src as usize])
+                    .map_err(|e| VmError::Memory(e, regs[Ip as usize]))?;
+            }
+            Sys(n) => {
+                if details {
+                    println!("Syscall {}", n);
+                }
+                let handler = match self.syscalls.get(&n) {
+                    Some(handler) => handler.clone(),
+                    None => return Err(VmError::UnknownSyscall(n, regs[Ip as usize])),
+                };
+                return handler(self);
+            }
+            Dst => {
+                let len = (regs[Sp as usize] + 1).max(0) as usize;
+                let text: String = stack[..len.min(stack.len())]
+                    .iter()
+                    .map(|val| format!("[{}]\n", val))
+                    .collect();
+                self.print_at_ip(text.as_bytes())?;
+            }
+            Snp => {
+                if let Some(handler) = self.snapshot.clone() {
+                    self.regs[Ip as usize] += 1;
+                    handler(self);
+                    self.regs[Ip as usize] -= 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn setup_environment(stack_size: usize) -> (Vec<i64>, [i64; NumOfRegisters as usize], bool) {
+    let stack = vec![0; stack_size];
+    let mut registers = [0; NumOfRegisters as usize];
+
+    registers[Sp as usize] = -1;
+
+    (stack, registers, true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stack() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(5)).unwrap();
+        assert_eq!(vm.stack[0], 5);
+        vm.eval(Psh(8)).unwrap();
+        assert_eq!(vm.stack[1], 8);
+        vm.eval(Pop).unwrap();
+        vm.eval(Pop).unwrap();
+        vm.eval(Psh(14)).unwrap();
+        assert_eq!(vm.stack[0], 14);
+    }
+
+    #[test]
+    fn registers_moving() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(5)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        assert_eq!(vm.regs[A as usize], 5);
+        vm.eval(Mov(B, A)).unwrap();
+        assert_eq!(vm.regs[B as usize], 5);
+    }
+
+    #[test]
+    fn registers_add() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(5)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(6)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+        vm.eval(Add(A, B)).unwrap();
+        assert_eq!(vm.regs[A as usize], 11);
+    }
+
+    #[test]
+    fn registers_sub() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(5)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(6)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+        vm.eval(Sub(A, B)).unwrap();
+        assert_eq!(vm.regs[A as usize], -1);
+    }
+
+    #[test]
+    fn registers_mul() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(5)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(6)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+        vm.eval(Mul(A, B)).unwrap();
+        assert_eq!(vm.regs[A as usize], 30);
+    }
+
+    #[test]
+    fn registers_div() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(10)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(5)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+        vm.eval(Div(A, B)).unwrap();
+        assert_eq!(vm.regs[A as usize], 2);
+    }
+
+    #[test]
+    fn halt_program() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(10)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(5)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+        vm.eval(Div(A, B)).unwrap();
+
+        vm.eval(Hlt).unwrap();
+
+        assert!(!vm.running);
+    }
+
+    #[test]
+    fn equality() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(5)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(5)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+
+        vm.eval(Tee(A, B)).unwrap();
+
+        assert_eq!(vm.regs[Eq as usize], 1);
+    }
+    #[test]
+    fn non_equality() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(5)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(6)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+
+        vm.eval(Tne(A, B)).unwrap();
+
+        assert_eq!(vm.regs[Eq as usize], 1);
+    }
+
+    #[test]
+    fn lower_than() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(5)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(6)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+
+        vm.eval(Tll(A, B)).unwrap();
+
+        assert_eq!(vm.regs[Eq as usize], 1);
+    }
+
+    #[test]
+    fn greater_than() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(8)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(6)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+
+        vm.eval(Tmm(A, B)).unwrap();
+
+        assert_eq!(vm.regs[Eq as usize], 1);
+    }
+    #[test]
+    fn greater_or_equal() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(8)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(8)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+
+        vm.eval(Tem(A, B)).unwrap();
+
+        assert_eq!(vm.regs[Eq as usize], 1);
+    }
+
+    #[test]
+    fn lower_or_equal() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(8)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(8)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+
+        vm.eval(Tel(A, B)).unwrap();
+
+        assert_eq!(vm.regs[Eq as usize], 1);
+    }
+
+    #[test]
+    fn jump() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(8)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(8)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+
+        vm.eval(Tel(A, B)).unwrap();
+
+        vm.eval(Jmp(3)).unwrap();
+        assert_eq!(vm.regs[Ip as usize], 2);
+    }
+
+    fn overflowing_vm(mode: OverflowMode) -> Vm {
+        let mut vm = Vm::new();
+        vm.overflow = mode;
+
+        vm.eval(Psh(i32::MAX as i64)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+        vm.eval(Psh(2)).unwrap();
+        vm.eval(Mov(B, St)).unwrap();
+        vm
+    }
+
+    #[test]
+    fn overflow_wrapping() {
+        let mut vm = overflowing_vm(OverflowMode::Wrapping);
+
+        vm.eval(Add(A, B)).unwrap();
+        assert_eq!(vm.regs[A as usize], i32::MIN as i64 + 1);
+        assert_eq!(vm.regs[Of as usize], 1);
+
+        vm.eval(Add(A, B)).unwrap();
+        assert_eq!(vm.regs[A as usize], i32::MIN as i64 + 3);
+        assert_eq!(vm.regs[Of as usize], 0);
+    }
+
+    #[test]
+    fn overflow_saturating() {
+        let mut vm = overflowing_vm(OverflowMode::Saturating);
+
+        vm.eval(Mul(A, B)).unwrap();
+        assert_eq!(vm.regs[A as usize], i32::MAX as i64);
+        assert_eq!(vm.regs[Of as usize], 1);
+    }
+
+    #[test]
+    fn overflow_trapping() {
+        let mut vm = overflowing_vm(OverflowMode::Trapping);
+
+        assert_eq!(vm.eval(Add(A, B)), Err(VmError::ArithmeticOverflow(0)));
+        assert_eq!(vm.regs[A as usize], i32::MAX as i64);
+
+        vm.eval(Sub(A, B)).unwrap();
+        assert_eq!(vm.regs[A as usize], i32::MAX as i64 - 2);
+        assert_eq!(vm.regs[Of as usize], 0);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(8)).unwrap();
+        vm.eval(Mov(A, St)).unwrap();
+
+        assert_eq!(vm.eval(Div(A, B)), Err(VmError::DivisionByZero(0)));
+    }
+
+    #[test]
+    fn stack_size() {
+        let mut vm = Vm::with_stack_size(2);
+
+        vm.eval(Psh(1)).unwrap();
+        vm.eval(Psh(2)).unwrap();
+        assert_eq!(vm.eval(Psh(3)), Err(VmError::StackOverflow(0)));
+        assert_eq!(vm.regs[Sp as usize], 1);
+    }
+
+    #[test]
+    fn push_pop_registers() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(7)).unwrap();
+        vm.eval(PopR(A)).unwrap();
+        assert_eq!(vm.regs[A as usize], 7);
+        assert_eq!(vm.regs[Sp as usize], -1);
+
+        vm.eval(PshR(A)).unwrap();
+        assert_eq!(vm.regs[St as usize], 7);
+        assert_eq!(vm.regs[Sp as usize], 0);
+
+        vm.eval(PopR(B)).unwrap();
+        assert_eq!(vm.eval(PopR(B)), Err(VmError::StackUnderflow(0)));
+    }
+
+    #[test]
+    fn stack_shuffling() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(1)).unwrap();
+        vm.eval(Psh(2)).unwrap();
+        vm.eval(Psh(3)).unwrap();
+        vm.eval(Rot).unwrap();
+        assert_eq!(&vm.stack[0..3], &[2, 3, 1]);
+        assert_eq!(vm.regs[St as usize], 1);
+
+        vm.eval(Swp).unwrap();
+        assert_eq!(&vm.stack[0..3], &[2, 1, 3]);
+
+        vm.eval(Ovr).unwrap();
+        vm.eval(Dup).unwrap();
+        assert_eq!(&vm.stack[0..5], &[2, 1, 3, 1, 1]);
+        assert_eq!(vm.regs[Sp as usize], 4);
+    }
+
+    #[test]
+    fn stack_shuffling_underflow() {
+        let mut vm = Vm::new();
+
+        assert_eq!(vm.eval(Dup), Err(VmError::StackUnderflow(0)));
+        vm.eval(Psh(1)).unwrap();
+        assert_eq!(vm.eval(Swp), Err(VmError::StackUnderflow(0)));
+        assert_eq!(vm.eval(Ovr), Err(VmError::StackUnderflow(0)));
+        vm.eval(Psh(2)).unwrap();
+        assert_eq!(vm.eval(Rot), Err(VmError::StackUnderflow(0)));
+        assert_eq!(&vm.stack[0..2], &[1, 2]);
+    }
+
+    #[test]
+    fn stack_arithmetic() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(10)).unwrap();
+        vm.eval(Psh(4)).unwrap();
+        vm.eval(SubS).unwrap();
+        assert_eq!(vm.regs[St as usize], 6);
+        assert_eq!(vm.regs[Sp as usize], 0);
+
+        vm.eval(Psh(3)).unwrap();
+        vm.eval(MulS).unwrap();
+        vm.eval(Psh(4)).unwrap();
+        vm.eval(DivS).unwrap();
+        vm.eval(Psh(1)).unwrap();
+        vm.eval(AddS).unwrap();
+        assert_eq!(vm.regs[St as usize], 5);
+        assert_eq!(vm.regs[Sp as usize], 0);
+
+        assert_eq!(vm.eval(AddS), Err(VmError::StackUnderflow(0)));
+    }
+
+    #[test]
+    fn stack_arithmetic_errors_keep_stack() {
+        let mut vm = Vm::new();
+        vm.overflow = OverflowMode::Trapping;
+
+        vm.eval(Psh(i32::MAX as i64)).unwrap();
+        vm.eval(Psh(1)).unwrap();
+        assert_eq!(vm.eval(AddS), Err(VmError::ArithmeticOverflow(0)));
+        vm.eval(Psh(0)).unwrap();
+        assert_eq!(vm.eval(DivS), Err(VmError::DivisionByZero(0)));
+        assert_eq!(&vm.stack[0..3], &[i32::MAX as i64, 1, 0]);
+        assert_eq!(vm.regs[Sp as usize], 2);
+    }
+
+    #[test]
+    fn pop_last_element() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(3)).unwrap();
+        vm.eval(Pop).unwrap();
+        assert_eq!(vm.regs[Sp as usize], -1);
+        assert_eq!(vm.regs[St as usize], 0);
+        assert!(vm.live_stack().is_empty());
+    }
+
+    #[test]
+    fn pop_empty_stack() {
+        let mut vm = Vm::new();
+
+        assert_eq!(vm.eval(Pop), Err(VmError::StackUnderflow(0)));
+        assert_eq!(vm.regs[Sp as usize], -1);
+    }
+
+    #[test]
+    fn zeros_are_live() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(0)).unwrap();
+        vm.eval(Psh(4)).unwrap();
+        vm.eval(Psh(0)).unwrap();
+        assert_eq!(vm.live_stack(), &[0, 4, 0]);
+        vm.eval(Pop).unwrap();
+        assert_eq!(vm.regs[St as usize], 4);
+        assert_eq!(vm.live_stack(), &[0, 4]);
+    }
+
+    #[test]
+    fn read_only_st() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(3)).unwrap();
+        assert_eq!(vm.eval(Mov(St, A)), Err(VmError::ReadOnlyRegister(0)));
+        assert_eq!(vm.eval(Add(St, St)), Err(VmError::ReadOnlyRegister(0)));
+        assert_eq!(vm.eval(PopR(St)), Err(VmError::ReadOnlyRegister(0)));
+        assert_eq!(vm.regs[St as usize], 3);
+        assert_eq!(vm.live_stack(), &[3]);
+    }
+
+    #[test]
+    fn stack_pointer_writes() {
+        let mut vm = Vm::with_stack_size(4);
+
+        vm.eval(Psh(1)).unwrap();
+        vm.eval(Psh(2)).unwrap();
+        vm.eval(Psh(3)).unwrap();
+        vm.eval(PopR(A)).unwrap();
+        vm.eval(PopR(A)).unwrap();
+
+        vm.regs[A as usize] = 1;
+        vm.eval(Mov(Sp, A)).unwrap();
+        assert_eq!(vm.regs[St as usize], 2);
+        vm.eval(Sub(Sp, A)).unwrap();
+        vm.eval(Sub(Sp, A)).unwrap();
+        assert_eq!(vm.regs[Sp as usize], -1);
+        assert_eq!(vm.regs[St as usize], 0);
+
+        assert_eq!(vm.eval(Sub(Sp, A)), Err(VmError::InvalidStackPointer(0)));
+        vm.regs[A as usize] = 4;
+        assert_eq!(vm.eval(Mov(Sp, A)), Err(VmError::InvalidStackPointer(0)));
+        assert_eq!(vm.regs[Sp as usize], -1);
+    }
+
+    #[test]
+    fn instruction_pointer_writes() {
+        let mut vm = Vm::new();
+        vm.regs[A as usize] = 3;
+
+        vm.eval(Mov(Ip, A)).unwrap();
+        assert_eq!(vm.regs[Ip as usize], 2);
+
+        vm.regs[A as usize] = -1;
+        assert_eq!(vm.eval(Mov(Ip, A)), Err(VmError::InvalidJump(2)));
+    }
+
+    #[test]
+    fn jump_out_of_program() {
+        let mut vm = Vm::new();
+        vm.regs[Eq as usize] = 1;
+
+        assert_eq!(vm.run(&[Jmp(5), Hlt]), Err(VmError::InvalidJump(5)));
+    }
+
+    #[test]
+    fn word_size_bounds() {
+        let mut narrow = Vm::new();
+        let mut wide = Vm::new();
+        wide.word_size = WordSize::W64;
+
+        for vm in [&mut narrow, &mut wide].iter_mut() {
+            vm.eval(Psh(i32::MAX as i64)).unwrap();
+            vm.eval(PopR(A)).unwrap();
+            vm.eval(Psh(1)).unwrap();
+            vm.eval(PopR(B)).unwrap();
+            vm.eval(Add(A, B)).unwrap();
+        }
+        assert_eq!(narrow.regs[A as usize], i32::MIN as i64);
+        assert_eq!(narrow.regs[Of as usize], 1);
+        assert_eq!(wide.regs[A as usize], i32::MAX as i64 + 1);
+        assert_eq!(wide.regs[Of as usize], 0);
+
+        wide.overflow = OverflowMode::Saturating;
+        wide.eval(Psh(i64::MAX)).unwrap();
+        wide.eval(PopR(A)).unwrap();
+        wide.eval(Add(A, B)).unwrap();
+        assert_eq!(wide.regs[A as usize], i64::MAX);
+        assert_eq!(wide.regs[Of as usize], 1);
+    }
+
+    #[test]
+    fn narrow_immediates_wrap() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(1 << 32)).unwrap();
+        assert_eq!(vm.regs[St as usize], 0);
+        vm.eval(Set(A, (1 << 32) + 1)).unwrap();
+        assert_eq!(vm.regs[A as usize], 1);
+        assert_eq!(vm.eval(Set(St, 1)), Err(VmError::ReadOnlyRegister(0)));
+    }
+
+    fn load(vm: &mut Vm, f: FloatRegisters, value: f64) {
+        vm.eval(Fld(f, value.to_bits())).unwrap();
+    }
+
+    #[test]
+    fn float_arithmetic() {
+        let mut vm = Vm::new();
+
+        load(&mut vm, Fa, 1.5);
+        load(&mut vm, Fb, 0.25);
+        vm.eval(Fad(Fa, Fb)).unwrap();
+        assert_eq!(vm.fregs[Fa as usize], 1.75);
+        vm.eval(Fml(Fa, Fb)).unwrap();
+        vm.eval(Fsb(Fa, Fb)).unwrap();
+        assert_eq!(vm.fregs[Fa as usize], 0.1875);
+        vm.eval(Fmv(Fc, Fa)).unwrap();
+        vm.eval(Fdv(Fc, Fb)).unwrap();
+        assert_eq!(vm.fregs[Fc as usize], 0.75);
+    }
+
+    #[test]
+    fn float_ieee_semantics() {
+        let mut vm = Vm::new();
+
+        load(&mut vm, Fa, 1.0);
+        vm.eval(Fdv(Fa, Fb)).unwrap();
+        assert_eq!(vm.fregs[Fa as usize], f64::INFINITY);
+        vm.eval(Fmv(Fc, Fa)).unwrap();
+        vm.eval(Fsb(Fc, Fa)).unwrap();
+        assert!(vm.fregs[Fc as usize].is_nan());
+
+        vm.eval(Fee(Fc, Fc)).unwrap();
+        assert_eq!(vm.regs[Eq as usize], 0);
+        vm.eval(Fne(Fc, Fc)).unwrap();
+        assert_eq!(vm.regs[Eq as usize], 1);
+        vm.eval(Fel(Fc, Fa)).unwrap();
+        assert_eq!(vm.regs[Eq as usize], 0);
+        vm.eval(Fmm(Fa, Fb)).unwrap();
+        assert_eq!(vm.regs[Eq as usize], 1);
+        vm.eval(Fll(Fa, Fb)).unwrap();
+        assert_eq!(vm.regs[Eq as usize], 0);
+        vm.eval(Fem(Fb, Fb)).unwrap();
+        assert_eq!(vm.regs[Eq as usize], 1);
+    }
+
+    #[test]
+    fn float_conversions() {
+        let mut vm = Vm::new();
+
+        vm.eval(Psh(-7)).unwrap();
+        vm.eval(PopR(A)).unwrap();
+        vm.eval(Itf(Fa, A)).unwrap();
+        assert_eq!(vm.fregs[Fa as usize], -7.0);
+
+        load(&mut vm, Fb, -2.9);
+        vm.eval(Fti(B, Fb)).unwrap();
+        assert_eq!(vm.regs[B as usize], -2);
+
+        load(&mut vm, Fb, f64::NAN);
+        vm.eval(Fti(B, Fb)).unwrap();
+        assert_eq!(vm.regs[B as usize], 0);
+
+        load(&mut vm, Fb, f64::INFINITY);
+        vm.eval(Fti(B, Fb)).unwrap();
+        assert_eq!(vm.regs[B as usize], i32::MAX as i64);
+        vm.word_size = WordSize::W64;
+        vm.eval(Fti(B, Fb)).unwrap();
+        assert_eq!(vm.regs[B as usize], i64::MAX);
+
+        assert_eq!(vm.eval(Fti(St, Fb)), Err(VmError::ReadOnlyRegister(0)));
+    }
+
+    #[test]
+    fn heap_load_store() {
+        let mut vm = Vm::new();
+        vm.regs[A as usize] = 2;
+
+        vm.eval(Alc(A, B)).unwrap();
+        vm.regs[C as usize] = 42;
+        vm.eval(Sto(B, C)).unwrap();
+        vm.eval(Lod(D, B)).unwrap();
+        assert_eq!(vm.regs[D as usize], 42);
+
+        vm.eval(Fre(B)).unwrap();
+        assert!(vm.heap.leaks().is_empty());
+    }
+
+    #[test]
+    fn heap_misuse() {
+        let mut vm = Vm::new();
+        vm.regs[A as usize] = 1;
+        vm.eval(Alc(A, B)).unwrap();
+
+        vm.eval(Add(A, B)).unwrap();
+        assert_eq!(
+            vm.eval(Lod(C, A)),
+            Err(VmError::Memory(HeapError::OutOfBounds, 0))
+        );
+        vm.eval(Fre(B)).unwrap();
+        assert_eq!(
+            vm.eval(Sto(B, C)),
+            Err(VmError::Memory(HeapError::UseAfterFree, 0))
+        );
+        assert_eq!(
+            vm.eval(Fre(B)),
+            Err(VmError::Memory(HeapError::DoubleFree, 0))
+        );
+        assert_eq!(vm.eval(Alc(A, St)), Err(VmError::ReadOnlyRegister(0)));
+        assert!(vm.heap.leaks().is_empty());
+    }
+
+    #[test]
+    fn heap_leaks() {
+        let mut vm = Vm::new();
+        vm.regs[A as usize] = 3;
+
+        vm.run(&[Alc(A, B), Alc(A, C), Fre(B), Hlt]).unwrap();
+        assert_eq!(vm.heap.leaks(), vec![(4, 3)]);
+    }
+
+    #[test]
+    fn syscalls() {
+        let mut vm = Vm::new();
+        vm.register_syscall(42, |vm| {
+            vm.regs[A as usize] *= 2;
+            Ok(())
+        });
+        vm.register_syscall(43, |vm| Err(VmError::DivisionByZero(vm.regs[Ip as usize])));
+        vm.regs[A as usize] = 21;
+
+        vm.eval(Sys(42)).unwrap();
+        assert_eq!(vm.regs[A as usize], 42);
+        assert_eq!(vm.eval(Sys(43)), Err(VmError::DivisionByZero(0)));
+        assert_eq!(vm.eval(Sys(44)), Err(VmError::UnknownSyscall(44, 0)));
+    }
+
+    #[test]
+    fn exit_syscall() {
+        let mut vm = Vm::new();
+        vm.regs[A as usize] = 3;
+
+        vm.run(&[Sys(SYS_EXIT), Psh(1), Hlt]).unwrap();
+        assert_eq!(vm.exit_code, 3);
+        assert_eq!(vm.live_stack(), &[] as &[i64]);
+    }
+
+    #[test]
+    fn halt_with_exit_code() {
+        let mut vm = Vm::new();
+        vm.regs[B as usize] = 7;
+
+        vm.run(&[HltR(B), Hlt]).unwrap();
+        assert_eq!(vm.exit_code, 7);
+        assert_eq!(vm.regs[Ip as usize], 1);
+    }
+
+    #[test]
+    fn fuel() {
+        let program = [Psh(1), Psh(2), Hlt];
+
+        let mut vm = Vm::new();
+        vm.fuel = Some(2);
+        assert_eq!(vm.run(&program), Err(VmError::OutOfFuel(2)));
+        assert_eq!(vm.live_stack(), &[1, 2]);
+
+        let mut vm = Vm::new();
+        vm.fuel = Some(3);
+        vm.run(&program).unwrap();
+        assert_eq!(vm.fuel, Some(0));
+    }
+
+    #[test]
+    fn dumps_small_stacks() {
+        let regs = [0; NumOfRegisters as usize];
+        let fregs = [0.0; NumOfFloatRegisters as usize];
+        for (stack, expected) in [(&[][..], "Stack : []\n"), (&[7], "Stack : [7]\n")] {
+            let mut out = vec![];
+            dump(&mut out, stack, &regs, &fregs).unwrap();
+            assert!(String::from_utf8(out).unwrap().ends_with(expected));
+        }
+
+        let mut vm = Vm::with_stack_size(0);
+        vm.output = Box::new(io::sink());
+        assert_eq!(vm.run(&[Dmp, Psh(1), Hlt]), Err(VmError::StackOverflow(1)));
+    }
+
+    #[test]
+    fn output_errors() {
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut vm = Vm::new();
+        vm.output = Box::new(Closed);
+        let program = [Set(A, 65), Prt(A), Hlt];
+        assert_eq!(vm.run(&program), Err(VmError::Io(1)));
+        vm.eval(Psh(1)).unwrap();
+        for instr in [Drg(A), Fpr(Fa), Dst, Dmp] {
+            assert_eq!(vm.eval(instr), Err(VmError::Io(1)));
+        }
+        assert_eq!(vm.written, 0);
+    }
+
+    #[test]
+    fn standard_syscalls() {
+        let mut vm = Vm::new();
+
+        vm.eval(Sys(SYS_TIME)).unwrap();
+        assert!(vm.regs[A as usize] > 0);
+        assert!((0..1_000_000_000).contains(&vm.regs[B as usize]));
+
+        // Buffers are checked before any I/O happens
+        vm.regs[A as usize] = 1;
+        vm.regs[B as usize] = 1;
+        vm.regs[C as usize] = 2;
+        assert_eq!(
+            vm.eval(Sys(SYS_WRITE)),
+            Err(VmError::Memory(HeapError::OutOfBounds, 0))
+        );
+        vm.regs[C as usize] = i64::MAX;
+        vm.regs[B as usize] = 5;
+        assert_eq!(
+            vm.eval(Sys(SYS_WRITE)),
+            Err(VmError::Memory(HeapError::OutOfBounds, 0))
+        );
+        vm.regs[C as usize] = 2;
+        vm.eval(Alc(C, B)).unwrap();
+        vm.regs[A as usize] = 9;
+        vm.eval(Sys(SYS_WRITE)).unwrap();
+        assert_eq!(vm.regs[A as usize], -1);
+        vm.regs[A as usize] = 9;
+        vm.eval(Sys(SYS_READ)).unwrap();
+        assert_eq!(vm.regs[A as usize], -1);
+    }
+
+    mod word_sizes {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn register() -> impl Strategy<Value = Registers> {
+            prop_oneof![Just(A), Just(B), Just(C)]
+        }
+
+        fn instruction() -> impl Strategy<Value = Instructions> {
+            prop_oneof![
+                any::<i16>().prop_map(|i| Psh(i as i64)),
+                register().prop_map(PopR),
+                register().prop_map(PshR),
+                (register(), register()).prop_map(|(a, b)| Add(a, b)),
+                (register(), register()).prop_map(|(a, b)| Sub(a, b)),
+                (register(), register()).prop_map(|(a, b)| Mul(a, b)),
+                (register(), register()).prop_map(|(a, b)| Div(a, b)),
+                Just(AddS),
+                Just(MulS),
+                Just(Dup),
+                (register(), register()).prop_map(|(a, b)| Tll(a, b)),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn identical_within_range(instrs in prop::collection::vec(instruction(), 0..48)) {
+                let mut narrow = Vm::new();
+                let mut wide = Vm::new();
+                narrow.overflow = OverflowMode::Trapping;
+                wide.overflow = OverflowMode::Trapping;
+                wide.word_size = WordSize::W64;
+
+                for instr in instrs {
+                    let result = narrow.eval(instr);
+                    if result == Err(VmError::ArithmeticOverflow(0)) {
+                        // Out of the 32 bit range, the widths are expected to differ
+                        break;
+                    }
+                    prop_assert_eq!(result, wide.eval(instr));
+                    prop_assert_eq!(narrow.regs, wide.regs);
+                    prop_assert_eq!(narrow.live_stack(), wide.live_stack());
+                }
+            }
+        }
+    }
+
+    mod stack_model {
+        use super::*;
+        use proptest::prelude::*;
+
+        const SIZE: usize = 8;
+
+        fn stack_instruction() -> impl Strategy<Value = Instructions> {
+            prop_oneof![
+                any::<i32>().prop_map(|i| Psh(i as i64)),
+                Just(Pop),
+                Just(PshR(A)),
+                Just(PopR(A)),
+                Just(Dup),
+                Just(Swp),
+                Just(Ovr),
+                Just(Rot),
+            ]
+        }
+
+        // What the stack should look like after `instr`, or None if it must fail
+        fn expected(model: &[i64], a: i64, instr: Instructions) -> Option<Vec<i64>> {
+            let mut next = model.to_vec();
+            let n = next.len();
+            match instr {
+                Psh(i) => next.push(i),
+                PshR(_) => next.push(a),
+                Pop | PopR(_) => {
+                    next.pop()?;
+                }
+                Dup => next.push(*next.last()?),
+                Ovr if n >= 2 => next.push(next[n - 2]),
+                Swp if n >= 2 => next.swap(n - 2, n - 1),
+                Rot if n >= 3 => next[n - 3..].rotate_left(1),
+                _ => return None,
+            }
+            if next.len() > SIZE {
+                return None;
+            }
+            Some(next)
+        }
+
+        proptest! {
+            #[test]
+            fn invariants(instrs in prop::collection::vec(stack_instruction(), 0..64)) {
+                let mut vm = Vm::with_stack_size(SIZE);
+                let mut model: Vec<i64> = vec![];
+
+                for instr in instrs {
+                    let a = vm.regs[A as usize];
+                    let popped = model.last().copied();
+                    let result = vm.eval(instr);
+
+                    match expected(&model, a, instr) {
+                        Some(next) => {
+                            prop_assert!(result.is_ok());
+                            if let PopR(reg) = instr {
+                                prop_assert_eq!(Some(vm.regs[reg as usize]), popped);
+                            }
+                            model = next;
+                        }
+                        None => prop_assert!(result.is_err()),
+                    }
+
+                    prop_assert_eq!(vm.live_stack(), &model[..]);
+                    prop_assert_eq!(vm.regs[Sp as usize], model.len() as i64 - 1);
+                    prop_assert_eq!(vm.regs[St as usize], model.last().copied().unwrap_or(0));
+                }
+            }
+        }
+    }
+}
@@ -1,544 +1,707 @@
-use crate::Instructions::*;
-use crate::Registers::*;
-use std::io;
-use std::io::Write;
+use clap::{Args, Parser, Subcommand};
+use std::io::{self, Read};
+use wlvm::debugger::Debugger;
+use wlvm::heap::*;
+use wlvm::parser::*;
+use wlvm::Instructions::*;
+use wlvm::*;
+
+// Exit codes, programs stopped by `hlt <register>` or the exit syscall exit with their own code,
+// which can be one of these
+const EXIT_USAGE: i32 = 64; // Invalid command line
+const EXIT_ASSEMBLY: i32 = 65; // Invalid source or bytecode
+const EXIT_NO_INPUT: i32 = 66; // The program could not be read
+const EXIT_TRAP: i32 = 70; // Runtime error
+const EXIT_WRITE: i32 = 73; // The output could not be written
+const EXIT_FUEL: i32 = 75; // The program ran out of fuel
+
+#[derive(Parser)]
+#[command(name = "wlvm", version, about, arg_required_else_help = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
 
-mod parser;
+#[derive(Subcommand)]
+enum Command {
+    /// Runs the code or bytecode file
+    Run {
+        #[command(flatten)]
+        input: Input,
+        #[command(flatten)]
+        machine: Machine,
+        #[command(flatten)]
+        snapshots: Snapshots,
+        /// Shows the instructions of the program before running it
+        #[arg(short, long)]
+        instructions: bool,
+        /// Shows the details while running code
+        #[arg(short, long)]
+        details: bool,
+        /// Writes a JSON record of each instruction run to the file, one per line
+        #[arg(long, value_name = "FILE")]
+        trace: Option<String>,
+        /// Adds the lines and branches run to the lcov report in the file
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["trace", "optimize"])]
+        coverage: Option<String>,
+        /// Compiles the hot blocks to native code
+        #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+        #[arg(long, conflicts_with_all = ["trace", "coverage"])]
+        jit: bool,
+    },
+    /// Runs the program without printing and dumps the memory
+    Dump {
+        #[command(flatten)]
+        input: Input,
+        #[command(flatten)]
+        machine: Machine,
+    },
+    /// Reports the errors of the program without running it
+    Check {
+        #[command(flatten)]
+        input: Input,
+        /// Number of slots in the stack
+        #[arg(long, value_name = "N", default_value_t = STACK_SIZE, value_parser = parse_stack_size)]
+        stack_size: usize,
+        /// Prints the lowest and highest stack depth before each instruction
+        #[arg(long)]
+        depths: bool,
+    },
+    /// Prints the control flow graph of the program in the Graphviz format
+    Cfg {
+        #[command(flatten)]
+        input: Input,
+    },
+    /// Writes the bytecode of the program
+    Assemble {
+        #[command(flatten)]
+        input: Input,
+        /// Number of slots in the stack the program will run with, -O keeps the pushes that
+        /// could overflow it
+        #[arg(long, value_name = "N", default_value_t = STACK_SIZE, value_parser = parse_stack_size)]
+        stack_size: usize,
+        /// Output file (default: the input file with the wlb extension)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Compiles a program of the structured language to bytecode
+    Build {
+        /// Source file
+        file: String,
+        /// Width of integers, 32 or 64
+        #[arg(long, value_name = "BITS", default_value = "32", value_parser = parse_word_size)]
+        word_size: WordSize,
+        /// Optimizes the program
+        #[arg(short = 'O')]
+        optimize: bool,
+        /// Number of slots in the stack the program will run with, -O keeps the pushes that
+        /// could overflow it
+        #[arg(long, value_name = "N", default_value_t = STACK_SIZE, value_parser = parse_stack_size)]
+        stack_size: usize,
+        /// Writes the assembly source instead of bytecode
+        #[arg(long)]
+        asm: bool,
+        /// Output file (default: the input file with the wlb extension, or vm with --asm)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Translates the program to a standalone source file
+    Compile {
+        #[command(flatten)]
+        input: Input,
+        #[command(flatten)]
+        machine: Machine,
+        /// Language to translate to
+        #[arg(long, value_parser = ["c", "wat"])]
+        target: String,
+        /// Output file (default: the input file with the extension of the target)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Prints the source code of a program or bytecode file
+    Disasm {
+        #[command(flatten)]
+        input: Input,
+    },
+    /// Runs the program step by step
+    Debug {
+        #[command(flatten)]
+        input: Input,
+        #[command(flatten)]
+        machine: Machine,
+        /// Memory used to step backwards, in MiB, the oldest steps being forgotten first
+        #[arg(long, value_name = "MIB", default_value_t = 64)]
+        history: usize,
+    },
+    /// Continues running a program from a snapshot
+    Resume {
+        /// Snapshot written by --snapshot
+        file: String,
+        #[command(flatten)]
+        snapshots: Snapshots,
+    },
+    /// Runs the program without printing and reports where it spent its time
+    Profile {
+        #[command(flatten)]
+        input: Input,
+        #[command(flatten)]
+        machine: Machine,
+        /// Number of instructions and blocks reported
+        #[arg(long, value_name = "N", default_value_t = 10)]
+        top: usize,
+    },
+}
 
-const STACK_SIZE: usize = 255;
+#[derive(Args)]
+struct Input {
+    /// Source or bytecode file, `-` reads it from stdin
+    file: String,
+    /// Rejects programs writing to ip, sp or st
+    #[arg(long)]
+    strict: bool,
+    /// Width of integers of source files, 32 or 64 (bytecode records its own)
+    #[arg(long, value_name = "BITS", default_value = "32", value_parser = parse_word_size)]
+    word_size: WordSize,
+    /// Optimizes the program
+    #[arg(short = 'O')]
+    optimize: bool,
+    /// Prints the program after each optimization pass to stderr
+    #[arg(long, requires = "optimize")]
+    print_passes: bool,
+    // Set by check, which reports lines with unknown mnemonics
+    #[arg(skip)]
+    reject_unknown: bool,
+}
 
-use parser::*;
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum Instructions {
-    Psh(i32),
-    Add(Registers, Registers),
-    Mul(Registers, Registers),
-    Div(Registers, Registers),
-    Sub(Registers, Registers),
-    Pop,
-    Mov(Registers, Registers),
-    Hlt,
-    Dst,
-    Drg(Registers),
-    Dmp,
-    Prt(Registers), // Prints the ascii letter corresponding of the register's content
-    Tee(Registers, Registers), // ==
-    Tne(Registers, Registers), // !=
-    Tll(Registers, Registers), // <
-    Tmm(Registers, Registers), // >
-    Tel(Registers, Registers), // <=
-    Tem(Registers, Registers), // >=
-    Jmp(i32),       // Jump to line if Eq is true
+#[derive(Args)]
+struct Machine {
+    /// What to do on arithmetic overflow : wrap, saturate or trap
+    #[arg(long, value_name = "MODE", default_value = "wrap", value_parser = parse_overflow)]
+    overflow: OverflowMode,
+    /// Number of slots in the stack
+    #[arg(long, value_name = "N", default_value_t = STACK_SIZE, value_parser = parse_stack_size)]
+    stack_size: usize,
+    /// Number of words of memory for alc
+    #[arg(long, value_name = "N", default_value_t = HEAP_SIZE)]
+    heap_size: usize,
+    /// Stops the program after n instructions
+    #[arg(long, value_name = "N")]
+    fuel: Option<u64>,
 }
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum Registers {
-    A = 0,
-    B = 1,
-    C = 2,
-    D = 3,
-    E = 4,
-    F = 5,
-    Ip = 6,
-    Sp = 7,
-    St = 8,
-    Eq = 9,
-    NumOfRegisters = 10,
+
+#[derive(Args)]
+struct Snapshots {
+    /// Writes the state of the VM to the file, to be continued with `resume`
+    #[arg(long, value_name = "FILE")]
+    snapshot: Option<String>,
+    /// When to write the snapshot, replacing the previous one : snp, hlt or error
+    #[arg(
+        long,
+        value_name = "WHEN",
+        value_delimiter = ',',
+        default_value = "snp",
+        value_parser = ["snp", "hlt", "error"],
+        requires = "snapshot"
+    )]
+    snapshot_on: Vec<String>,
 }
 
-fn reg_name(reg: i32) -> &'static str {
-    match reg {
-        0 => "A",
-        1 => "B",
-        2 => "C",
-        3 => "D",
-        4 => "E",
-        5 => "F",
-        6 => "Ip",
-        7 => "Sp",
-        8 => "St",
-        9 => "Eq",
-        _ => "_ ",
-    }
+fn parse_word_size(bits: &str) -> Result<WordSize, String> {
+    WordSize::from_bits(bits).ok_or_else(|| String::from("expected 32 or 64"))
 }
 
-fn fetch(program: &Vec<Instructions>, ip: usize) -> Instructions {
-    program[ip]
+fn parse_overflow(mode: &str) -> Result<OverflowMode, String> {
+    OverflowMode::from_name(mode).ok_or_else(|| String::from("expected wrap, saturate or trap"))
 }
 
-pub fn dump(stack: &Vec<i32>, regs: &[i32; NumOfRegisters as usize]) {
-    print!("[");
-    for i in 0..(NumOfRegisters as usize) {
-        print!("{}: {}, ", reg_name(i as i32), regs[i]);
-    }
-    println!("]");
-    println!();
-    print!("Stack : [{}, ", stack[0]);
-    for i in 1..stack.len() {
-        if i == stack.len() - 1 {
-            println!("{}]", stack[i]);
-        } else {
-            print!("{}, ", stack[i]);
-        }
+fn parse_stack_size(size: &str) -> Result<usize, String> {
+    match size.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(String::from("expected a positive integer")),
     }
 }
 
-fn eval(
-    instr: Instructions,
-    running: &mut bool,
-    stack: &mut Vec<i32>,
-    regs: &mut [i32; NumOfRegisters as usize],
-    details: bool,
-) {
-    // Instrucion Pointer : regs[6]
-    // Stack Pointer : regs[7]
-
-    if details {
-        print!("{} - ", regs[6]);
+impl Input {
+    // Loads a source file, or a bytecode file produced by `assemble`, optimized for a stack of
+    // `stack_size` slots with -O
+    fn load(&self, stack_size: usize) -> (WordSize, Vec<Instructions>) {
+        let (word_size, program, _) = self.load_with_lines(stack_size);
+        (word_size, program)
     }
 
-    match instr {
-        Dmp => dump(stack, regs),
-        Prt(reg) => {
-            if (0..256).contains(&regs[reg as usize]) {
-                print!("{}", regs[reg as usize] as u8 as char);
-                io::stdout().flush().unwrap();
+    // Same as `load`, with the source line of each instruction. Bytecode and optimized
+    // programs have none.
+    fn load_with_lines(
+        &self,
+        stack_size: usize,
+    ) -> (WordSize, Vec<Instructions>, Vec<Option<usize>>) {
+        let read = if self.file == "-" {
+            let mut bytes = vec![];
+            io::stdin().read_to_end(&mut bytes).map(|_| bytes)
+        } else {
+            std::fs::read(&self.file)
+        };
+        let bytes = match read {
+            Ok(b) => b,
+            Err(e) => {
+                eprintln!("Error: {}: {}", self.file, e);
+                std::process::exit(EXIT_NO_INPUT);
             }
+        };
+
+        if bytecode::is_bytecode(&bytes) {
+            return match bytecode::decode(&bytes) {
+                Ok((word_size, program)) => {
+                    let lines = vec![None; program.len()];
+                    (
+                        word_size,
+                        self.optimized(program, word_size, stack_size),
+                        lines,
+                    )
+                }
+                Err(e) => {
+                    eprintln!("Error: {}: {}", self.file, e);
+                    std::process::exit(EXIT_ASSEMBLY);
+                }
+            };
         }
-        Tee(a, b) => {
-            if details {
-                println!("{} == {}", regs[a as usize], regs[b as usize]);
+
+        let source = match String::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(_) => {
+                eprintln!("Error: {}: not a source or bytecode file", self.file);
+                std::process::exit(EXIT_ASSEMBLY);
             }
-            regs[Eq as usize] = (regs[a as usize] == regs[b as usize]) as i32;
-        }
-        Tne(a, b) => {
-            if details {
-                println!("{} != {}", regs[a as usize], regs[b as usize]);
+        };
+        let options = ParseOptions {
+            strict: self.strict,
+            word_size: self.word_size,
+            reject_unknown: self.reject_unknown,
+        };
+        match parse_with_lines(&source, options) {
+            Ok((program, lines)) if self.optimize => {
+                let lines = vec![None; lines.len()];
+                (
+                    self.word_size,
+                    self.optimized(program, self.word_size, stack_size),
+                    lines,
+                )
             }
-            regs[Eq as usize] = (regs[a as usize] != regs[b as usize]) as i32;
-        }
-        Tll(a, b) => {
-            if details {
-                println!("{} < {}", regs[a as usize], regs[b as usize]);
+            Ok((program, lines)) => (self.word_size, program, lines),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(EXIT_ASSEMBLY);
             }
-            regs[Eq as usize] = (regs[a as usize] < regs[b as usize]) as i32;
         }
-        Tmm(a, b) => {
-            if details {
-                println!("{} > {}", regs[a as usize], regs[b as usize]);
-            }
-            regs[Eq as usize] = (regs[a as usize] > regs[b as usize]) as i32;
+    }
+}
+
+impl Input {
+    fn optimized(
+        &self,
+        program: Vec<Instructions>,
+        word_size: WordSize,
+        stack_size: usize,
+    ) -> Vec<Instructions> {
+        if self.optimize {
+            optimize::optimize(&program, word_size, stack_size, |pass, program| {
+                if self.print_passes {
+                    eprintln!("; after {}", pass);
+                    eprint!("{}", disasm::disassemble(program));
+                }
+            })
+        } else {
+            program
         }
-        Tel(a, b) => {
-            if details {
-                println!("{}  <= {}", regs[a as usize], regs[b as usize]);
-            }
-            regs[Eq as usize] = (regs[a as usize] <= regs[b as usize]) as i32;
+    }
+}
+
+impl Machine {
+    fn build(&self, word_size: WordSize) -> Vm {
+        let mut vm = Vm::with_stack_size(self.stack_size);
+        vm.heap = Heap::new(self.heap_size);
+        vm.overflow = self.overflow;
+        vm.word_size = word_size;
+        vm.fuel = self.fuel;
+        vm
+    }
+}
+
+impl Snapshots {
+    fn on(&self, when: &str) -> Option<&String> {
+        self.snapshot
+            .as_ref()
+            .filter(|_| self.snapshot_on.iter().any(|w| w == when))
+    }
+
+    // Makes snp write the snapshot
+    fn install(&self, vm: &mut Vm, program: &[Instructions]) {
+        if let Some(file) = self.on("snp") {
+            let (file, program) = (file.clone(), program.to_vec());
+            vm.on_snapshot(move |vm| write_snapshot(&file, vm, &program));
         }
-        Tem(a, b) => {
-            if details {
-                println!("{}  >= {}", regs[a as usize], regs[b as usize]);
-            }
-            regs[Eq as usize] = (regs[a as usize] >= regs[b as usize]) as i32;
+    }
+
+    // Same as `exit`, writing the snapshot first if asked for when the program stops this way
+    fn exit(&self, vm: &Vm, program: &[Instructions], result: Result<(), VmError>) -> ! {
+        if let Some(file) = self.on(if result.is_ok() { "hlt" } else { "error" }) {
+            write_snapshot(file, vm, program);
         }
-        Jmp(i) => {
-            if i < 0 {
-                panic!("ERR_ATEMPTED_TO_JUMP_TO_NEGATIVE_OPERATION_NUMBER");
+        exit(vm, result)
+    }
+}
+
+fn write_snapshot(file: &str, vm: &Vm, program: &[Instructions]) {
+    if let Err(e) = std::fs::write(file, snapshot::save(vm, program)) {
+        eprintln!("Error: failed to write {}: {}", file, e);
+        std::process::exit(EXIT_WRITE);
+    }
+}
+
+// Exits with the code of the program, or the one of its error
+fn exit(vm: &Vm, result: Result<(), VmError>) -> ! {
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(match e {
+            VmError::OutOfFuel(_) => EXIT_FUEL,
+            VmError::Io(_) => EXIT_WRITE,
+            _ => EXIT_TRAP,
+        });
+    }
+    std::process::exit(exit_code(vm.exit_code));
+}
+
+// Low 8 bits of the exit code of the program, which is all Unix keeps, on every platform
+fn exit_code(code: i64) -> i32 {
+    (code & 0xff) as i32
+}
+
+// Adds the run to the lcov report, which is created if missing
+fn add_coverage(
+    report: &str,
+    file: &str,
+    program: &[Instructions],
+    lines: &[Option<usize>],
+    profile: &profile::Profile,
+) {
+    let mut total = match std::fs::read_to_string(report) {
+        Ok(lcov) => match coverage::Coverage::parse(&lcov) {
+            Ok(coverage) => coverage,
+            Err(e) => {
+                eprintln!("Error: {}: {}", report, e);
+                std::process::exit(EXIT_WRITE);
             }
-            if details {
-                println!("Jumped to {}", i);
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => coverage::Coverage::new(),
+        Err(e) => {
+            eprintln!("Error: {}: {}", report, e);
+            std::process::exit(EXIT_WRITE);
+        }
+    };
+    total.add(file, program, lines, profile);
+    if let Err(e) = std::fs::write(report, total.to_lcov()) {
+        eprintln!("Error: failed to write {}: {}", report, e);
+        std::process::exit(EXIT_WRITE);
+    }
+}
+
+fn main() {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            std::process::exit(if e.use_stderr() { EXIT_USAGE } else { 0 });
+        }
+    };
+
+    match cli.command {
+        Command::Run {
+            input,
+            machine,
+            snapshots,
+            instructions,
+            details,
+            trace,
+            coverage,
+            #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+            jit,
+        } => {
+            let (word_size, program, lines) = input.load_with_lines(machine.stack_size);
+            let mut vm = machine.build(word_size);
+            vm.details = details;
+            snapshots.install(&mut vm, &program);
+            if instructions {
+                println!("{:?}\n==============================", program);
             }
-            if regs[Eq as usize] == 1 {
-                if details {
-                    println!("Goto {}", i);
+            if let Some(trace) = trace {
+                let result = std::fs::File::create(&trace)
+                    .and_then(|file| vm.run_traced(&program, &mut io::BufWriter::new(file)));
+                match result {
+                    Ok(result) => snapshots.exit(&vm, &program, result),
+                    Err(e) => {
+                        eprintln!("Error: failed to write {}: {}", trace, e);
+                        std::process::exit(EXIT_WRITE);
+                    }
                 }
-                regs[Ip as usize] = i - 1;
-            } else {
-                if details {
-                    println!("None");
+            }
+            if let Some(report) = coverage {
+                if lines.iter().all(Option::is_none) {
+                    eprintln!("Error: {}: coverage needs a source file", input.file);
+                    std::process::exit(EXIT_USAGE);
                 }
+                let mut profile = profile::Profile::new(&program);
+                let result = vm.run_profiled(&engine::Decoded::new(&program), &mut profile);
+                add_coverage(&report, &input.file, &program, &lines, &profile);
+                snapshots.exit(&vm, &program, result);
             }
-        }
-        Hlt => {
-            if details {
-                println!("Quit");
+            #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+            if jit {
+                let result = match wlvm::jit::Jit::compile(&program, word_size) {
+                    Ok(compiled) => vm.run_jit(&program, &compiled),
+                    Err(e) => {
+                        eprintln!("Error: cannot allocate native code: {}", e);
+                        vm.run_decoded(&engine::Decoded::new(&program))
+                    }
+                };
+                snapshots.exit(&vm, &program, result);
             }
-            *running = false;
+            let result = vm.run_decoded(&engine::Decoded::new(&program));
+            snapshots.exit(&vm, &program, result);
         }
-        Psh(i) => {
-            if (regs[7] + 1) as usize >= STACK_SIZE {
-                panic!("ERR_STACK_OVERFLOW");
-            }
-            regs[7] += 1;
-            stack[regs[7] as usize] = i;
-            regs[8] = i;
-            if details {
-                println!("-> {}", i);
-            }
+        Command::Resume { file, snapshots } => {
+            let bytes = match std::fs::read(&file) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    eprintln!("Error: {}: {}", file, e);
+                    std::process::exit(EXIT_NO_INPUT);
+                }
+            };
+            let (mut vm, program) = match snapshot::load(&bytes) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    eprintln!("Error: {}: {}", file, e);
+                    std::process::exit(EXIT_ASSEMBLY);
+                }
+            };
+            snapshots.install(&mut vm, &program);
+            let result = vm.run_decoded(&engine::Decoded::new(&program));
+            snapshots.exit(&vm, &program, result);
         }
-        Pop => {
-            if regs[7] - 1 < 0 && regs[7] != 0 {
-                // adding exception for popping the last element
-                panic!("ERR_STACK_UNDERFLOW");
-            }
-            let popped = stack[regs[7] as usize];
-
-            if regs[7] != 0 {
-                regs[7] -= 1;
-                regs[8] = stack[regs[7] as usize];
-            } else {
-                regs[7] -= 1;
-                regs[8] = 0;
-            }
-
-            if details {
-                println!("<- {}", popped);
-            }
+        Command::Dump { input, machine } => {
+            let (word_size, program) = input.load(machine.stack_size);
+            let mut program = program
+                .into_iter()
+                .filter(|x| is_valid(*x))
+                .collect::<Vec<Instructions>>();
+            program.push(Dmp);
+            program.push(Hlt);
+            let mut vm = machine.build(word_size);
+            let result = vm.run(&program);
+            exit(&vm, result);
         }
-        Add(a, b) => {
-            if details {
-                println!("{} + {}", regs[a as usize], regs[b as usize]);
+        Command::Check {
+            mut input,
+            stack_size,
+            depths,
+        } => {
+            input.reject_unknown = true;
+            let (_, program) = input.load(stack_size);
+            if depths {
+                let depths = analysis::stack_depths(&program, stack_size);
+                for (ip, instr) in program.iter().enumerate() {
+                    let depth = match depths[ip] {
+                        Some((lo, hi)) => format!("{}..{}", lo, hi),
+                        None => String::from("-"),
+                    };
+                    println!("{:>4} | {:>9} | {}", ip, depth, instr);
+                }
             }
-            regs[a as usize] += regs[b as usize];
-        }
-        Sub(a, b) => {
-            if details {
-                println!("{} - {}", regs[a as usize], regs[b as usize]);
+            let diagnostics = analysis::check(&program, stack_size);
+            for diagnostic in &diagnostics {
+                eprintln!("{}\n", diagnostic);
             }
-            regs[a as usize] -= regs[b as usize];
-        }
-        Mul(a, b) => {
-            if details {
-                println!("{} * {}", regs[a as usize], regs[b as usize]);
+            let errors = diagnostics
+                .iter()
+                .filter(|d| d.severity == analysis::Severity::Error)
+                .count();
+            println!(
+                "{}: {} error(s), {} warning(s)",
+                input.file,
+                errors,
+                diagnostics.len() - errors
+            );
+            if errors > 0 {
+                std::process::exit(EXIT_ASSEMBLY);
             }
-            regs[a as usize] *= regs[b as usize];
         }
-        Div(a, b) => {
-            if details {
-                println!("{} / {}", regs[a as usize], regs[b as usize]);
+        Command::Cfg { input } => {
+            let (_, program) = input.load(STACK_SIZE);
+            print!("{}", cfg::Cfg::new(&program).to_dot(&program));
+        }
+        Command::Assemble {
+            input,
+            stack_size,
+            output,
+        } => {
+            let (word_size, program) = input.load(stack_size);
+            let output = match output {
+                Some(o) => o,
+                None if input.file == "-" => String::from("out.wlb"),
+                None => std::path::Path::new(&input.file)
+                    .with_extension("wlb")
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            if let Err(e) = std::fs::write(&output, bytecode::encode(&program, word_size)) {
+                eprintln!("Error: failed to write {}: {}", output, e);
+                std::process::exit(EXIT_WRITE);
             }
-            regs[a as usize] /= regs[b as usize];
         }
-        Mov(a, b) => {
-            if details {
-                println!("{} <-| {}", reg_name(a as i32), reg_name(b as i32));
+        Command::Build {
+            file,
+            word_size,
+            optimize,
+            stack_size,
+            asm,
+            output,
+        } => {
+            let source = match std::fs::read_to_string(&file) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Error: {}: {}", file, e);
+                    std::process::exit(EXIT_NO_INPUT);
+                }
+            };
+            let mut program = match lang::compile(&source, word_size) {
+                Ok(program) => program,
+                Err(e) => {
+                    eprintln!("{}\n", e);
+                    std::process::exit(EXIT_ASSEMBLY);
+                }
+            };
+            if optimize {
+                program = optimize::optimize(&program, word_size, stack_size, |_, _| ());
             }
-            if a == Ip {
-                regs[a as usize] = regs[b as usize] - 1; // Being the same as jump
+            let output = output.unwrap_or_else(|| {
+                std::path::Path::new(&file)
+                    .with_extension(if asm { "vm" } else { "wlb" })
+                    .to_string_lossy()
+                    .into_owned()
+            });
+            let bytes = match asm {
+                true => disasm::disassemble(&program).into_bytes(),
+                false => bytecode::encode(&program, word_size),
+            };
+            if let Err(e) = std::fs::write(&output, bytes) {
+                eprintln!("Error: failed to write {}: {}", output, e);
+                std::process::exit(EXIT_WRITE);
             }
-            regs[a as usize] = regs[b as usize];
-        }
-        Drg(reg) => {
-            println!("[{}]", regs[reg as usize]);
         }
-        Dst => {
-            for val in stack {
-                if val != &0 {
-                    println!("[{}]", val);
-                }
+        Command::Compile {
+            input,
+            machine,
+            target,
+            output,
+        } => {
+            let (word_size, program) = input.load(machine.stack_size);
+            let vm = machine.build(word_size);
+            let output = match output {
+                Some(o) => o,
+                None if input.file == "-" => format!("out.{}", target),
+                None => std::path::Path::new(&input.file)
+                    .with_extension(&target)
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            let source = match target.as_str() {
+                "c" => c::compile(&program, &vm),
+                _ => wat::compile(&program, &vm),
+            };
+            if let Err(e) = std::fs::write(&output, source) {
+                eprintln!("Error: failed to write {}: {}", output, e);
+                std::process::exit(EXIT_WRITE);
             }
         }
-    }
-}
-
-fn help() {
-    println!(
-        "wlvm version {} by Wafelack <wafelack@protonmail.com>\n",
-        env!("CARGO_PKG_VERSION")
-    );
-    println!("usage: wlvm <command> [flags]\n");
-    println!("COMMANDS:");
-    println!("\trun <filename> : Runs the code file");
-    println!("\tdump <filename>: Runs the program and dumps the memory");
-    println!("\nFLAGS:");
-    println!("\t--instructions | -d: Shows the instructions run in the program");
-    println!("\t--details | -d     : Shows the details while running code");
-    std::process::exit(0);
-}
-
-fn is_present(args: &Vec<String>, to_search: &str) -> bool {
-    for arg in args {
-        if arg == to_search {
-            return true;
+        Command::Disasm { input } => {
+            let (_, program) = input.load(STACK_SIZE);
+            print!("{}", disasm::disassemble(&program));
         }
-    }
-    false
-}
-
-fn main() {
-    let args = std::env::args().skip(1).collect::<Vec<String>>();
-
-    let mut program: Vec<Instructions> = vec![];
-
-    let mut details = false;
-
-    if args.len() < 1 {
-        help();
-    } else {
-        if args[0] == "run" {
-            if args.len() < 2 {
-                help();
-            } else {
-                if !std::path::Path::new(&args[1]).exists() {
-                    eprintln!("Error: no input files");
-                    std::process::exit(66);
-                } else {
-                    program = parse_file(&args[1]);
-                }
-                if is_present(&args, "--instructions") || is_present(&args, "-i") {
-                    println!("{:?}\n==============================", program);
-                }
-                if is_present(&args, "--details") || is_present(&args, "-d") {
-                    details = true;
-                }
+        Command::Debug {
+            input,
+            machine,
+            history,
+        } => {
+            if input.file == "-" {
+                eprintln!("Error: the debugger reads its commands from stdin, not the program");
+                std::process::exit(EXIT_USAGE);
             }
-        } else if args[0] == "dump" {
-            if args.len() < 2 {
-                help();
-            } else {
-                if !std::path::Path::new(&args[1]).exists() {
-                    eprintln!("Error: no input files");
-                    std::process::exit(66);
-                } else {
-                    program = parse_file(&args[1]);
-                    program.push(Dmp);
-                    program = program
-                        .iter()
-                        .filter(|x| is_valid(**x))
-                        .map(|s| *s)
-                        .collect();
-                    program.push(Dmp);
-                    program.push(Hlt);
+            let (word_size, program) = input.load(machine.stack_size);
+            let mut vm = machine.build(word_size);
+            let stdin = io::stdin();
+            let mut debugger = Debugger::new(&mut vm, &program);
+            debugger.history_limit = history.saturating_mul(1 << 20);
+            let result = debugger.session(stdin.lock(), io::stdout());
+            match result {
+                Ok(result) => exit(&vm, result),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(EXIT_WRITE);
                 }
             }
-        } else {
-            help();
         }
-    }
-
-    let (mut stack, mut registers, mut running) = setup_environment();
-
-    while running {
-        let instr = fetch(&program, registers[6] as usize);
-        eval(instr, &mut running, &mut stack, &mut registers, details);
-        registers[6] += 1;
+        Command::Profile {
+            input,
+            machine,
+            top,
+        } => {
+            let (word_size, program, lines) = input.load_with_lines(machine.stack_size);
+            let mut vm = machine.build(word_size);
+            vm.output = Box::new(io::sink());
+            let mut profile = profile::Profile::new(&program);
+            let result = vm.run_profiled(&engine::Decoded::new(&program), &mut profile);
+            print!("{}", profile.report(&program, &lines, top));
+            exit(&vm, result);
+        }
     }
 }
 
-fn setup_environment() -> (Vec<i32>, [i32; NumOfRegisters as usize], bool) {
-    let stack = vec![0; STACK_SIZE];
-    let mut registers = [0; NumOfRegisters as usize];
-
-    registers[Sp as usize] = -1;
-
-    (stack, registers, true)
-}
-
+// Whether dump keeps the instruction, leaving out the ones printing or stopping the program,
+// and syscalls which may do both
 fn is_valid(instr: Instructions) -> bool {
-    match instr {
-        Prt(_) => false,
-        Drg(_) => false,
-        Dst => false,
-        Dmp => false,
-        Hlt => false,
-        _ => true,
-    }
+    !matches!(
+        instr,
+        Prt(_) | Drg(_) | Fpr(_) | Dst | Dmp | Hlt | HltR(_) | Sys(_)
+    )
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use wlvm::Registers::*;
 
     #[test]
-    fn validation() {
-        assert!(!is_valid(Prt(A)));
-        assert!(!is_valid(Drg(A)));
-        assert!(!is_valid(Dst));
-        assert!(!is_valid(Dmp));
-        assert!(!is_valid(Hlt));
-    }
-
-    #[test]
-    fn stack() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(5), &mut running, &mut stack, &mut registers, false);
-        assert_eq!(stack[0], 5);
-        eval(Psh(8), &mut running, &mut stack, &mut registers, false);
-        assert_eq!(stack[1], 8);
-        eval(Pop, &mut running, &mut stack, &mut registers, false);
-        eval(Pop, &mut running, &mut stack, &mut registers, false);
-        eval(Psh(14), &mut running, &mut stack, &mut registers, false);
-        assert_eq!(stack[0], 14);
-    }
-
-    #[test]
-    fn registers_moving() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(5), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        assert_eq!(registers[A as usize], 5);
-        eval(Mov(B, A), &mut running, &mut stack, &mut registers, false);
-        assert_eq!(registers[B as usize], 5);
-    }
-
-    #[test]
-    fn registers_add() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(5), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        eval(Psh(6), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(B, St), &mut running, &mut stack, &mut registers, false);
-        eval(Add(A, B), &mut running, &mut stack, &mut registers, false);
-        assert_eq!(registers[A as usize], 11);
-    }
-
-    #[test]
-    fn registers_sub() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(5), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        eval(Psh(6), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(B, St), &mut running, &mut stack, &mut registers, false);
-        eval(Sub(A, B), &mut running, &mut stack, &mut registers, false);
-        assert_eq!(registers[A as usize], -1);
-    }
-
-    #[test]
-    fn registers_mul() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(5), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        eval(Psh(6), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(B, St), &mut running, &mut stack, &mut registers, false);
-        eval(Mul(A, B), &mut running, &mut stack, &mut registers, false);
-        assert_eq!(registers[A as usize], 30);
-    }
-
-    #[test]
-    fn registers_div() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(10), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        eval(Psh(5), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(B, St), &mut running, &mut stack, &mut registers, false);
-        eval(Div(A, B), &mut running, &mut stack, &mut registers, false);
-        assert_eq!(registers[A as usize], 2);
-    }
-
-    #[test]
-    fn halt_program() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(10), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        eval(Psh(5), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(B, St), &mut running, &mut stack, &mut registers, false);
-        eval(Div(A, B), &mut running, &mut stack, &mut registers, false);
-
-        eval(Hlt, &mut running, &mut stack, &mut registers, false);
-
-        assert!(!running);
-    }
-
-    #[test]
-    fn equality() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(5), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        eval(Psh(5), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(B, St), &mut running, &mut stack, &mut registers, false);
-
-        eval(Tee(A, B), &mut running, &mut stack, &mut registers, false);
-
-        assert_eq!(registers[Eq as usize], 1);
-    }
-    #[test]
-    fn non_equality() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(5), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        eval(Psh(6), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(B, St), &mut running, &mut stack, &mut registers, false);
-
-        eval(Tne(A, B), &mut running, &mut stack, &mut registers, false);
-
-        assert_eq!(registers[Eq as usize], 1);
+    fn command_line() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
     }
 
     #[test]
-    fn lower_than() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(5), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        eval(Psh(6), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(B, St), &mut running, &mut stack, &mut registers, false);
-
-        eval(Tll(A, B), &mut running, &mut stack, &mut registers, false);
-
-        assert_eq!(registers[Eq as usize], 1);
+    fn exit_codes() {
+        assert_eq!(exit_code(3), 3);
+        assert_eq!(exit_code(256 + 70), 70);
+        assert_eq!(exit_code(-1), 255);
+        assert_eq!(exit_code(i64::MIN), 0);
     }
 
     #[test]
-    fn greater_than() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(8), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        eval(Psh(6), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(B, St), &mut running, &mut stack, &mut registers, false);
-
-        eval(Tmm(A, B), &mut running, &mut stack, &mut registers, false);
-
-        assert_eq!(registers[Eq as usize], 1);
-    }
-    #[test]
-    fn greater_or_equal() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(8), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        eval(Psh(8), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(B, St), &mut running, &mut stack, &mut registers, false);
-
-        eval(Tem(A, B), &mut running, &mut stack, &mut registers, false);
-
-        assert_eq!(registers[Eq as usize], 1);
-    }
-
-    #[test]
-    fn lower_or_equal() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(8), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        eval(Psh(8), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(B, St), &mut running, &mut stack, &mut registers, false);
-
-        eval(Tel(A, B), &mut running, &mut stack, &mut registers, false);
-
-        assert_eq!(registers[Eq as usize], 1);
-    }
-
-    #[test]
-    fn jump() {
-        let (mut stack, mut registers, mut running) = setup_environment();
-
-        eval(Psh(8), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(A, St), &mut running, &mut stack, &mut registers, false);
-        eval(Psh(8), &mut running, &mut stack, &mut registers, false);
-        eval(Mov(B, St), &mut running, &mut stack, &mut registers, false);
-
-        eval(Tel(A, B), &mut running, &mut stack, &mut registers, false);
-
-        eval(Jmp(3), &mut running, &mut stack, &mut registers, false);
-        assert_eq!(registers[Ip as usize], 2);
+    fn validation() {
+        assert!(!is_valid(Prt(A)));
+        assert!(!is_valid(Drg(A)));
+        assert!(!is_valid(Dst));
+        assert!(!is_valid(Dmp));
+        assert!(!is_valid(Hlt));
+        assert!(!is_valid(HltR(A)));
+        assert!(!is_valid(Sys(syscall::SYS_EXIT)));
+        assert!(!is_valid(Sys(syscall::SYS_WRITE)));
     }
 }
@@ -0,0 +1,613 @@
+use crate::analysis::{has_computed_jumps, stack_depths, writes};
+use crate::cfg::Cfg;
+use crate::heap::Heap;
+use crate::{Instructions, Instructions::*, OverflowMode, Registers, Registers::*, Vm, WordSize};
+
+// Registers that can be written without side effects
+fn is_general(reg: Registers) -> bool {
+    (reg as usize) < Ip as usize
+}
+
+// Instructions that can run, following both ways of every jmp
+fn live(program: &[Instructions]) -> Vec<bool> {
+    let mut seen = vec![false; program.len()];
+    let mut todo = vec![0];
+    while let Some(ip) = todo.pop() {
+        if ip >= program.len() || seen[ip] {
+            continue;
+        }
+        seen[ip] = true;
+        match program[ip] {
+            Hlt | HltR(_) => (),
+            Jmp(target) => todo.extend(&[ip + 1, target as usize]),
+            _ => todo.push(ip + 1),
+        }
+    }
+    seen
+}
+
+/// Removes the deleted (None) instructions, and makes jumps to them go to the next kept one.
+pub(crate) fn compact(rewritten: &[Option<Instructions>]) -> Vec<Instructions> {
+    // New index of each instruction, or of the next kept one when deleted
+    let mut map = vec![0; rewritten.len() + 1];
+    let mut kept = rewritten.iter().filter(|i| i.is_some()).count();
+    map[rewritten.len()] = kept;
+    for ip in (0..rewritten.len()).rev() {
+        if rewritten[ip].is_some() {
+            kept -= 1;
+            map[ip] = kept;
+        } else {
+            map[ip] = map[ip + 1];
+        }
+    }
+
+    rewritten
+        .iter()
+        .flatten()
+        .map(|instr| match *instr {
+            Jmp(target) if target >= 0 && (target as usize) < rewritten.len() => {
+                Jmp(map[target as usize] as i32)
+            }
+            instr => instr,
+        })
+        .collect()
+}
+
+// One rewrite of every pattern found
+fn peephole_pass(program: &[Instructions], stack_size: usize) -> Vec<Instructions> {
+    let mut targeted = vec![false; program.len()];
+    for instr in program {
+        if let Jmp(target) = *instr {
+            if target >= 0 && (target as usize) < program.len() {
+                targeted[target as usize] = true;
+            }
+        }
+    }
+    let live = live(program);
+    let depths = stack_depths(program, stack_size);
+
+    let mut rewritten = program.iter().map(|i| Some(*i)).collect::<Vec<_>>();
+    let mut ip = 0;
+    while ip < program.len() {
+        if !live[ip] {
+            rewritten[ip] = None;
+            ip += 1;
+            continue;
+        }
+
+        // Patterns can only span instructions that are not jumped to, except the first one
+        let window = (ip + 1..program.len())
+            .take_while(|&i| !targeted[i])
+            .count()
+            + 1;
+        // Pushes are only removed when they can't overflow the stack
+        let fits = matches!(depths[ip], Some((_, hi)) if hi < stack_size);
+        let replaced = match &program[ip..ip + window.min(3)] {
+            [Psh(n), Mov(r, St), Pop, ..] if fits && is_general(*r) => Some((vec![Set(*r, *n)], 3)),
+            [Psh(n), PopR(r), ..] if fits && is_general(*r) => Some((vec![Set(*r, *n)], 2)),
+            [Psh(_), Pop, ..] | [PshR(_), Pop, ..] if fits => Some((vec![], 2)),
+            [Mov(a, b), ..] if a == b && is_general(*a) => Some((vec![], 1)),
+            _ => None,
+        };
+
+        match replaced {
+            Some((with, len)) => {
+                for i in 0..len {
+                    rewritten[ip + i] = with.get(i).copied();
+                }
+                ip += len;
+            }
+            None => ip += 1,
+        }
+    }
+    compact(&rewritten)
+}
+
+/// Rewrites known patterns until none is left :
+/// - `psh n`, `mov r st`, `pop` and `psh n`, `pop r` become `set r n`
+/// - pushes immediately popped and `mov r r` are removed
+/// - instructions that can never run, like the ones after hlt, are removed
+///
+/// Pushes that could overflow a stack of `stack_size` slots are kept. Jumps are retargeted to
+/// the new indices. Programs writing to ip are left untouched, as their jump targets are
+/// unknown.
+pub fn peephole(program: &[Instructions], stack_size: usize) -> Vec<Instructions> {
+    if has_computed_jumps(program) {
+        return program.to_vec();
+    }
+    let mut program = program.to_vec();
+    loop {
+        let next = peephole_pass(&program, stack_size);
+        if next == program {
+            return program;
+        }
+        program = next;
+    }
+}
+
+// Known register values, None when unknown. ip, sp and st are never known.
+type Values = [Option<i64>; NumOfRegisters as usize];
+
+// Evaluates register arithmetic and tests on known values with the VM itself, None if it
+// overflows or fails. Without overflow the result is the same in every overflow mode.
+struct Folder {
+    vm: Vm,
+}
+
+impl Folder {
+    fn new(word_size: WordSize) -> Folder {
+        let mut vm = Vm::with_stack_size(1);
+        vm.heap = Heap::new(0);
+        vm.overflow = OverflowMode::Trapping;
+        vm.word_size = word_size;
+        Folder { vm }
+    }
+
+    // Value written by the instruction, in its destination or in eq for tests
+    fn fold(&mut self, instr: Instructions, regs: &Values) -> Option<i64> {
+        let (a, b, dest) = match instr {
+            Add(a, b) | Sub(a, b) | Mul(a, b) | Div(a, b) => (a, b, a),
+            Tee(a, b) | Tne(a, b) | Tll(a, b) | Tmm(a, b) | Tel(a, b) | Tem(a, b) => (a, b, Eq),
+            _ => return None,
+        };
+        self.vm.regs[a as usize] = regs[a as usize]?;
+        self.vm.regs[b as usize] = regs[b as usize]?;
+        self.vm.regs[Ip as usize] = 0;
+        self.vm.eval(instr).ok()?;
+        Some(self.vm.regs[dest as usize])
+    }
+
+    fn narrow(&self, value: i64) -> i64 {
+        match self.vm.word_size {
+            WordSize::W32 => value as i32 as i64,
+            WordSize::W64 => value,
+        }
+    }
+}
+
+// Registers after the instruction
+fn transfer(instr: Instructions, regs: &Values, folder: &mut Folder) -> Values {
+    let mut next = *regs;
+    for reg in writes(instr) {
+        next[reg as usize] = None;
+    }
+    match instr {
+        Set(r, n) => next[r as usize] = Some(folder.narrow(n)),
+        Mov(a, b) => next[a as usize] = regs[b as usize],
+        Add(a, _) | Sub(a, _) | Mul(a, _) | Div(a, _) => {
+            // of is cleared before the result is written, which can be to of
+            let result = folder.fold(instr, regs);
+            next[Of as usize] = result.map(|_| 0);
+            next[a as usize] = result;
+        }
+        Tee(..) | Tne(..) | Tll(..) | Tmm(..) | Tel(..) | Tem(..) => {
+            next[Eq as usize] = folder.fold(instr, regs)
+        }
+        // Float registers aren't followed, so their tests give an unknown eq
+        Fee(..) | Fne(..) | Fll(..) | Fmm(..) | Fel(..) | Fem(..) => next[Eq as usize] = None,
+        AddS | SubS | MulS | DivS => next[Of as usize] = None,
+        Sys(_) => next = [None; NumOfRegisters as usize], // Handlers can write anything
+        _ => (),
+    }
+    for special in &[Ip, Sp, St] {
+        next[*special as usize] = None;
+    }
+    next
+}
+
+// Whether the jmp at the end of a block is taken, None if unknown
+fn jumps(block_end: Instructions, regs: &Values) -> Option<bool> {
+    match block_end {
+        Jmp(_) => regs[Eq as usize].map(|eq| eq == 1),
+        _ => None,
+    }
+}
+
+/// Propagates constants through the registers and eq, starting from the zeroed registers of
+/// a new VM :
+/// - register arithmetic, moves and tests on known values become `set`
+/// - jmp that are never taken are removed, and blocks that can't be reached anymore too
+///
+/// Arithmetic is only folded when it doesn't overflow, so the result doesn't depend on the
+/// overflow mode. Programs writing to ip are left untouched.
+pub fn constants(program: &[Instructions], word_size: WordSize) -> Vec<Instructions> {
+    if has_computed_jumps(program) || program.is_empty() {
+        return program.to_vec();
+    }
+    let cfg = Cfg::new(program);
+    let mut folder = Folder::new(word_size);
+
+    let mut start = [Some(0); NumOfRegisters as usize];
+    for special in &[Ip, Sp, St] {
+        start[*special as usize] = None;
+    }
+
+    // Registers at the start of each block, None for blocks not reached yet. Values only go
+    // from known to unknown, so this terminates.
+    let mut entry: Vec<Option<Values>> = vec![None; cfg.blocks.len()];
+    entry[0] = Some(start);
+    let mut todo = vec![0];
+    while let Some(b) = todo.pop() {
+        let block = &cfg.blocks[b];
+        let mut regs = entry[b].unwrap();
+        for instr in &program[block.start..block.end] {
+            regs = transfer(*instr, &regs, &mut folder);
+        }
+
+        let taken = jumps(program[block.end - 1], &regs);
+        let mut next = vec![];
+        if taken != Some(false) {
+            next.extend(block.taken);
+        }
+        if taken != Some(true) {
+            next.extend(block.next);
+        }
+        for n in next {
+            let joined = match entry[n] {
+                Some(old) => {
+                    let mut joined = old;
+                    for (j, r) in joined.iter_mut().zip(regs.iter()) {
+                        if *j != *r {
+                            *j = None;
+                        }
+                    }
+                    joined
+                }
+                None => regs,
+            };
+            if entry[n] != Some(joined) {
+                entry[n] = Some(joined);
+                todo.push(n);
+            }
+        }
+    }
+
+    let mut rewritten = vec![None; program.len()];
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        let mut regs = match entry[b] {
+            Some(regs) => regs,
+            None => continue, // Never reached
+        };
+        for ip in block.start..block.end {
+            let instr = program[ip];
+            let next = transfer(instr, &regs, &mut folder);
+            rewritten[ip] = match instr {
+                Jmp(_) if jumps(instr, &regs) == Some(false) => None,
+                Set(r, _) if regs[r as usize].is_some() && regs[r as usize] == next[r as usize] => {
+                    None
+                }
+                // Arithmetic also clears of, which a set wouldn't do
+                Add(r, _) | Sub(r, _) | Mul(r, _) | Div(r, _)
+                    if r != Of && regs[Of as usize] != Some(0) =>
+                {
+                    Some(instr)
+                }
+                Mov(r, _) | Add(r, _) | Sub(r, _) | Mul(r, _) | Div(r, _)
+                    if !matches!(r, Ip | Sp | St) =>
+                {
+                    match next[r as usize] {
+                        Some(value) => Some(Set(r, value)),
+                        None => Some(instr),
+                    }
+                }
+                Tee(..) | Tne(..) | Tll(..) | Tmm(..) | Tel(..) | Tem(..) => {
+                    match next[Eq as usize] {
+                        Some(value) => Some(Set(Eq, value)),
+                        None => Some(instr),
+                    }
+                }
+                _ => Some(instr),
+            };
+            regs = next;
+        }
+    }
+    compact(&rewritten)
+}
+
+/// Optimization passes run by `optimize`, in order.
+pub const PASSES: &[&str] = &["constants", "peephole"];
+
+/// Runs every pass for a VM with `stack_size` slots, calling `after` with the name of the pass
+/// and the program it produced.
+pub fn optimize<F>(
+    program: &[Instructions],
+    word_size: WordSize,
+    stack_size: usize,
+    mut after: F,
+) -> Vec<Instructions>
+where
+    F: FnMut(&str, &[Instructions]),
+{
+    let mut program = program.to_vec();
+    for pass in PASSES {
+        program = match *pass {
+            "constants" => constants(&program, word_size),
+            _ => peephole(&program, stack_size),
+        };
+        after(pass, &program);
+    }
+    program
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::{FloatRegisters, FloatRegisters::*, Vm, VmError, STACK_SIZE};
+    use proptest::prelude::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Everything a program can observe : the values given to `sys 100` (the output), the
+    /// registers except ip, the live stack, the exit code and the error.
+    pub type Observed = (Vec<i64>, Vec<i64>, Vec<i64>, i64, Option<VmError>);
+
+    pub fn observe(program: &[Instructions]) -> Observed {
+        observe_with(program, Vm::run)
+    }
+
+    /// Same as `observe`, running the program with `run` instead of `Vm::run`.
+    pub fn observe_with<F>(program: &[Instructions], run: F) -> Observed
+    where
+        F: FnOnce(&mut Vm, &[Instructions]) -> Result<(), VmError>,
+    {
+        observe_on(Vm::new(), program, run)
+    }
+
+    /// Same as `observe_with`, running the program on `vm`.
+    pub fn observe_on<F>(mut vm: Vm, program: &[Instructions], run: F) -> Observed
+    where
+        F: FnOnce(&mut Vm, &[Instructions]) -> Result<(), VmError>,
+    {
+        let output = Rc::new(RefCell::new(vec![]));
+        let recorded = output.clone();
+        vm.register_syscall(100, move |vm| {
+            recorded.borrow_mut().push(vm.regs[A as usize]);
+            Ok(())
+        });
+
+        // Errors keep their kind, not the instruction they happened at
+        let error = run(&mut vm, program).err().map(|e| match e {
+            VmError::ArithmeticOverflow(_) => VmError::ArithmeticOverflow(0),
+            VmError::DivisionByZero(_) => VmError::DivisionByZero(0),
+            VmError::StackOverflow(_) => VmError::StackOverflow(0),
+            VmError::StackUnderflow(_) => VmError::StackUnderflow(0),
+            VmError::InvalidJump(_) => VmError::InvalidJump(0),
+            e => e,
+        });
+        let mut regs = vm.regs.to_vec();
+        regs.remove(Ip as usize);
+        let stack = vm.live_stack().to_vec();
+        let output = output.borrow().clone();
+        (output, regs, stack, vm.exit_code, error)
+    }
+
+    fn register(index: u8) -> Registers {
+        [A, B, C, St, Eq, Of][index as usize % 6]
+    }
+
+    fn float_register(index: u8) -> FloatRegisters {
+        [Fa, Fb][index as usize % 2]
+    }
+
+    // Float test of the two registers, writing eq
+    fn float_test(op: u8, a: FloatRegisters, b: FloatRegisters) -> Instructions {
+        [Fee, Fne, Fll, Fmm, Fel, Fem][op as usize % 6](a, b)
+    }
+
+    /// Programs using the patterns above, with jumps only going forward so they terminate.
+    pub fn program() -> impl Strategy<Value = Vec<Instructions>> {
+        prop::collection::vec(any::<(u8, u8, u8, i8)>(), 1..40).prop_map(|raw| {
+            let len = raw.len();
+            let mut program = raw
+                .iter()
+                .enumerate()
+                .map(|(ip, &(op, x, y, n))| {
+                    let (a, b) = (register(x), register(y));
+                    let a = if a == St { A } else { a };
+                    let (fa, fb) = (float_register(x), float_register(y));
+                    match op % 25 {
+                        0 | 1 => Psh(n as i64),
+                        12 | 13 => Set(a, n as i64),
+                        14 => Tee(a, b),
+                        15 => Sub(a, b),
+                        16 => Mul(a, b),
+                        17 => Div(a, b),
+                        18 => Dup,
+                        19 => AddS,
+                        20 => DivS,
+                        21 => Fld(fa, (n as f64 / 2.0).to_bits()),
+                        22 => Itf(fa, b),
+                        23 => float_test(n as u8, fa, fb),
+                        24 => Fti(a, fa),
+                        2 => Mov(a, b),
+                        3 => Pop,
+                        4 => PopR(a),
+                        5 => PshR(b),
+                        6 => Add(a, b),
+                        7 => Tll(a, b),
+                        8 => Jmp((ip + 1 + x as usize % (len - ip)) as i32),
+                        9 => Sys(100),
+                        10 => Hlt,
+                        _ => Mov(a, a),
+                    }
+                })
+                .collect::<Vec<Instructions>>();
+            program.push(Hlt);
+            program
+        })
+    }
+
+    #[test]
+    fn patterns() {
+        let program = [
+            Psh(3),
+            Mov(A, St),
+            Pop,
+            Psh(4),
+            PopR(B),
+            Mov(B, B),
+            Psh(1),
+            Pop,
+            Tll(A, B),
+            Jmp(11),
+            Drg(A),
+            Hlt,
+            Drg(B),
+        ];
+        assert_eq!(
+            peephole(&program, STACK_SIZE),
+            vec![Set(A, 3), Set(B, 4), Tll(A, B), Jmp(5), Drg(A), Hlt]
+        );
+    }
+
+    #[test]
+    fn jump_targets_split_patterns() {
+        // The pop is jumped to, so the push is still needed
+        let program = [Psh(3), Tee(A, A), Jmp(4), Psh(5), PopR(A), Hlt];
+        assert_eq!(peephole(&program, STACK_SIZE), program.to_vec());
+
+        let program = [Jmp(1), Mov(A, A), Hlt];
+        assert_eq!(peephole(&program, STACK_SIZE), vec![Jmp(1), Hlt]);
+    }
+
+    #[test]
+    fn overflowing_pushes_are_kept() {
+        let program = [Psh(1), Psh(2), Pop, Psh(3), PopR(A), Hlt];
+        assert_eq!(peephole(&program, 1), program.to_vec());
+        assert_eq!(peephole(&program, 2), vec![Psh(1), Set(A, 3), Hlt]);
+    }
+
+    #[test]
+    fn computed_jumps_are_kept() {
+        let program = [Psh(3), PopR(Ip), Mov(A, A), Hlt];
+        assert_eq!(peephole(&program, STACK_SIZE), program.to_vec());
+    }
+
+    #[test]
+    fn folds_constants() {
+        let program = [
+            Set(A, 6),
+            Set(B, 7),
+            Mul(A, B),
+            Mov(C, A),
+            Tll(B, A),
+            Set(B, 7),
+            Hlt,
+        ];
+        assert_eq!(
+            constants(&program, WordSize::W64),
+            vec![
+                Set(A, 6),
+                Set(B, 7),
+                Set(A, 42),
+                Set(C, 42),
+                Set(Eq, 1),
+                Hlt
+            ]
+        );
+
+        // Overflow depends on the mode, and unknown values aren't folded
+        let program = [Set(A, i32::MAX as i64), Add(A, A), PopR(B), Add(B, B), Hlt];
+        assert_eq!(constants(&program, WordSize::W32), program.to_vec());
+        assert_eq!(
+            constants(&program, WordSize::W64),
+            vec![
+                Set(A, i32::MAX as i64),
+                Set(A, (i32::MAX as i64) * 2),
+                PopR(B),
+                Add(B, B),
+                Hlt
+            ]
+        );
+    }
+
+    #[test]
+    fn folds_jumps() {
+        // 0: set a 1, 1: tne a b, 2: jmp 5, 3: prt a, 4: hlt, 5: tee a b, 6: jmp 8, 7: hlt, 8: prt b
+        let program = [
+            Set(A, 1),
+            Tne(A, B),
+            Jmp(5),
+            Prt(A),
+            Hlt,
+            Tee(A, B),
+            Jmp(8),
+            Hlt,
+            Prt(B),
+            Hlt,
+        ];
+        assert_eq!(
+            constants(&program, WordSize::W32),
+            vec![Set(A, 1), Set(Eq, 1), Jmp(3), Set(Eq, 0), Hlt]
+        );
+
+        // Loops join the values coming from every predecessor
+        let program = [
+            Set(B, 3),
+            Set(C, 1),
+            Sub(B, C),
+            Tne(B, Eq),
+            Jmp(2),
+            Prt(C),
+            Hlt,
+        ];
+        assert_eq!(constants(&program, WordSize::W32), program.to_vec());
+    }
+
+    #[test]
+    fn float_tests_write_eq() {
+        // The jmp is taken as fa == fa, which isn't known
+        let program = [
+            Fld(Fa, 1f64.to_bits()),
+            Fee(Fa, Fa),
+            Jmp(5),
+            Set(A, 1),
+            Sys(100),
+            Set(A, 2),
+            Sys(100),
+            Hlt,
+        ];
+        assert_eq!(constants(&program, WordSize::W32), program.to_vec());
+        let optimized = optimize(&program, WordSize::W32, STACK_SIZE, |_, _| ());
+        assert_eq!(observe(&optimized), observe(&program));
+        assert_eq!(observe(&program).0, [2]);
+    }
+
+    #[test]
+    fn prints_passes() {
+        let mut passes = vec![];
+        let program = optimize(
+            &[Psh(2), PopR(A), Hlt],
+            WordSize::W32,
+            STACK_SIZE,
+            |pass, program| passes.push((pass.to_string(), program.to_vec())),
+        );
+        assert_eq!(program, vec![Set(A, 2), Hlt]);
+        assert_eq!(
+            passes,
+            vec![
+                ("constants".to_string(), vec![Psh(2), PopR(A), Hlt]),
+                ("peephole".to_string(), vec![Set(A, 2), Hlt]),
+            ]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn same_behaviour(program in program(), stack_size in 1..4usize) {
+            prop_assert_eq!(observe(&peephole(&program, STACK_SIZE)), observe(&program));
+            let small = |program: &[Instructions]| {
+                observe_on(Vm::with_stack_size(stack_size), program, Vm::run)
+            };
+            prop_assert_eq!(small(&peephole(&program, stack_size)), small(&program));
+        }
+
+        #[test]
+        fn constants_same_behaviour(program in program()) {
+            let word_size = Vm::new().word_size;
+            prop_assert_eq!(observe(&constants(&program, word_size)), observe(&program));
+            let optimized = optimize(&program, word_size, STACK_SIZE, |_, _| ());
+            prop_assert_eq!(observe(&optimized), observe(&program));
+        }
+    }
+}
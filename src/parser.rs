@@ -1,5 +1,10 @@
-use crate::{Instructions, Instructions::*, Registers::*};
+use crate::{
+  FloatRegisters, FloatRegisters::*, Instructions, Instructions::*, Registers, Registers::*,
+  WordSize,
+};
+use std::fmt;
 use std::fs;
+use std::io;
 
 fn error(line: usize, whr: &str, message: &str) {
   eprintln!("{} | {}", line, whr);
@@ -7,22 +12,93 @@ fn error(line: usize, whr: &str, message: &str) {
   eprintln!("{}\n", message);
 }
 
-pub fn parse_file(filename: &str) -> Vec<Instructions> {
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ParseOptions {
+  pub strict: bool, // Rejects writes to ip, sp and st
+  pub word_size: WordSize,
+  pub reject_unknown: bool, // Rejects lines starting with an unknown mnemonic, ignored otherwise
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+  Read(io::Error), // The file could not be read
+  Invalid,         // The errors have already been reported
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ParseError::Read(e) => write!(f, "Failed to read file: {}", e),
+      ParseError::Invalid => write!(f, "Aborting due to previous errors"),
+    }
+  }
+}
+
+pub(crate) fn parse_register(raw: &str) -> Option<Registers> {
+  match raw {
+    "a" => Some(A),
+    "b" => Some(B),
+    "c" => Some(C),
+    "d" => Some(D),
+    "e" => Some(E),
+    "f" => Some(F),
+    "ip" => Some(Ip),
+    "sp" => Some(Sp),
+    "st" => Some(St),
+    "eq" => Some(Eq),
+    "of" => Some(Of),
+    _ => None,
+  }
+}
+
+fn parse_float_register(raw: &str) -> Option<FloatRegisters> {
+  match raw {
+    "fa" => Some(Fa),
+    "fb" => Some(Fb),
+    "fc" => Some(Fc),
+    "fd" => Some(Fd),
+    "fe" => Some(Fe),
+    "ff" => Some(Ff),
+    _ => None,
+  }
+}
+
+// Whether the instruction is followed by something else than a comment
+fn has_operand(splited: &[&str]) -> bool {
+  splited.len() > 1 && !splited[1].is_empty() && !splited[1].starts_with(';')
+}
+
+// Registers that programs should not write to directly
+fn is_protected(reg: Registers) -> bool {
+  matches!(reg, Ip | Sp | St)
+}
+
+pub fn parse_file(filename: &str, options: ParseOptions) -> Result<Vec<Instructions>, ParseError> {
+  let fc = fs::read_to_string(filename).map_err(ParseError::Read)?;
+  parse_source(&fc, options)
+}
+
+/// Parses a program, reporting the errors on stderr.
+pub fn parse_source(source: &str, options: ParseOptions) -> Result<Vec<Instructions>, ParseError> {
+  parse_with_lines(source, options).map(|(instrs, _)| instrs)
+}
+
+/// Same as `parse_source`, also giving the line of each instruction, None for the `hlt` added
+/// at the end of programs not ending with one.
+pub fn parse_with_lines(
+  source: &str,
+  options: ParseOptions,
+) -> Result<(Vec<Instructions>, Vec<Option<usize>>), ParseError> {
   let mut instrs: Vec<Instructions> = vec![];
+  let mut source_lines: Vec<Option<usize>> = vec![];
   let mut had_error = false;
 
-  let fc = match fs::read_to_string(filename) {
-    Ok(s) => s,
-    Err(e) => {
-      eprintln!("Failed to read file");
-      eprintln!("{}", e);
-      std::process::exit(-1);
-    }
-  };
-  let lines = fc.split('\n').collect::<Vec<&str>>();
+  let lines = source.split('\n').collect::<Vec<&str>>();
 
   let mut ln = 0usize;
   for line in lines {
+    // Lines hold one instruction at most
+    source_lines.resize(instrs.len(), Some(ln));
     ln += 1;
     let splited = line.split(' ').collect::<Vec<&str>>();
 
@@ -41,32 +117,17 @@ pub fn parse_file(filename: &str) -> Vec<Instructions> {
 
         let raw = splited[1];
 
-        let reg = if raw == "a" {
-          A
-        } else if raw == "b" {
-          B
-        } else if raw == "c" {
-          C
-        } else if raw == "e" {
-          D
-        } else if raw == "d" {
-          E
-        } else if raw == "f" {
-          F
-        } else if raw == "ip" {
-          Ip
-        } else if raw == "sp" {
-          Sp
-        } else if raw == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw),
-          );
-          had_error = true;
-          continue;
+        let reg = match parse_register(raw) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
         instrs.push(Prt(reg))
@@ -85,60 +146,30 @@ pub fn parse_file(filename: &str) -> Vec<Instructions> {
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match parse_register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match parse_register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
         instrs.push(Tee(reg_a, reg_b));
@@ -157,60 +188,30 @@ pub fn parse_file(filename: &str) -> Vec<Instructions> {
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match parse_register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match parse_register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
         instrs.push(Tne(reg_a, reg_b));
@@ -229,60 +230,30 @@ pub fn parse_file(filename: &str) -> Vec<Instructions> {
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match parse_register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match parse_register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
         instrs.push(Tll(reg_a, reg_b));
@@ -301,60 +272,30 @@ pub fn parse_file(filename: &str) -> Vec<Instructions> {
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match parse_register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match parse_register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
         instrs.push(Tmm(reg_a, reg_b));
@@ -373,60 +314,30 @@ pub fn parse_file(filename: &str) -> Vec<Instructions> {
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match parse_register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match parse_register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
         instrs.push(Tel(reg_a, reg_b));
@@ -445,60 +356,30 @@ pub fn parse_file(filename: &str) -> Vec<Instructions> {
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match parse_register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_b),
-          );
-          had_error = true;
-          continue;
+        let reg_b = match parse_register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
         instrs.push(Tem(reg_a, reg_b));
@@ -525,174 +406,203 @@ pub fn parse_file(filename: &str) -> Vec<Instructions> {
 
         instrs.push(Jmp(instruction as i32));
       }
-      "psh" => {
+      "sys" => {
         if splited.len() < 2 {
-          error(ln, line, "Syntax error: valid syntax: `psh <integer>`");
+          error(
+            ln,
+            line,
+            "Syntax error: valid syntax: `sys <syscall_number>`",
+          );
           had_error = true;
           continue;
         }
 
-        let to_psh = match splited[1].parse::<i32>() {
-          Ok(i) => i,
+        let number = match splited[1].parse::<u32>() {
+          Ok(n) => n,
           Err(_e) => {
             error(
               ln,
               line,
-              &format!("Type error : {} is not a valid integer", splited[1]),
+              &format!("Type error : {} is not valid integer >= 0", splited[1]),
             );
             had_error = true;
             continue;
           }
         };
 
-        instrs.push(Psh(to_psh));
+        instrs.push(Sys(number));
       }
-      "mov" => {
-        if splited.len() < 3 {
+      "psh" => {
+        if splited.len() < 2 {
           error(
             ln,
             line,
-            "Syntax error: valid syntax: `mov <register_a> <register_b>`",
+            "Syntax error: valid syntax: `psh <integer>` or `psh <register>`",
           );
           had_error = true;
           continue;
         }
 
-        let raw_a = splited[1];
-        let raw_b = splited[2];
+        let to_psh = match splited[1].parse::<i64>() {
+          Ok(i) => i,
+          Err(_e) => {
+            if let Some(reg) = parse_register(splited[1]) {
+              instrs.push(PshR(reg));
+              continue;
+            }
+            error(
+              ln,
+              line,
+              &format!(
+                "Type error : {} is not a valid integer or register",
+                splited[1]
+              ),
+            );
+            had_error = true;
+            continue;
+          }
+        };
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
+        if options.word_size == WordSize::W32 && to_psh as i32 as i64 != to_psh {
           error(
             ln,
             line,
-            &format!("Type error : {} is not a valid register", raw_a),
+            &format!(
+              "Type error : {} does not fit in a 32 bit word, use `--word-size 64`",
+              splited[1]
+            ),
           );
           had_error = true;
           continue;
-        };
+        }
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
+        instrs.push(Psh(to_psh));
+      }
+      "set" => {
+        if splited.len() < 3 {
           error(
             ln,
             line,
-            &format!("Type error : {} is not a valid register", raw_b),
+            "Syntax error: valid syntax: `set <register> <integer>`",
           );
           had_error = true;
           continue;
+        }
+
+        let reg = match parse_register(splited[1]) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", splited[1]),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
-        instrs.push(Mov(reg_a, reg_b));
-      }
+        let value = match splited[2].parse::<i64>() {
+          Ok(i) if options.word_size == WordSize::W64 || i as i32 as i64 == i => i,
+          Ok(_) => {
+            error(
+              ln,
+              line,
+              &format!(
+                "Type error : {} does not fit in a 32 bit word, use `--word-size 64`",
+                splited[2]
+              ),
+            );
+            had_error = true;
+            continue;
+          }
+          Err(_e) => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid integer", splited[2]),
+            );
+            had_error = true;
+            continue;
+          }
+        };
 
-      "add" => {
-        if splited.len() < 3 {
+        if options.strict && is_protected(reg) {
           error(
             ln,
             line,
-            "Syntax error: valid syntax: `mov <register_a> <register_b>`",
+            &format!(
+              "Access error : {} cannot be written in strict mode",
+              splited[1]
+            ),
           );
           had_error = true;
           continue;
         }
 
-        let raw_a = splited[1];
-        let raw_b = splited[2];
-
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
+        instrs.push(Set(reg, value));
+      }
+      "mov" => {
+        if splited.len() < 3 {
+          error(
             ln,
             line,
-            &format!("Type error : {} is not a valid register", raw_a),
+            "Syntax error: valid syntax: `mov <register_a> <register_b>`",
           );
           had_error = true;
           continue;
+        }
+
+        let raw_a = splited[1];
+        let raw_b = splited[2];
+
+        let reg_a = match parse_register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        let reg_b = match parse_register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
+        if options.strict && is_protected(reg_a) {
           error(
             ln,
             line,
-            &format!("Type error : {} is not a valid register", raw_b),
+            &format!(
+              "Access error : {} cannot be written in strict mode",
+              splited[1]
+            ),
           );
           had_error = true;
           continue;
-        };
+        }
 
-        instrs.push(Add(reg_a, reg_b));
+        instrs.push(Mov(reg_a, reg_b));
       }
-      "sub" => {
+
+      "add" => {
+        if !has_operand(&splited) {
+          instrs.push(AddS);
+          continue;
+        }
         if splited.len() < 3 {
           error(
             ln,
@@ -706,65 +616,111 @@ pub fn parse_file(filename: &str) -> Vec<Instructions> {
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
+        let reg_a = match parse_register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        let reg_b = match parse_register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        if options.strict && is_protected(reg_a) {
           error(
             ln,
             line,
-            &format!("Type error : {} is not a valid register", raw_a),
+            &format!(
+              "Access error : {} cannot be written in strict mode",
+              splited[1]
+            ),
           );
           had_error = true;
           continue;
-        };
+        }
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
+        instrs.push(Add(reg_a, reg_b));
+      }
+      "sub" => {
+        if !has_operand(&splited) {
+          instrs.push(SubS);
+          continue;
+        }
+        if splited.len() < 3 {
           error(
             ln,
             line,
-            &format!("Type error : {} is not a valid register", raw_b),
+            "Syntax error: valid syntax: `mov <register_a> <register_b>`",
           );
           had_error = true;
           continue;
+        }
+
+        let raw_a = splited[1];
+        let raw_b = splited[2];
+
+        let reg_a = match parse_register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        let reg_b = match parse_register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
+        if options.strict && is_protected(reg_a) {
+          error(
+            ln,
+            line,
+            &format!(
+              "Access error : {} cannot be written in strict mode",
+              splited[1]
+            ),
+          );
+          had_error = true;
+          continue;
+        }
+
         instrs.push(Sub(reg_a, reg_b));
       }
       "mul" => {
+        if !has_operand(&splited) {
+          instrs.push(MulS);
+          continue;
+        }
         if splited.len() < 3 {
           error(
             ln,
@@ -778,65 +734,52 @@ pub fn parse_file(filename: &str) -> Vec<Instructions> {
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
-          error(
-            ln,
-            line,
-            &format!("Type error : {} is not a valid register", raw_a),
-          );
-          had_error = true;
-          continue;
+        let reg_a = match parse_register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        let reg_b = match parse_register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
+        if options.strict && is_protected(reg_a) {
           error(
             ln,
             line,
-            &format!("Type error : {} is not a valid register", raw_b),
+            &format!(
+              "Access error : {} cannot be written in strict mode",
+              splited[1]
+            ),
           );
           had_error = true;
           continue;
-        };
+        }
 
         instrs.push(Mul(reg_a, reg_b));
       }
       "div" => {
+        if !has_operand(&splited) {
+          instrs.push(DivS);
+          continue;
+        }
         if splited.len() < 3 {
           error(
             ln,
@@ -850,66 +793,87 @@ pub fn parse_file(filename: &str) -> Vec<Instructions> {
         let raw_a = splited[1];
         let raw_b = splited[2];
 
-        let reg_a = if raw_a == "a" {
-          A
-        } else if raw_a == "b" {
-          B
-        } else if raw_a == "c" {
-          C
-        } else if raw_a == "e" {
-          D
-        } else if raw_a == "d" {
-          E
-        } else if raw_a == "f" {
-          F
-        } else if raw_a == "ip" {
-          Ip
-        } else if raw_a == "sp" {
-          Sp
-        } else if raw_a == "st" {
-          St
-        } else {
+        let reg_a = match parse_register(raw_a) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_a),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        let reg_b = match parse_register(raw_b) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw_b),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        if options.strict && is_protected(reg_a) {
           error(
             ln,
             line,
-            &format!("Type error : {} is not a valid register", raw_a),
+            &format!(
+              "Access error : {} cannot be written in strict mode",
+              splited[1]
+            ),
           );
           had_error = true;
           continue;
+        }
+
+        instrs.push(Div(reg_a, reg_b));
+      }
+      "pop" => {
+        if !has_operand(&splited) {
+          instrs.push(Pop);
+          continue;
+        }
+
+        let reg = match parse_register(splited[1]) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", splited[1]),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
-        let reg_b = if raw_b == "a" {
-          A
-        } else if raw_b == "b" {
-          B
-        } else if raw_b == "c" {
-          C
-        } else if raw_b == "e" {
-          D
-        } else if raw_b == "d" {
-          E
-        } else if raw_b == "f" {
-          F
-        } else if raw_b == "ip" {
-          Ip
-        } else if raw_b == "sp" {
-          Sp
-        } else if raw_b == "st" {
-          St
-        } else {
+        if options.strict && is_protected(reg) {
           error(
             ln,
             line,
-            &format!("Type error : {} is not a valid register", raw_b),
+            &format!(
+              "Access error : {} cannot be written in strict mode",
+              splited[1]
+            ),
           );
           had_error = true;
           continue;
-        };
+        }
 
-        instrs.push(Div(reg_a, reg_b));
+        instrs.push(PopR(reg));
       }
-      "pop" => instrs.push(Pop),
+      "dup" => instrs.push(Dup),
+      "swp" => instrs.push(Swp),
+      "ovr" => instrs.push(Ovr),
+      "rot" => instrs.push(Rot),
       "dst" => instrs.push(Dst),
+      "snp" => instrs.push(Snp),
       "drg" => {
         if splited.len() < 2 {
           error(ln, line, "Syntax error: valid syntax: `drg <register>`");
@@ -919,50 +883,340 @@ pub fn parse_file(filename: &str) -> Vec<Instructions> {
 
         let raw = splited[1];
 
-        let reg = if raw == "a" {
-          A
-        } else if raw == "b" {
-          B
-        } else if raw == "c" {
-          C
-        } else if raw == "e" {
-          D
-        } else if raw == "d" {
-          E
-        } else if raw == "f" {
-          F
-        } else if raw == "ip" {
-          Ip
-        } else if raw == "sp" {
-          Sp
-        } else if raw == "st" {
-          St
-        } else if raw == "eq" {
-          Eq
-        } else {
+        let reg = match parse_register(raw) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        instrs.push(Drg(reg));
+      }
+      "fld" => {
+        if splited.len() < 3 {
+          error(
+            ln,
+            line,
+            "Syntax error: valid syntax: `fld <float_register> <float>`",
+          );
+          had_error = true;
+          continue;
+        }
+
+        let freg = match parse_float_register(splited[1]) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid float register", splited[1]),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        let value = match splited[2].parse::<f64>() {
+          Ok(f) => f,
+          Err(_e) => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid float", splited[2]),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        instrs.push(Fld(freg, value.to_bits()));
+      }
+      "fmv" | "fad" | "fsb" | "fml" | "fdv" | "fee" | "fne" | "fll" | "fmm" | "fel" | "fem" => {
+        if splited.len() < 3 {
           error(
             ln,
             line,
-            &format!("Type error : {} is not a valid register", raw),
+            &format!(
+              "Syntax error: valid syntax: `{} <float_register_a> <float_register_b>`",
+              splited[0]
+            ),
           );
           had_error = true;
           continue;
+        }
+
+        let mut fregs = vec![];
+        for raw in &splited[1..3] {
+          match parse_float_register(raw) {
+            Some(r) => fregs.push(r),
+            None => error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid float register", raw),
+            ),
+          }
+        }
+        if fregs.len() < 2 {
+          had_error = true;
+          continue;
+        }
+        let (a, b) = (fregs[0], fregs[1]);
+
+        instrs.push(match splited[0] {
+          "fmv" => Fmv(a, b),
+          "fad" => Fad(a, b),
+          "fsb" => Fsb(a, b),
+          "fml" => Fml(a, b),
+          "fdv" => Fdv(a, b),
+          "fee" => Fee(a, b),
+          "fne" => Fne(a, b),
+          "fll" => Fll(a, b),
+          "fmm" => Fmm(a, b),
+          "fel" => Fel(a, b),
+          _ => Fem(a, b),
+        });
+      }
+      "itf" | "fti" => {
+        let (freg_pos, reg_pos) = if splited[0] == "itf" { (1, 2) } else { (2, 1) };
+        if splited.len() < 3 {
+          error(
+            ln,
+            line,
+            if splited[0] == "itf" {
+              "Syntax error: valid syntax: `itf <float_register> <register>`"
+            } else {
+              "Syntax error: valid syntax: `fti <register> <float_register>`"
+            },
+          );
+          had_error = true;
+          continue;
+        }
+
+        let freg = match parse_float_register(splited[freg_pos]) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!(
+                "Type error : {} is not a valid float register",
+                splited[freg_pos]
+              ),
+            );
+            had_error = true;
+            continue;
+          }
         };
 
-        instrs.push(Drg(reg));
+        let reg = match parse_register(splited[reg_pos]) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", splited[reg_pos]),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        if splited[0] == "itf" {
+          instrs.push(Itf(freg, reg));
+          continue;
+        }
+
+        if options.strict && is_protected(reg) {
+          error(
+            ln,
+            line,
+            &format!(
+              "Access error : {} cannot be written in strict mode",
+              splited[1]
+            ),
+          );
+          had_error = true;
+          continue;
+        }
+
+        instrs.push(Fti(reg, freg));
+      }
+      "fpr" => {
+        if splited.len() < 2 {
+          error(
+            ln,
+            line,
+            "Syntax error: valid syntax: `fpr <float_register>`",
+          );
+          had_error = true;
+          continue;
+        }
+
+        let freg = match parse_float_register(splited[1]) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid float register", splited[1]),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        instrs.push(Fpr(freg));
+      }
+      "alc" | "lod" | "sto" => {
+        if splited.len() < 3 {
+          error(
+            ln,
+            line,
+            match splited[0] {
+              "alc" => "Syntax error: valid syntax: `alc <register_size> <register_dest>`",
+              "lod" => "Syntax error: valid syntax: `lod <register_dest> <register_address>`",
+              _ => "Syntax error: valid syntax: `sto <register_address> <register_src>`",
+            },
+          );
+          had_error = true;
+          continue;
+        }
+
+        let mut regs = vec![];
+        for raw in &splited[1..3] {
+          match parse_register(raw) {
+            Some(r) => regs.push(r),
+            None => error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", raw),
+            ),
+          }
+        }
+        if regs.len() < 2 {
+          had_error = true;
+          continue;
+        }
+        let (a, b) = (regs[0], regs[1]);
+
+        let dest = match splited[0] {
+          "alc" => Some((b, splited[2])),
+          "lod" => Some((a, splited[1])),
+          _ => None,
+        };
+        if let Some((dest, raw)) = dest {
+          if options.strict && is_protected(dest) {
+            error(
+              ln,
+              line,
+              &format!("Access error : {} cannot be written in strict mode", raw),
+            );
+            had_error = true;
+            continue;
+          }
+        }
+
+        instrs.push(match splited[0] {
+          "alc" => Alc(a, b),
+          "lod" => Lod(a, b),
+          _ => Sto(a, b),
+        });
+      }
+      "fre" => {
+        if splited.len() < 2 {
+          error(
+            ln,
+            line,
+            "Syntax error: valid syntax: `fre <register_address>`",
+          );
+          had_error = true;
+          continue;
+        }
+
+        let reg = match parse_register(splited[1]) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", splited[1]),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        instrs.push(Fre(reg));
       }
       "hlt" => {
-        instrs.push(Hlt);
-        return instrs;
+        if !has_operand(&splited) {
+          instrs.push(Hlt);
+          break;
+        }
+
+        let reg = match parse_register(splited[1]) {
+          Some(r) => r,
+          None => {
+            error(
+              ln,
+              line,
+              &format!("Type error : {} is not a valid register", splited[1]),
+            );
+            had_error = true;
+            continue;
+          }
+        };
+
+        instrs.push(HltR(reg));
+        break;
       }
 
-      _ => (),
+      mnemonic => {
+        if options.reject_unknown && !mnemonic.is_empty() {
+          error(
+            ln,
+            line,
+            &format!("Syntax error : unknown instruction `{}`", mnemonic),
+          );
+          had_error = true;
+        }
+      }
     }
   }
   if had_error {
-    eprintln!("Aborting due to previous errors");
-    std::process::exit(-7);
+    return Err(ParseError::Invalid);
+  }
+  source_lines.resize(instrs.len(), Some(ln));
+  if !matches!(instrs.last(), Some(Hlt) | Some(HltR(_))) {
+    instrs.push(Hlt);
+    source_lines.push(None);
+  }
+  Ok((instrs, source_lines))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn unknown_mnemonics() {
+    let source = "psh 3\npus 4\n\n; comment\naddd\nhlt";
+    let program = parse_source(source, ParseOptions::default()).unwrap();
+    assert_eq!(program, [Psh(3), Hlt]);
+    let options = ParseOptions {
+      reject_unknown: true,
+      ..ParseOptions::default()
+    };
+    assert!(matches!(
+      parse_source(source, options),
+      Err(ParseError::Invalid)
+    ));
+    assert!(parse_source("psh 3\n\n; comment\nhlt", options).is_ok());
   }
-  instrs.push(Hlt);
-  instrs
 }
@@ -0,0 +1,217 @@
+use crate::cfg::Cfg;
+use crate::engine::Decoded;
+use crate::{Instructions, Instructions::*, Registers::*, Vm, VmError};
+use std::fmt::Write;
+
+/// Number of times each instruction of a program ran.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    pub counts: Vec<u64>,
+    pub taken: Vec<u64>, // Times each jmp jumped, the others going to the next instruction
+}
+
+impl Profile {
+    pub fn new(program: &[Instructions]) -> Profile {
+        Profile {
+            counts: vec![0; program.len()],
+            taken: vec![0; program.len()],
+        }
+    }
+
+    /// Number of instructions run.
+    pub fn steps(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Number of times each block was entered, which is the count of its first instruction
+    /// unless computed jumps land in the middle of blocks.
+    pub fn blocks(&self, cfg: &Cfg) -> Vec<u64> {
+        cfg.blocks.iter().map(|b| self.counts[b.start]).collect()
+    }
+
+    /// The `n` instructions run the most, the first ones first among those run as much.
+    pub fn hottest(&self, n: usize) -> Vec<usize> {
+        let mut ips = (0..self.counts.len())
+            .filter(|&ip| self.counts[ip] > 0)
+            .collect::<Vec<usize>>();
+        ips.sort_by_key(|&ip| std::cmp::Reverse(self.counts[ip]));
+        ips.truncate(n);
+        ips
+    }
+
+    /// Text report of the `top` hottest instructions and blocks, and of the jmps run, with
+    /// the source line of each instruction when known.
+    pub fn report(&self, program: &[Instructions], lines: &[Option<usize>], top: usize) -> String {
+        let steps = self.steps();
+        let percent = |count: u64| 100.0 * count as f64 / steps.max(1) as f64;
+        let line = |ip: usize| match lines.get(ip).copied().flatten() {
+            Some(line) => line.to_string(),
+            None => String::from("-"),
+        };
+
+        let mut report = format!("{} instructions run\n", steps);
+        report += "\nHottest instructions\n";
+        report += "     count       %    ip  line  instruction\n";
+        for ip in self.hottest(top) {
+            let count = self.counts[ip];
+            let _ = writeln!(
+                report,
+                "{:>10} {:>6.2}% {:>5} {:>5}  {}",
+                count,
+                percent(count),
+                ip,
+                line(ip),
+                program[ip]
+            );
+        }
+
+        let cfg = Cfg::new(program);
+        let blocks = self.blocks(&cfg);
+        let mut hot = (0..blocks.len())
+            .filter(|&b| blocks[b] > 0)
+            .collect::<Vec<usize>>();
+        hot.sort_by_key(|&b| std::cmp::Reverse(blocks[b]));
+        report += "\nHottest blocks\n";
+        report += "   entries       %  instructions\n";
+        for &b in hot.iter().take(top) {
+            let block = &cfg.blocks[b];
+            let run: u64 = self.counts[block.start..block.end].iter().sum();
+            let _ = writeln!(
+                report,
+                "{:>10} {:>6.2}%  {}..{}",
+                blocks[b],
+                percent(run),
+                block.start,
+                block.end - 1
+            );
+        }
+
+        report += "\nJumps\n";
+        report += "    ip  line       taken   not taken\n";
+        for (ip, instr) in program.iter().enumerate() {
+            if matches!(instr, Jmp(_)) && self.counts[ip] > 0 {
+                let _ = writeln!(
+                    report,
+                    "{:>6} {:>5} {:>11} {:>11}",
+                    ip,
+                    line(ip),
+                    self.taken[ip],
+                    self.counts[ip] - self.taken[ip]
+                );
+            }
+        }
+        report
+    }
+}
+
+impl Vm {
+    /// Same as `run_decoded`, counting the instructions run in `profile`, including the one
+    /// that failed.
+    pub fn run_profiled(
+        &mut self,
+        program: &Decoded,
+        profile: &mut Profile,
+    ) -> Result<(), VmError> {
+        let len = profile.counts.len();
+        while self.running {
+            let ip = self.regs[Ip as usize];
+            // Jumps out of the program and running out of fuel fail before running anything
+            if ip >= 0 && (ip as usize) < len && self.fuel != Some(0) {
+                profile.counts[ip as usize] += 1;
+                if let Some(Jmp(_)) = program.instruction(ip as usize) {
+                    if self.regs[Eq as usize] == 1 {
+                        profile.taken[ip as usize] += 1;
+                    }
+                }
+            }
+            self.step_decoded(program)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::{parse_with_lines, ParseOptions};
+
+    // Counts down from 3, printing each value
+    const COUNTDOWN: &str = "; countdown\nset a 3\nset b 1\nset c 0\ndrg a\nsub a b\n\
+                             tne a c\n; loop\njmp 3\nhlt";
+
+    fn profile(source: &str) -> (Vec<Instructions>, Vec<Option<usize>>, Profile) {
+        let (program, lines) = parse_with_lines(source, ParseOptions::default()).unwrap();
+        let mut profile = Profile::new(&program);
+        let mut vm = Vm::new();
+        vm.output = Box::new(std::io::sink());
+        vm.run_profiled(&Decoded::new(&program), &mut profile)
+            .unwrap();
+        (program, lines, profile)
+    }
+
+    #[test]
+    fn lines() {
+        let (program, lines) =
+            parse_with_lines("; test\nset a 1\n\ndrg a", ParseOptions::default()).unwrap();
+        assert_eq!(program, [Set(A, 1), Drg(A), Hlt]);
+        assert_eq!(lines, [Some(2), Some(4), None]);
+        let (_, lines) = parse_with_lines("hlt\nset a 1", ParseOptions::default()).unwrap();
+        assert_eq!(lines, [Some(1)]);
+    }
+
+    #[test]
+    fn counts() {
+        let (program, lines, profile) = profile(COUNTDOWN);
+        assert_eq!(lines[3], Some(5));
+        assert_eq!(profile.counts, [1, 1, 1, 3, 3, 3, 3, 1]);
+        assert_eq!(profile.steps(), 16);
+        assert_eq!(profile.taken[6], 2);
+        assert_eq!(profile.hottest(2), [3, 4]);
+
+        let cfg = Cfg::new(&program);
+        assert_eq!(profile.blocks(&cfg), [1, 3, 1]);
+    }
+
+    #[test]
+    fn failing() {
+        let program = [Set(A, 0), Div(A, A), Hlt];
+        let mut profile = Profile::new(&program);
+        let result = Vm::new().run_profiled(&Decoded::new(&program), &mut profile);
+        assert_eq!(result, Err(VmError::DivisionByZero(1)));
+        assert_eq!(profile.counts, [1, 1, 0]);
+
+        let program = [Jmp(0), Hlt];
+        let mut vm = Vm::new();
+        vm.regs[Eq as usize] = 1;
+        vm.fuel = Some(2);
+        let mut profile = Profile::new(&program);
+        let result = vm.run_profiled(&Decoded::new(&program), &mut profile);
+        assert_eq!(result, Err(VmError::OutOfFuel(0)));
+        assert_eq!(profile.counts, [2, 0]);
+        assert_eq!(profile.taken, [2, 0]);
+    }
+
+    #[test]
+    fn report() {
+        let (program, lines, profile) = profile(COUNTDOWN);
+        let report = profile.report(&program, &lines, 2);
+        assert_eq!(
+            report,
+            "16 instructions run\n\
+             \n\
+             Hottest instructions\n     \
+             count       %    ip  line  instruction\n         \
+             3  18.75%     3     5  drg a\n         \
+             3  18.75%     4     6  sub a b\n\
+             \n\
+             Hottest blocks\n   \
+             entries       %  instructions\n         \
+             3  75.00%  3..6\n         \
+             1  18.75%  0..2\n\
+             \n\
+             Jumps\n    \
+             ip  line       taken   not taken\n     \
+             6     9           2           1\n"
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use crate::bytecode::{self, BytecodeError};
+use crate::heap::Heap;
+use crate::FloatRegisters::NumOfFloatRegisters;
+use crate::{Instructions, OverflowMode, Registers::*, Vm};
+use std::fmt;
+use std::rc::Rc;
+
+/// Called by `snp`, see `Vm::on_snapshot`.
+pub type Handler = Rc<dyn Fn(&Vm)>;
+
+// Layout of a snapshot, integers being little endian :
+//
+// magic     : b"WLVS"
+// version   : u8
+// program   : u64 length, then the program assembled with its word size
+// running   : u8, 0 once halted
+// overflow  : u8, 0 wrap, 1 saturate, 2 trap
+// exit code : i64
+// fuel      : u8 1 then u64 if limited, u8 0 otherwise
+// written   : u64, number of bytes printed
+// registers : 11 i64, then the 6 float registers as the u64 of their bits
+// stack     : u64 length, then every slot as i64, dead ones included
+// heap      : u64 length, then every word as i64, followed by the allocated blocks and the
+//             freed ones, each a u64 count of (u64 address, u64 size) pairs
+pub const MAGIC: &[u8; 4] = b"WLVS";
+pub const VERSION: u8 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Program(BytecodeError),
+    Invalid(&'static str), // The part of the state that can't be restored
+    Truncated,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not a wlvm snapshot"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {}", v)
+            }
+            SnapshotError::Program(e) => write!(f, "invalid program: {}", e),
+            SnapshotError::Invalid(what) => write!(f, "invalid {}", what),
+            SnapshotError::Truncated => write!(f, "unexpected end of file"),
+        }
+    }
+}
+
+fn u64s(out: &mut Vec<u8>, values: impl Iterator<Item = u64>) {
+    for value in values {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn blocks(out: &mut Vec<u8>, blocks: &[(usize, usize)]) {
+    out.extend_from_slice(&(blocks.len() as u64).to_le_bytes());
+    u64s(
+        out,
+        blocks
+            .iter()
+            .flat_map(|&(start, size)| [start as u64, size as u64]),
+    );
+}
+
+/// Saves everything needed to resume running the program on the VM, except the syscall
+/// handlers and where the output goes.
+pub fn save(vm: &Vm, program: &[Instructions]) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    let assembled = bytecode::encode(program, vm.word_size);
+    out.extend_from_slice(&(assembled.len() as u64).to_le_bytes());
+    out.extend_from_slice(&assembled);
+
+    out.push(vm.running as u8);
+    out.push(match vm.overflow {
+        OverflowMode::Wrapping => 0,
+        OverflowMode::Saturating => 1,
+        OverflowMode::Trapping => 2,
+    });
+    out.extend_from_slice(&vm.exit_code.to_le_bytes());
+    match vm.fuel {
+        Some(fuel) => {
+            out.push(1);
+            out.extend_from_slice(&fuel.to_le_bytes());
+        }
+        None => out.push(0),
+    }
+    out.extend_from_slice(&vm.written.to_le_bytes());
+
+    u64s(&mut out, vm.regs.iter().map(|&reg| reg as u64));
+    u64s(&mut out, vm.fregs.iter().map(|reg| reg.to_bits()));
+    out.extend_from_slice(&(vm.stack.len() as u64).to_le_bytes());
+    u64s(&mut out, vm.stack.iter().map(|&slot| slot as u64));
+    out.extend_from_slice(&(vm.heap.memory.len() as u64).to_le_bytes());
+    u64s(&mut out, vm.heap.memory.iter().map(|&word| word as u64));
+    blocks(&mut out, &vm.heap.leaks());
+    blocks(&mut out, &vm.heap.freed());
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if n > self.bytes.len() - self.pos {
+            return Err(SnapshotError::Truncated);
+        }
+        let taken = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    // Length of a list of items of `size` bytes, checked against the bytes left
+    fn len(&mut self, size: usize) -> Result<usize, SnapshotError> {
+        let len = self.u64()?;
+        match len.checked_mul(size as u64) {
+            Some(bytes) if bytes <= (self.bytes.len() - self.pos) as u64 => Ok(len as usize),
+            _ => Err(SnapshotError::Truncated),
+        }
+    }
+
+    fn words(&mut self) -> Result<Vec<i64>, SnapshotError> {
+        let len = self.len(8)?;
+        (0..len).map(|_| Ok(self.u64()? as i64)).collect()
+    }
+
+    fn blocks(&mut self) -> Result<Vec<(usize, usize)>, SnapshotError> {
+        let len = self.len(16)?;
+        (0..len)
+            .map(|_| Ok((self.u64()? as usize, self.u64()? as usize)))
+            .collect()
+    }
+}
+
+/// Reads back a VM and its program saved by `save`. The VM has the standard syscalls and prints
+/// to stdout.
+pub fn load(bytes: &[u8]) -> Result<(Vm, Vec<Instructions>), SnapshotError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(SnapshotError::BadMagic);
+    }
+    let mut reader = Reader {
+        bytes,
+        pos: MAGIC.len(),
+    };
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let len = reader.len(1)?;
+    let (word_size, program) =
+        bytecode::decode(reader.take(len)?).map_err(SnapshotError::Program)?;
+
+    let running = reader.byte()? != 0;
+    let overflow = match reader.byte()? {
+        0 => OverflowMode::Wrapping,
+        1 => OverflowMode::Saturating,
+        2 => OverflowMode::Trapping,
+        _ => return Err(SnapshotError::Invalid("overflow mode")),
+    };
+    let exit_code = reader.u64()? as i64;
+    let fuel = match reader.byte()? {
+        0 => None,
+        _ => Some(reader.u64()?),
+    };
+    let written = reader.u64()?;
+
+    let mut regs = [0; NumOfRegisters as usize];
+    for reg in regs.iter_mut() {
+        *reg = reader.u64()? as i64;
+    }
+    let mut fregs = [0.0; NumOfFloatRegisters as usize];
+    for reg in fregs.iter_mut() {
+        *reg = f64::from_bits(reader.u64()?);
+    }
+    let stack = reader.words()?;
+    if stack.is_empty() || regs[Sp as usize] < -1 || regs[Sp as usize] >= stack.len() as i64 {
+        return Err(SnapshotError::Invalid("stack"));
+    }
+    let memory = reader.words()?;
+    let (live, freed) = (reader.blocks()?, reader.blocks()?);
+    let heap = Heap::from_parts(memory, &live, &freed).ok_or(SnapshotError::Invalid("heap"))?;
+
+    let mut vm = Vm::with_stack_size(stack.len());
+    vm.stack = stack;
+    vm.regs = regs;
+    vm.fregs = fregs;
+    vm.heap = heap;
+    vm.running = running;
+    vm.overflow = overflow;
+    vm.word_size = word_size;
+    vm.exit_code = exit_code;
+    vm.fuel = fuel;
+    vm.written = written;
+    Ok((vm, program))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::{parse_source, ParseOptions};
+    use crate::{Instructions::*, VmError, WordSize, HEAP_SIZE};
+    use std::cell::RefCell;
+
+    // Fills a list on the heap and sums it on the stack, taking snapshots along the way
+    const PROGRAM: &str = "set a 5\nalc a b\nset c 1\nset d 0\nset e 1\nmov f b\n\
+                           ; fill\nsto b a\nadd b c\nsub a c\nsnp\ntne a d\njmp 6\n\
+                           fld fa 2.5\nalc c b\nfre b\nsnp\nset b 5\nset a 0\n\
+                           ; sum\nlod c f\npsh c\nadd a c\nprt a\nsnp\nsub b e\nadd f e\n\
+                           tne b d\njmp 18\nset c 5\nsub f c\nfre f\nhlt a";
+
+    // Same state, the output and the syscalls left aside
+    fn assert_same(vm: &Vm, other: &Vm) {
+        assert_eq!(vm.regs, other.regs);
+        assert_eq!(vm.fregs, other.fregs);
+        assert_eq!(vm.stack, other.stack);
+        assert_eq!(vm.heap.memory, other.heap.memory);
+        assert_eq!(vm.heap.leaks(), other.heap.leaks());
+        assert_eq!(vm.heap.freed(), other.heap.freed());
+        assert_eq!(
+            (vm.running, vm.overflow, vm.word_size),
+            (other.running, other.overflow, other.word_size)
+        );
+        assert_eq!(
+            (vm.exit_code, vm.fuel, vm.written),
+            (other.exit_code, other.fuel, other.written)
+        );
+    }
+
+    fn new_vm() -> Vm {
+        let mut vm = Vm::new();
+        vm.output = Box::new(std::io::sink());
+        vm.overflow = OverflowMode::Saturating;
+        vm.word_size = WordSize::W64;
+        vm.fuel = Some(1000);
+        vm
+    }
+
+    #[test]
+    fn resumes() {
+        let program = parse_source(PROGRAM, ParseOptions::default()).unwrap();
+        let snapshots = Rc::new(RefCell::new(vec![]));
+        let mut expected = new_vm();
+        let (taken, saved) = (snapshots.clone(), program.clone());
+        expected.on_snapshot(move |vm| taken.borrow_mut().push(save(vm, &saved)));
+        expected.run(&program).unwrap();
+        assert_eq!(expected.exit_code, 15);
+        assert_eq!(expected.written, 5);
+        let snapshots = snapshots.borrow();
+        assert_eq!(snapshots.len(), 5 + 1 + 5);
+
+        for snapshot in snapshots.iter() {
+            let (mut vm, restored) = load(snapshot).unwrap();
+            assert_eq!(restored, program);
+            vm.output = Box::new(std::io::sink());
+            assert_eq!(save(&vm, &restored), *snapshot);
+            vm.run(&restored).unwrap();
+            assert_same(&vm, &expected);
+        }
+    }
+
+    #[test]
+    fn at_any_point() {
+        let program = parse_source(PROGRAM, ParseOptions::default()).unwrap();
+        let mut expected = new_vm();
+        expected.run(&program).unwrap();
+
+        let mut vm = new_vm();
+        let mut steps = 0;
+        while vm.running {
+            let (mut resumed, _) = load(&save(&vm, &program)).unwrap();
+            resumed.output = Box::new(std::io::sink());
+            resumed.run(&program).unwrap();
+            assert_same(&resumed, &expected);
+            vm.step(&program).unwrap();
+            steps += 1;
+        }
+        assert_same(&vm, &expected);
+        assert!(steps > 50);
+    }
+
+    #[test]
+    fn on_error() {
+        let program = [Psh(1), Set(A, 0), Div(A, A), Hlt];
+        let mut vm = Vm::new();
+        assert_eq!(vm.run(&program), Err(VmError::DivisionByZero(2)));
+        let (mut resumed, _) = load(&save(&vm, &program)).unwrap();
+        assert_eq!(resumed.regs[Ip as usize], 2);
+        assert_eq!(resumed.live_stack(), &[1]);
+        assert_eq!(resumed.run(&program), Err(VmError::DivisionByZero(2)));
+    }
+
+    #[test]
+    fn invalid() {
+        let program = [Psh(1), Hlt];
+        let mut vm = Vm::new();
+        vm.heap.alloc(3).unwrap();
+        let bytes = save(&vm, &program);
+
+        assert_eq!(load(b"WLVM").err(), Some(SnapshotError::BadMagic));
+        for len in 0..bytes.len() {
+            assert!(load(&bytes[..len]).is_err());
+        }
+        let mut version = bytes.clone();
+        version[4] = 9;
+        assert_eq!(
+            load(&version).err(),
+            Some(SnapshotError::UnsupportedVersion(9))
+        );
+
+        // The allocated block, at 1 for 3 words, moved past the end of the memory
+        let mut heap = bytes.clone();
+        let block = heap.len() - 8 - 16;
+        heap[block..block + 8].copy_from_slice(&(HEAP_SIZE as u64).to_le_bytes());
+        assert_eq!(load(&heap).err(), Some(SnapshotError::Invalid("heap")));
+    }
+}
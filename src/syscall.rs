@@ -0,0 +1,102 @@
+use crate::heap::HeapError;
+use crate::{Registers::*, Vm, VmError};
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A host function called by `sys <n>`. Arguments and results are passed in the registers.
+pub type Syscall = Rc<dyn Fn(&mut Vm) -> Result<(), VmError>>;
+
+pub const SYS_EXIT: u32 = 0;
+pub const SYS_WRITE: u32 = 1;
+pub const SYS_READ: u32 = 2;
+pub const SYS_TIME: u32 = 3;
+
+/// Registers the standard table on the VM.
+pub fn register_standard(vm: &mut Vm) {
+    vm.register_syscall(SYS_EXIT, exit);
+    vm.register_syscall(SYS_WRITE, write);
+    vm.register_syscall(SYS_READ, read);
+    vm.register_syscall(SYS_TIME, time);
+}
+
+// exit : stops the program with the exit code in a
+fn exit(vm: &mut Vm) -> Result<(), VmError> {
+    vm.exit_code = vm.regs[A as usize];
+    vm.running = false;
+    vm.report_leaks();
+    Ok(())
+}
+
+// Addresses of the `len` words starting at `addr`, checked against the heap
+fn buffer(vm: &Vm, addr: i64, len: i64) -> Result<std::ops::Range<i64>, VmError> {
+    let ip = vm.regs[Ip as usize];
+    let end = addr
+        .checked_add(len.max(0))
+        .ok_or(VmError::Memory(HeapError::OutOfBounds, ip))?;
+    for word in addr..end {
+        vm.heap.load(word).map_err(|e| VmError::Memory(e, ip))?;
+    }
+    Ok(addr..end)
+}
+
+// write : writes the low byte of the c words at address b to the file descriptor a (1 is
+// the output of the VM, 2 is stderr). a is set to the number of bytes written, or -1 on failure.
+fn write(vm: &mut Vm) -> Result<(), VmError> {
+    let (fd, addr, len) = (
+        vm.regs[A as usize],
+        vm.regs[B as usize],
+        vm.regs[C as usize],
+    );
+    let bytes = buffer(vm, addr, len)?
+        .map(|word| vm.heap.load(word).unwrap() as u8)
+        .collect::<Vec<u8>>();
+
+    let written = match fd {
+        1 => vm.print(&bytes).and_then(|_| vm.output.flush()),
+        2 => io::stderr().write_all(&bytes),
+        _ => Err(io::ErrorKind::InvalidInput.into()),
+    };
+    vm.regs[A as usize] = match written {
+        Ok(_) => bytes.len() as i64,
+        Err(_) => -1,
+    };
+    Ok(())
+}
+
+// read : reads up to c bytes from the file descriptor a (0 is stdin) into the words at
+// address b. a is set to the number of bytes read, 0 at the end of the input, or -1 on failure.
+fn read(vm: &mut Vm) -> Result<(), VmError> {
+    let (fd, addr, len) = (
+        vm.regs[A as usize],
+        vm.regs[B as usize],
+        vm.regs[C as usize],
+    );
+    let words = buffer(vm, addr, len)?;
+
+    let mut bytes = vec![0; (words.end - words.start) as usize];
+    let read = match fd {
+        0 => io::stdin().read(&mut bytes),
+        _ => Err(io::ErrorKind::InvalidInput.into()),
+    };
+    vm.regs[A as usize] = match read {
+        Ok(n) => {
+            for (word, &byte) in words.zip(&bytes[..n]) {
+                vm.heap.store(word, byte as i64).unwrap();
+            }
+            n as i64
+        }
+        Err(_) => -1,
+    };
+    Ok(())
+}
+
+// time : sets a to the seconds elapsed since the unix epoch, and b to the nanoseconds
+fn time(vm: &mut Vm) -> Result<(), VmError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    vm.regs[A as usize] = now.as_secs() as i64;
+    vm.regs[B as usize] = now.subsec_nanos() as i64;
+    Ok(())
+}
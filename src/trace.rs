@@ -0,0 +1,246 @@
+//! Traces of the execution, one JSON record per line for each instruction run :
+//!
+//! ```text
+//! {"step":3,"ip":3,"instr":"add a b","regs":{"a":5,"of":0},"pop":[],"push":[],"output":""}
+//! ```
+//!
+//! `regs` holds the registers the instruction changed, except ip which is the `ip` of the next
+//! record, `pop` the values removed from the stack, topmost first, and `push` the values added
+//! to it, in the order they were pushed. `output` is what the program printed. The record of
+//! the instruction that failed ends with an `error` field.
+
+use crate::{FloatRegisters, Instructions, Registers, Registers::*, Vm, VmError};
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+// The output of the VM while tracing, recording what goes through it
+struct Tee(Rc<RefCell<Recorder>>);
+
+struct Recorder {
+    inner: Box<dyn Write>,
+    bytes: Vec<u8>,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut recorder = self.0.borrow_mut();
+        let written = recorder.inner.write(buf)?;
+        recorder.bytes.extend_from_slice(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().inner.flush()
+    }
+}
+
+impl Vm {
+    /// Same as `run`, writing the record of each step to `out`. Fails if `out` does, which
+    /// stops the program.
+    pub fn run_traced<W: Write>(
+        &mut self,
+        program: &[Instructions],
+        out: &mut W,
+    ) -> io::Result<Result<(), VmError>> {
+        let recorder = Rc::new(RefCell::new(Recorder {
+            inner: std::mem::replace(&mut self.output, Box::new(io::sink())),
+            bytes: vec![],
+        }));
+        self.output = Box::new(Tee(recorder.clone()));
+
+        let result = self.trace(program, out, &recorder);
+
+        self.output = Box::new(io::sink());
+        let recorder = Rc::try_unwrap(recorder).ok().unwrap().into_inner();
+        self.output = recorder.inner;
+        let result = result?;
+        out.flush()?;
+        Ok(result)
+    }
+
+    fn trace<W: Write>(
+        &mut self,
+        program: &[Instructions],
+        out: &mut W,
+        recorder: &RefCell<Recorder>,
+    ) -> io::Result<Result<(), VmError>> {
+        let mut step = 0u64;
+        while self.running {
+            let ip = self.regs[Ip as usize];
+            let (regs, fregs) = (self.regs, self.fregs);
+            let stack = self.live_stack().to_vec();
+
+            let result = self.step(program);
+
+            let mut record = format!("{{\"step\":{},\"ip\":{},\"instr\":", step, ip);
+            match program.get(ip as usize).filter(|_| ip >= 0) {
+                Some(instr) => record += &string(&instr.to_string()),
+                None => record += "null",
+            }
+            let mut changed = vec![];
+            for (i, (old, new)) in regs.iter().zip(self.regs.iter()).enumerate() {
+                if old != new && i != Ip as usize {
+                    let name = Registers::from_index(i).unwrap().to_string();
+                    changed.push(format!("{}:{}", string(&name), new));
+                }
+            }
+            for (i, (old, new)) in fregs.iter().zip(self.fregs.iter()).enumerate() {
+                if old.to_bits() != new.to_bits() {
+                    let name = FloatRegisters::from_index(i).unwrap().to_string();
+                    changed.push(format!("{}:{}", string(&name), float(*new)));
+                }
+            }
+            let _ = write!(record, ",\"regs\":{{{}}}", changed.join(","));
+
+            let live = self.live_stack();
+            let kept = stack
+                .iter()
+                .zip(live.iter())
+                .take_while(|(old, new)| old == new)
+                .count();
+            let popped = stack[kept..].iter().rev();
+            let _ = write!(
+                record,
+                ",\"pop\":[{}],\"push\":[{}]",
+                list(popped),
+                list(live[kept..].iter())
+            );
+
+            let bytes = std::mem::take(&mut recorder.borrow_mut().bytes);
+            record += ",\"output\":";
+            record += &string(&String::from_utf8_lossy(&bytes));
+            if let Err(e) = &result {
+                record += ",\"error\":";
+                record += &string(&e.to_string());
+            }
+            record += "}\n";
+            out.write_all(record.as_bytes())?;
+
+            if result.is_err() {
+                return Ok(result);
+            }
+            step += 1;
+        }
+        Ok(Ok(()))
+    }
+}
+
+// JSON string
+fn string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            '\r' => escaped += "\\r",
+            '\t' => escaped += "\\t",
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn list<'a>(values: impl Iterator<Item = &'a i64>) -> String {
+    values.map(i64::to_string).collect::<Vec<_>>().join(",")
+}
+
+// JSON number, or string for the values JSON has no number for
+fn float(f: f64) -> String {
+    match f.is_finite() {
+        true => format!("{:?}", f),
+        false => string(&f.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::{parse_source, ParseOptions};
+    use crate::WordSize;
+
+    fn parse(source: &str) -> Vec<Instructions> {
+        parse_source(source, ParseOptions::default()).unwrap()
+    }
+
+    fn trace(source: &str, vm: &mut Vm) -> (Vec<String>, Result<(), VmError>) {
+        let program = parse(source);
+        let mut out = vec![];
+        let result = vm.run_traced(&program, &mut out).unwrap();
+        let lines = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        (lines, result)
+    }
+
+    #[test]
+    fn records() {
+        let mut vm = Vm::new();
+        vm.output = Box::new(io::sink());
+        let (lines, result) = trace(
+            "psh 2\npsh 3\nadd\nset a 65\nprt a\nfld fa 1.5\nswp\nhlt",
+            &mut vm,
+        );
+        assert_eq!(result, Err(VmError::StackUnderflow(6)));
+        assert_eq!(
+            lines,
+            [
+                r#"{"step":0,"ip":0,"instr":"psh 2","regs":{"sp":0,"st":2},"pop":[],"push":[2],"output":""}"#,
+                r#"{"step":1,"ip":1,"instr":"psh 3","regs":{"sp":1,"st":3},"pop":[],"push":[3],"output":""}"#,
+                r#"{"step":2,"ip":2,"instr":"add","regs":{"sp":0,"st":5},"pop":[3,2],"push":[5],"output":""}"#,
+                r#"{"step":3,"ip":3,"instr":"set a 65","regs":{"a":65},"pop":[],"push":[],"output":""}"#,
+                r#"{"step":4,"ip":4,"instr":"prt a","regs":{},"pop":[],"push":[],"output":"A"}"#,
+                r#"{"step":5,"ip":5,"instr":"fld fa 1.5","regs":{"fa":1.5},"pop":[],"push":[],"output":""}"#,
+                r#"{"step":6,"ip":6,"instr":"swp","regs":{},"pop":[],"push":[],"output":"","error":"ERR_STACK_UNDERFLOW at instruction 6"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn output() {
+        let mut vm = Vm::new();
+        vm.output = Box::new(Vec::new());
+        let (lines, _) = trace("set a 34\nprt a\nset a 10\nprt a\ndrg a\nhlt", &mut vm);
+        assert!(lines[1].ends_with(r#""output":"\""}"#));
+        assert!(lines[3].ends_with(r#""output":"\n"}"#));
+        assert!(lines[4].ends_with(r#""output":"[10]\n"}"#));
+        assert_eq!(lines.len(), 6);
+
+        assert_eq!(string("\u{1}é"), "\"\\u0001é\"");
+        assert_eq!(float(2.0), "2.0");
+        assert_eq!(float(f64::NEG_INFINITY), "\"-inf\"");
+    }
+
+    #[test]
+    fn same_as_run() {
+        let program = crate::lang::compile(
+            "let i = 3;\nwhile i > 0 {\n  print i;\n  i = i - 1;\n}",
+            WordSize::W32,
+        )
+        .unwrap();
+        let mut expected = Vm::new();
+        expected.output = Box::new(io::sink());
+        let mut steps = 0;
+        while expected.running {
+            expected.step(&program).unwrap();
+            steps += 1;
+        }
+        let mut vm = Vm::new();
+        vm.output = Box::new(io::sink());
+        let mut out = vec![];
+        assert_eq!(vm.run_traced(&program, &mut out).unwrap(), Ok(()));
+        assert_eq!(vm.regs, expected.regs);
+        let lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        assert_eq!(lines.len(), steps);
+        assert!(lines[steps - 1].starts_with(&format!("{{\"step\":{},", steps - 1)));
+        assert!(lines[steps - 1].contains(r#""instr":"hlt""#));
+    }
+}
@@ -0,0 +1,1026 @@
+use crate::analysis::has_computed_jumps;
+use crate::{
+    FloatRegisters, Instructions, Instructions::*, OverflowMode, Registers, Registers::*, Vm,
+    WordSize,
+};
+use std::fmt::Write;
+
+// Messages of the errors raised by the generated code, in the order of the data segment
+const ERRORS: &[&str] = &[
+    "ERR_ARITHMETIC_OVERFLOW",
+    "ERR_DIVISION_BY_ZERO",
+    "ERR_STACK_OVERFLOW",
+    "ERR_STACK_UNDERFLOW",
+    "ERR_READ_ONLY_REGISTER",
+    "ERR_INVALID_STACK_POINTER",
+    "ERR_INVALID_JUMP",
+    "ERR_OUT_OF_FUEL",
+    "ERR_INVALID_ALLOCATION_SIZE",
+    "ERR_OUT_OF_MEMORY",
+    "ERR_DOUBLE_FREE",
+    "ERR_INVALID_FREE",
+    "ERR_USE_AFTER_FREE",
+    "ERR_OUT_OF_BOUNDS",
+];
+
+// Everything the generated code calls, mirroring the VM. Operations are numbered as in `$wrapping`.
+const RUNTIME: &str = r#"
+  (func $address (param $array i32) (param $index i64) (result i32)
+    (i32.add (local.get $array) (i32.shl (i32.wrap_i64 (local.get $index)) (i32.const 3))))
+
+  ;; add, sub, mul, div on 64 bits, the division of i64::MIN by -1 giving i64::MIN
+  (func $wrapping (param $op i32) (param $lhs i64) (param $rhs i64) (result i64)
+    (block $div
+      (block $mul
+        (block $sub
+          (block $add
+            (br_table $add $sub $mul $div (local.get $op)))
+          (return (i64.add (local.get $lhs) (local.get $rhs))))
+        (return (i64.sub (local.get $lhs) (local.get $rhs))))
+      (return (i64.mul (local.get $lhs) (local.get $rhs))))
+    (if (result i64)
+      (i32.and
+        (i64.eq (local.get $lhs) (i64.const -9223372036854775808))
+        (i64.eq (local.get $rhs) (i64.const -1)))
+      (then (i64.const -9223372036854775808))
+      (else (i64.div_s (local.get $lhs) (local.get $rhs)))))
+
+  ;; Whether the 64 bit operation overflowed, giving the wrapped result
+  (func $overflows (param $op i32) (param $lhs i64) (param $rhs i64) (param $result i64) (result i32)
+    (block $div
+      (block $mul
+        (block $sub
+          (block $add
+            (br_table $add $sub $mul $div (local.get $op)))
+          ;; The sign of the result differs from the signs of both operands
+          (return
+            (i64.lt_s
+              (i64.and
+                (i64.xor (local.get $lhs) (local.get $result))
+                (i64.xor (local.get $rhs) (local.get $result)))
+              (i64.const 0))))
+        ;; The operands have different signs, and the result the sign of the right one
+        (return
+          (i64.lt_s
+            (i64.and
+              (i64.xor (local.get $lhs) (local.get $rhs))
+              (i64.xor (local.get $lhs) (local.get $result)))
+            (i64.const 0))))
+      ;; Dividing the result by the left operand doesn't give the right one back
+      (if (i64.eqz (local.get $lhs))
+        (then (return (i32.const 0))))
+      (if (i64.eq (local.get $lhs) (i64.const -1))
+        (then (return (i64.eq (local.get $rhs) (i64.const -9223372036854775808)))))
+      (return (i64.ne (i64.div_s (local.get $result) (local.get $lhs)) (local.get $rhs))))
+    (i32.and
+      (i64.eq (local.get $lhs) (i64.const -9223372036854775808))
+      (i64.eq (local.get $rhs) (i64.const -1))))
+
+  ;; Bound reached by the overflowing 64 bit operation
+  (func $bound (param $op i32) (param $lhs i64) (param $rhs i64) (result i64)
+    (local $positive i32)
+    (block $div
+      (block $mul
+        (block $sub
+          (block $add
+            (br_table $add $sub $mul $div (local.get $op)))
+          (local.set $positive (i64.gt_s (local.get $rhs) (i64.const 0)))
+          (br $div))
+        (local.set $positive (i64.lt_s (local.get $rhs) (i64.const 0)))
+        (br $div))
+      (local.set $positive
+        (i32.eq
+          (i64.lt_s (local.get $lhs) (i64.const 0))
+          (i64.lt_s (local.get $rhs) (i64.const 0)))))
+    (if (i32.eq (local.get $op) (i32.const 3))
+      (then (local.set $positive (i32.const 1))))
+    (select
+      (i64.const 9223372036854775807)
+      (i64.const -9223372036854775808)
+      (local.get $positive)))
+
+  (func $arithmetic (param $op i32) (param $lhs i64) (param $rhs i64) (param $ip i64) (result i64)
+    (local $wrapped i64) (local $saturated i64) (local $overflowed i32)
+    (local.set $lhs (call $narrow (local.get $lhs)))
+    (local.set $rhs (call $narrow (local.get $rhs)))
+    (local.set $wrapped (call $wrapping (local.get $op) (local.get $lhs) (local.get $rhs)))
+    (if (global.get $w32)
+      (then
+        ;; The exact result of two 32 bit operands always fits in 64 bits
+        (local.set $saturated
+          (select
+            (i64.const -2147483648)
+            (select
+              (i64.const 2147483647)
+              (local.get $wrapped)
+              (i64.gt_s (local.get $wrapped) (i64.const 2147483647)))
+            (i64.lt_s (local.get $wrapped) (i64.const -2147483648))))
+        (local.set $overflowed (i64.ne (local.get $wrapped) (local.get $saturated)))
+        (local.set $wrapped (call $narrow (local.get $wrapped))))
+      (else
+        (local.set $overflowed
+          (call $overflows (local.get $op) (local.get $lhs) (local.get $rhs) (local.get $wrapped)))
+        (local.set $saturated
+          (select
+            (call $bound (local.get $op) (local.get $lhs) (local.get $rhs))
+            (local.get $wrapped)
+            (local.get $overflowed)))))
+    (if (i32.and (local.get $overflowed) (i32.eq (global.get $overflow) (i32.const 2)))
+      (then (call $ERR_ARITHMETIC_OVERFLOW (local.get $ip))))
+    (global.set $of (i64.extend_i32_u (local.get $overflowed)))
+    (select
+      (local.get $saturated)
+      (local.get $wrapped)
+      (i32.eq (global.get $overflow) (i32.const 1))))
+
+  ;; Value of st for the current sp
+  (func $top (result i64)
+    (if (result i64) (i64.ge_s (global.get $sp) (i64.const 0))
+      (then (i64.load (call $address (global.get $stack) (global.get $sp))))
+      (else (i64.const 0))))
+
+  (func $push (param $value i64) (param $ip i64)
+    (if (i64.ge_s (i64.add (global.get $sp) (i64.const 1)) (global.get $stack_size))
+      (then (call $ERR_STACK_OVERFLOW (local.get $ip))))
+    (global.set $sp (i64.add (global.get $sp) (i64.const 1)))
+    (i64.store (call $address (global.get $stack) (global.get $sp)) (local.get $value))
+    (global.set $st (local.get $value)))
+
+  (func $peek (param $depth i64) (param $ip i64) (result i64)
+    (if (i64.lt_s (i64.sub (global.get $sp) (local.get $depth)) (i64.const 0))
+      (then (call $ERR_STACK_UNDERFLOW (local.get $ip))))
+    (i64.load (call $address (global.get $stack) (i64.sub (global.get $sp) (local.get $depth)))))
+
+  (func $pop (param $ip i64) (result i64)
+    (local $popped i64)
+    (local.set $popped (call $peek (i64.const 0) (local.get $ip)))
+    (global.set $sp (i64.sub (global.get $sp) (i64.const 1)))
+    (global.set $st (call $top))
+    (local.get $popped))
+
+  (func $set_sp (param $value i64) (param $ip i64)
+    (if (i32.or
+          (i64.lt_s (local.get $value) (i64.const -1))
+          (i64.ge_s (local.get $value) (global.get $stack_size)))
+      (then (call $ERR_INVALID_STACK_POINTER (local.get $ip))))
+    (global.set $sp (local.get $value))
+    (global.set $st (call $top)))
+
+  (func $stack_arithmetic (param $op i32) (param $ip i64)
+    (local $rhs i64) (local $lhs i64) (local $result i64)
+    (local.set $rhs (call $peek (i64.const 0) (local.get $ip)))
+    (local.set $lhs (call $peek (i64.const 1) (local.get $ip)))
+    (local.set $result
+      (call $arithmetic (local.get $op) (local.get $lhs) (local.get $rhs) (local.get $ip)))
+    (drop (call $pop (local.get $ip)))
+    (drop (call $pop (local.get $ip)))
+    (call $push (local.get $result) (local.get $ip)))
+
+  (func $swp (param $ip i64)
+    (local $first i64)
+    (local.set $first (call $peek (i64.const 0) (local.get $ip)))
+    (i64.store
+      (call $address (global.get $stack) (global.get $sp))
+      (call $peek (i64.const 1) (local.get $ip)))
+    (i64.store
+      (call $address (global.get $stack) (i64.sub (global.get $sp) (i64.const 1)))
+      (local.get $first))
+    (global.set $st (call $top)))
+
+  (func $rot (param $ip i64)
+    (local $third i64)
+    (local.set $third (call $peek (i64.const 2) (local.get $ip)))
+    (i64.store
+      (call $address (global.get $stack) (i64.sub (global.get $sp) (i64.const 2)))
+      (call $peek (i64.const 1) (local.get $ip)))
+    (i64.store
+      (call $address (global.get $stack) (i64.sub (global.get $sp) (i64.const 1)))
+      (call $peek (i64.const 0) (local.get $ip)))
+    (i64.store (call $address (global.get $stack) (global.get $sp)) (local.get $third))
+    (global.set $st (call $top)))
+
+  (func $dst
+    (local $i i64)
+    (block $done
+      (loop $next
+        (br_if $done (i64.gt_s (local.get $i) (global.get $sp)))
+        (call $drg (i64.load (call $address (global.get $stack) (local.get $i))))
+        (local.set $i (i64.add (local.get $i) (i64.const 1)))
+        (br $next))))
+
+  (func $print_char (param $value i64)
+    (if (i64.lt_u (local.get $value) (i64.const 256))
+      (then (call $prt (local.get $value)))))
+
+  ;; First fit, freed blocks overlapping the new one are forgotten
+  (func $alloc (param $size i64) (param $ip i64) (result i64)
+    (local $start i64) (local $end i64) (local $word i64) (local $block i64) (local $w i64)
+    (if (i64.le_s (local.get $size) (i64.const 0))
+      (then (call $ERR_INVALID_ALLOCATION_SIZE (local.get $ip))))
+    (local.set $start (i64.const 1))
+    (block $found
+      (loop $search
+        (if (i64.gt_s (local.get $size) (i64.sub (global.get $heap_size) (local.get $start)))
+          (then (call $ERR_OUT_OF_MEMORY (local.get $ip))))
+        (local.set $end (i64.add (local.get $start) (local.get $size)))
+        (local.set $word (local.get $start))
+        (block $taken
+          (loop $scan
+            (br_if $taken (i64.ne (i64.load (call $address (global.get $live) (local.get $word))) (i64.const 0)))
+            (local.set $word (i64.add (local.get $word) (i64.const 1)))
+            (br_if $scan (i64.lt_s (local.get $word) (local.get $end))))
+          (br $found))
+        (local.set $start
+          (i64.add
+            (local.get $word)
+            (i64.load (call $address (global.get $sizes) (local.get $word)))))
+        (br $search)))
+    (local.set $word (local.get $start))
+    (loop $claim
+      (local.set $block (i64.load (call $address (global.get $freed) (local.get $word))))
+      (if (i64.ne (local.get $block) (i64.const 0))
+        (then
+          (local.set $w (local.get $block))
+          (loop $forget
+            (i64.store (call $address (global.get $freed) (local.get $w)) (i64.const 0))
+            (local.set $w (i64.add (local.get $w) (i64.const 1)))
+            (br_if $forget
+              (i64.lt_s
+                (local.get $w)
+                (i64.add
+                  (local.get $block)
+                  (i64.load (call $address (global.get $sizes) (local.get $block)))))))))
+      (i64.store (call $address (global.get $words) (local.get $word)) (i64.const 0))
+      (i64.store (call $address (global.get $live) (local.get $word)) (local.get $start))
+      (local.set $word (i64.add (local.get $word) (i64.const 1)))
+      (br_if $claim (i64.lt_s (local.get $word) (local.get $end))))
+    (i64.store (call $address (global.get $sizes) (local.get $start)) (local.get $size))
+    (local.get $start))
+
+  (func $free (param $ptr i64) (param $ip i64)
+    (local $word i64) (local $end i64)
+    (if (i32.or
+          (i64.lt_s (local.get $ptr) (i64.const 1))
+          (i64.ge_s (local.get $ptr) (global.get $heap_size)))
+      (then (call $ERR_INVALID_FREE (local.get $ip))))
+    (if (i64.eq (i64.load (call $address (global.get $live) (local.get $ptr))) (local.get $ptr))
+      (then
+        (local.set $word (local.get $ptr))
+        (local.set $end
+          (i64.add (local.get $ptr) (i64.load (call $address (global.get $sizes) (local.get $ptr)))))
+        (loop $release
+          (i64.store (call $address (global.get $live) (local.get $word)) (i64.const 0))
+          (i64.store (call $address (global.get $freed) (local.get $word)) (local.get $ptr))
+          (local.set $word (i64.add (local.get $word) (i64.const 1)))
+          (br_if $release (i64.lt_s (local.get $word) (local.get $end))))
+        (return)))
+    (if (i64.eq (i64.load (call $address (global.get $freed) (local.get $ptr))) (local.get $ptr))
+      (then (call $ERR_DOUBLE_FREE (local.get $ip))))
+    (call $ERR_INVALID_FREE (local.get $ip)))
+
+  ;; Byte address of the heap word, which has to be allocated
+  (func $check (param $addr i64) (param $ip i64) (result i32)
+    (if (i32.and
+          (i64.ge_s (local.get $addr) (i64.const 0))
+          (i64.lt_s (local.get $addr) (global.get $heap_size)))
+      (then
+        (if (i64.ne (i64.load (call $address (global.get $live) (local.get $addr))) (i64.const 0))
+          (then (return (call $address (global.get $words) (local.get $addr)))))
+        (if (i64.ne (i64.load (call $address (global.get $freed) (local.get $addr))) (i64.const 0))
+          (then (call $ERR_USE_AFTER_FREE (local.get $ip))))))
+    (call $ERR_OUT_OF_BOUNDS (local.get $ip))
+    (unreachable))
+"#;
+
+/// WebAssembly text of a module behaving like `program` run on `vm`, with the same word size,
+/// overflow mode, stack and heap sizes and fuel. Its `run` function returns the exit code.
+///
+/// The registers are exported as mutable globals named after them (`a`, `sp`, `fa`...), and
+/// the stack and heap as words of the exported `memory`, starting at the byte offsets in the
+/// `stack` and `heap` globals. Jumps go back to a dispatching loop, or break out of the blocks
+/// of later instructions when going forward.
+///
+/// The module imports from `env` :
+/// - `prt (i64)`, `drg (i64)` and `fpr (f64)`, called with the printed value
+/// - `dmp (i64)` and `sys (i64 i64)`, called with the instruction (and the syscall number),
+///   reading the state through the exports. Only the exit syscall is part of the module.
+/// - `error (i32 i32 i64)`, called with the address and length of the error message in memory
+///   and the instruction, before trapping
+///
+/// Leaks are not reported.
+pub fn compile(program: &[Instructions], vm: &Vm) -> String {
+    let len = program.len();
+    let computed_jumps = has_computed_jumps(program);
+    // Blocks for the instructions jumped to, the first one and the end of the program
+    let mut targets = vec![computed_jumps; len + 1];
+    targets[0] = true;
+    targets[len] = true;
+    for instr in program {
+        if let Jmp(target) = *instr {
+            if target >= 0 && target as usize <= len {
+                targets[target as usize] = true;
+            }
+        }
+    }
+
+    // Memory : error messages, then the stack and the heap with its bookkeeping
+    let mut data = String::new();
+    let mut messages = vec![];
+    for error in ERRORS {
+        messages.push((data.len(), error.len()));
+        data += error;
+    }
+    let stack = data.len().div_ceil(8) * 8;
+    let (stack_size, heap_size) = (vm.stack.len(), vm.heap.memory.len());
+    let heap = stack + 8 * stack_size;
+    let arrays = [
+        heap,
+        heap + 8 * heap_size,
+        heap + 16 * heap_size,
+        heap + 24 * heap_size,
+    ];
+    let pages = (heap + 32 * heap_size).div_ceil(0x10000);
+
+    let mut wat = String::from(";; Generated by wlvm\n(module\n");
+    for (name, params) in &[
+        ("error", "i32 i32 i64"),
+        ("prt", "i64"),
+        ("drg", "i64"),
+        ("fpr", "f64"),
+        ("dmp", "i64"),
+        ("sys", "i64 i64"),
+    ] {
+        writeln!(
+            wat,
+            "  (import \"env\" \"{0}\" (func ${0} (param {1})))",
+            name, params
+        )
+        .unwrap();
+    }
+    writeln!(wat, "  (memory (export \"memory\") {})", pages.max(1)).unwrap();
+    writeln!(wat, "  (data (i32.const 0) \"{}\")", data).unwrap();
+
+    for reg in &[A, B, C, D, E, F, Sp, St, Eq, Of] {
+        let value = if *reg == Sp { -1 } else { 0 };
+        writeln!(
+            wat,
+            "  (global ${0} (export \"{0}\") (mut i64) (i64.const {1}))",
+            reg, value
+        )
+        .unwrap();
+    }
+    for f in &["fa", "fb", "fc", "fd", "fe", "ff"] {
+        writeln!(
+            wat,
+            "  (global ${0} (export \"{0}\") (mut f64) (f64.const 0))",
+            f
+        )
+        .unwrap();
+    }
+    if let Some(fuel) = vm.fuel {
+        writeln!(
+            wat,
+            "  (global $fuel (mut i64) (i64.const {}))",
+            fuel as i64
+        )
+        .unwrap();
+    }
+    writeln!(
+        wat,
+        "  (global $stack (export \"stack\") i32 (i32.const {}))",
+        stack
+    )
+    .unwrap();
+    writeln!(
+        wat,
+        "  (global $heap (export \"heap\") i32 (i32.const {}))",
+        heap
+    )
+    .unwrap();
+    for (name, offset) in ["words", "live", "freed", "sizes"].iter().zip(&arrays) {
+        writeln!(wat, "  (global ${} i32 (i32.const {}))", name, offset).unwrap();
+    }
+    writeln!(wat, "  (global $stack_size i64 (i64.const {}))", stack_size).unwrap();
+    writeln!(wat, "  (global $heap_size i64 (i64.const {}))", heap_size).unwrap();
+    let w32 = vm.word_size == WordSize::W32;
+    writeln!(wat, "  (global $w32 i32 (i32.const {}))", w32 as u8).unwrap();
+    let overflow = match vm.overflow {
+        OverflowMode::Wrapping => 0,
+        OverflowMode::Saturating => 1,
+        OverflowMode::Trapping => 2,
+    };
+    writeln!(wat, "  (global $overflow i32 (i32.const {}))", overflow).unwrap();
+
+    for (error, (offset, len)) in ERRORS.iter().zip(&messages) {
+        writeln!(
+            wat,
+            "  (func ${} (param $ip i64)\n    \
+             (call $error (i32.const {}) (i32.const {}) (local.get $ip))\n    (unreachable))",
+            error, offset, len
+        )
+        .unwrap();
+    }
+    let narrow = if w32 {
+        "(i64.extend32_s (local.get 0))"
+    } else {
+        "(local.get 0)"
+    };
+    writeln!(
+        wat,
+        "  (func $narrow (param i64) (result i64)\n    {})",
+        narrow
+    )
+    .unwrap();
+    wat += RUNTIME;
+
+    wat += "\n  (func (export \"run\") (result i64)\n    (local $target i64)\n";
+    wat += "    (loop $dispatch\n";
+    // Instruction n follows the end of its block, nested in the blocks of later instructions
+    let blocks: Vec<usize> = (0..=len).filter(|ip| targets[*ip]).collect();
+    for (depth, ip) in blocks.iter().rev().enumerate() {
+        writeln!(wat, "{}(block $L{}", "  ".repeat(depth + 3), ip).unwrap();
+    }
+    let mut open = blocks.len();
+    let indent = "  ".repeat(open + 3);
+    let table: Vec<String> = (0..=len)
+        .map(|ip| match targets[ip] {
+            true => format!("$L{}", ip),
+            false => String::from("$invalid"),
+        })
+        .collect();
+    writeln!(wat, "{}(block $invalid", indent).unwrap();
+    writeln!(wat, "{}  (br_table {} $invalid", indent, table.join(" ")).unwrap();
+    writeln!(
+        wat,
+        "{}    (i32.wrap_i64 (select (local.get $target) (i64.const {}) \
+         (i64.le_u (local.get $target) (i64.const {}))))))",
+        indent,
+        len + 1,
+        len
+    )
+    .unwrap();
+    writeln!(
+        wat,
+        "{}(call $ERR_INVALID_JUMP (local.get $target)))",
+        indent
+    )
+    .unwrap();
+    open -= 1;
+
+    for (ip, instr) in program.iter().enumerate() {
+        let indent = "  ".repeat(open + 3);
+        writeln!(wat, "{};; {}", indent, instr).unwrap();
+        if vm.fuel.is_some() {
+            writeln!(
+                wat,
+                "{}(if (i64.eqz (global.get $fuel)) (then (call $ERR_OUT_OF_FUEL (i64.const {}))))",
+                indent, ip
+            )
+            .unwrap();
+            writeln!(
+                wat,
+                "{}(global.set $fuel (i64.sub (global.get $fuel) (i64.const 1)))",
+                indent
+            )
+            .unwrap();
+        }
+        for line in instruction(*instr, ip as i64, len, vm.word_size).lines() {
+            writeln!(wat, "{}{}", indent, line).unwrap();
+        }
+        if targets[ip + 1] {
+            wat.pop();
+            wat += ")\n";
+            open -= 1;
+        }
+    }
+    // Running past the last instruction
+    writeln!(
+        wat,
+        "      (call $ERR_INVALID_JUMP (i64.const {})))\n    (unreachable)))",
+        len
+    )
+    .unwrap();
+    wat
+}
+
+fn narrow(value: i64, word_size: WordSize) -> i64 {
+    match word_size {
+        WordSize::W32 => value as i32 as i64,
+        WordSize::W64 => value,
+    }
+}
+
+// Expression reading the register, ip being the current instruction
+fn read(reg: Registers, ip: i64) -> String {
+    match reg {
+        Ip => format!("(i64.const {})", ip),
+        reg => format!("(global.get ${})", reg),
+    }
+}
+
+// Instructions writing the value to the register, as `Vm::write_register`
+fn write(reg: Registers, value: &str, ip: i64) -> String {
+    match reg {
+        St => format!(
+            "(drop {})\n(call $ERR_READ_ONLY_REGISTER (i64.const {}))",
+            value, ip
+        ),
+        Sp => format!("(call $set_sp {} (i64.const {}))", value, ip),
+        Ip => format!(
+            "(local.set $target {})\n(if (i64.lt_s (local.get $target) (i64.const 0))\n  \
+             (then (call $ERR_INVALID_JUMP (i64.const {}))))\n(br $dispatch)",
+            value, ip
+        ),
+        reg => format!("(global.set ${} {})", reg, value),
+    }
+}
+
+// Instructions running the instruction
+fn instruction(instr: Instructions, ip: i64, len: usize, word_size: WordSize) -> String {
+    let r = |reg: Registers| read(reg, ip);
+    let fail = |error: &str| format!("(call ${} (i64.const {}))", error, ip);
+    let arithmetic = |op: u8, a: Registers, b: Registers| match a {
+        St => fail("ERR_READ_ONLY_REGISTER"),
+        a => write(
+            a,
+            &format!(
+                "(call $arithmetic (i32.const {}) {} {} (i64.const {}))",
+                op,
+                r(a),
+                r(b),
+                ip
+            ),
+            ip,
+        ),
+    };
+    let test = |op: &str, a: String, b: String| {
+        format!("(global.set $eq (i64.extend_i32_u ({} {} {})))", op, a, b)
+    };
+    let f = |reg: FloatRegisters| format!("(global.get ${})", reg);
+    let float = |op: &str, a: FloatRegisters, b: FloatRegisters| {
+        format!("(global.set ${} ({} {} {}))", a, op, f(a), f(b))
+    };
+    let stack_arithmetic = |op: u8| {
+        format!(
+            "(call $stack_arithmetic (i32.const {}) (i64.const {}))",
+            op, ip
+        )
+    };
+
+    match instr {
+        Psh(i) => format!(
+            "(call $push (i64.const {}) (i64.const {}))",
+            narrow(i, word_size),
+            ip
+        ),
+        PshR(reg) => format!("(call $push {} (i64.const {}))", r(reg), ip),
+        Pop => format!("(drop (call $pop (i64.const {})))", ip),
+        PopR(St) => fail("ERR_READ_ONLY_REGISTER"),
+        PopR(reg) => write(reg, &format!("(call $pop (i64.const {}))", ip), ip),
+        Dup => format!(
+            "(call $push (call $peek (i64.const 0) (i64.const {0})) (i64.const {0}))",
+            ip
+        ),
+        Ovr => format!(
+            "(call $push (call $peek (i64.const 1) (i64.const {0})) (i64.const {0}))",
+            ip
+        ),
+        Swp => format!("(call $swp (i64.const {}))", ip),
+        Rot => format!("(call $rot (i64.const {}))", ip),
+        AddS => stack_arithmetic(0),
+        SubS => stack_arithmetic(1),
+        MulS => stack_arithmetic(2),
+        DivS => format!(
+            "(if (i64.eqz (call $peek (i64.const 0) (i64.const {})))\n  (then {}))\n{}",
+            ip,
+            fail("ERR_DIVISION_BY_ZERO"),
+            stack_arithmetic(3)
+        ),
+        Add(a, b) => arithmetic(0, a, b),
+        Sub(a, b) => arithmetic(1, a, b),
+        Mul(a, b) => arithmetic(2, a, b),
+        Div(a, b) => format!(
+            "(if (i64.eqz {})\n  (then {}))\n{}",
+            r(b),
+            fail("ERR_DIVISION_BY_ZERO"),
+            arithmetic(3, a, b)
+        ),
+        Mov(a, b) => write(a, &r(b), ip),
+        Set(reg, i) => write(reg, &format!("(i64.const {})", narrow(i, word_size)), ip),
+        Hlt => String::from("(return (i64.const 0))"),
+        HltR(reg) => format!("(return {})", r(reg)),
+        Dst => String::from("(call $dst)"),
+        Drg(reg) => format!("(call $drg {})", r(reg)),
+        Dmp => format!("(call $dmp (i64.const {}))", ip),
+        Prt(reg) => format!("(call $print_char {})", r(reg)),
+        Tee(a, b) => test("i64.eq", r(a), r(b)),
+        Tne(a, b) => test("i64.ne", r(a), r(b)),
+        Tll(a, b) => test("i64.lt_s", r(a), r(b)),
+        Tmm(a, b) => test("i64.gt_s", r(a), r(b)),
+        Tel(a, b) => test("i64.le_s", r(a), r(b)),
+        Tem(a, b) => test("i64.ge_s", r(a), r(b)),
+        Jmp(target) => {
+            let jump = if target < 0 {
+                fail("ERR_INVALID_JUMP")
+            } else if target as usize > len {
+                format!("(call $ERR_INVALID_JUMP (i64.const {}))", target)
+            } else if target as i64 > ip {
+                format!("(br $L{})", target)
+            } else {
+                format!("(local.set $target (i64.const {})) (br $dispatch)", target)
+            };
+            format!(
+                "(if (i64.eq (global.get $eq) (i64.const 1))\n  (then {}))",
+                jump
+            )
+        }
+        Fld(f, bits) => format!(
+            "(global.set ${} (f64.reinterpret_i64 (i64.const {})))",
+            f, bits as i64
+        ),
+        Fmv(a, b) => format!("(global.set ${} {})", a, f(b)),
+        Fad(a, b) => float("f64.add", a, b),
+        Fsb(a, b) => float("f64.sub", a, b),
+        Fml(a, b) => float("f64.mul", a, b),
+        Fdv(a, b) => float("f64.div", a, b),
+        Fee(a, b) => test("f64.eq", f(a), f(b)),
+        Fne(a, b) => test("f64.ne", f(a), f(b)),
+        Fll(a, b) => test("f64.lt", f(a), f(b)),
+        Fmm(a, b) => test("f64.gt", f(a), f(b)),
+        Fel(a, b) => test("f64.le", f(a), f(b)),
+        Fem(a, b) => test("f64.ge", f(a), f(b)),
+        Itf(a, reg) => format!("(global.set ${} (f64.convert_i64_s {}))", a, r(reg)),
+        // NaN gives 0, out of range values saturate to the word bounds
+        Fti(reg, a) => {
+            let value = match word_size {
+                WordSize::W32 => format!("(i64.extend_i32_s (i32.trunc_sat_f64_s {}))", f(a)),
+                WordSize::W64 => format!("(i64.trunc_sat_f64_s {})", f(a)),
+            };
+            write(reg, &value, ip)
+        }
+        Fpr(a) => format!("(call $fpr {})", f(a)),
+        Alc(_, St) => fail("ERR_READ_ONLY_REGISTER"),
+        Alc(size, dest) => write(
+            dest,
+            &format!("(call $alloc {} (i64.const {}))", r(size), ip),
+            ip,
+        ),
+        Fre(ptr) => format!("(call $free {} (i64.const {}))", r(ptr), ip),
+        Lod(dest, ptr) => write(
+            dest,
+            &format!("(i64.load (call $check {} (i64.const {})))", r(ptr), ip),
+            ip,
+        ),
+        Sto(ptr, src) => format!(
+            "(i64.store (call $check {} (i64.const {})) {})",
+            r(ptr),
+            ip,
+            r(src)
+        ),
+        Sys(0) => String::from("(return (global.get $a))"),
+        Sys(n) => format!("(call $sys (i64.const {}) (i64.const {}))", n, ip),
+        Snp => String::from("(nop) ;; snp : snapshots are only taken by the VM"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::optimize::test::program;
+    use crate::FloatRegisters::*;
+    use proptest::prelude::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use wasmi::{Caller, Engine, Extern, Linker, Module, Store};
+
+    // What the host functions were called with
+    #[derive(Default)]
+    struct Host {
+        printed: Vec<String>,
+        recorded: Vec<i64>, // Values of a at `sys 100`
+        error: Option<String>,
+    }
+
+    // Recorded values, registers without ip, live stack, exit code and error of a run
+    type Run = (Vec<i64>, Vec<i64>, Vec<i64>, i64, Option<String>);
+
+    fn global(caller: &Caller<Host>, name: &str) -> i64 {
+        let global = caller.get_export(name).and_then(Extern::into_global);
+        global.unwrap().get(caller).i64().unwrap()
+    }
+
+    fn instantiate(program: &[Instructions], vm: &Vm) -> (Store<Host>, wasmi::Instance) {
+        let wasm = ::wat::parse_str(compile(program, vm)).unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm[..]).unwrap();
+        let mut store = Store::new(&engine, Host::default());
+        let mut linker = Linker::<Host>::new(&engine);
+        linker
+            .func_wrap(
+                "env",
+                "error",
+                |mut caller: Caller<Host>, addr: i32, len: i32, ip: i64| {
+                    let memory = caller.get_export("memory").and_then(Extern::into_memory);
+                    let mut message = vec![0; len as usize];
+                    memory
+                        .unwrap()
+                        .read(&caller, addr as usize, &mut message)
+                        .unwrap();
+                    let message = String::from_utf8(message).unwrap();
+                    caller.data_mut().error = Some(format!("{} at instruction {}", message, ip));
+                },
+            )
+            .unwrap();
+        linker
+            .func_wrap("env", "prt", |mut caller: Caller<Host>, value: i64| {
+                let c = value as u8 as char;
+                caller.data_mut().printed.push(c.to_string());
+            })
+            .unwrap();
+        linker
+            .func_wrap("env", "drg", |mut caller: Caller<Host>, value: i64| {
+                caller.data_mut().printed.push(format!("[{}]", value));
+            })
+            .unwrap();
+        linker
+            .func_wrap("env", "fpr", |mut caller: Caller<Host>, value: f64| {
+                caller.data_mut().printed.push(format!("[{}]", value));
+            })
+            .unwrap();
+        linker
+            .func_wrap("env", "dmp", |mut caller: Caller<Host>, ip: i64| {
+                let sp = global(&caller, "sp");
+                caller.data_mut().printed.push(format!("dmp {} {}", ip, sp));
+            })
+            .unwrap();
+        linker
+            .func_wrap(
+                "env",
+                "sys",
+                |mut caller: Caller<Host>, n: i64, ip: i64| -> Result<(), wasmi::Error> {
+                    if n != 100 {
+                        let error = format!("ERR_UNKNOWN_SYSCALL {} at instruction {}", n, ip);
+                        caller.data_mut().error = Some(error.clone());
+                        return Err(wasmi::Error::new(error));
+                    }
+                    let a = global(&caller, "a");
+                    caller.data_mut().recorded.push(a);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+        (store, instance)
+    }
+
+    fn run(program: &[Instructions], vm: &Vm) -> (Run, Vec<String>) {
+        let (mut store, instance) = instantiate(program, vm);
+        let run = instance.get_typed_func::<(), i64>(&store, "run").unwrap();
+        let exit_code = run.call(&mut store, ()).unwrap_or(0);
+
+        let regs: Vec<i64> = ["a", "b", "c", "d", "e", "f", "sp", "st", "eq", "of"]
+            .iter()
+            .map(|name| {
+                instance
+                    .get_global(&store, name)
+                    .unwrap()
+                    .get(&store)
+                    .i64()
+                    .unwrap()
+            })
+            .collect();
+        let stack = instance.get_global(&store, "stack").unwrap().get(&store);
+        let mut bytes = vec![0; 8 * (regs[6] + 1) as usize];
+        let memory = instance.get_memory(&store, "memory").unwrap();
+        memory
+            .read(&store, stack.i32().unwrap() as usize, &mut bytes)
+            .unwrap();
+        let stack = bytes
+            .chunks(8)
+            .map(|word| {
+                i64::from_le_bytes([
+                    word[0], word[1], word[2], word[3], word[4], word[5], word[6], word[7],
+                ])
+            })
+            .collect();
+        let host = store.into_data();
+        (
+            (host.recorded, regs, stack, exit_code, host.error),
+            host.printed,
+        )
+    }
+
+    // Runs the program on a VM built like `vm`, with `sys 100` recording a
+    fn interpret(program: &[Instructions], vm: &Vm) -> Run {
+        let recorded = Rc::new(RefCell::new(vec![]));
+        let mut interpreter = Vm::with_stack_size(vm.stack.len());
+        interpreter.heap = crate::heap::Heap::new(vm.heap.memory.len());
+        interpreter.overflow = vm.overflow;
+        interpreter.word_size = vm.word_size;
+        interpreter.fuel = vm.fuel;
+        let record = recorded.clone();
+        interpreter.register_syscall(100, move |vm| {
+            record.borrow_mut().push(vm.regs[A as usize]);
+            Ok(())
+        });
+
+        let error = interpreter.run(program).err().map(|e| e.to_string());
+        let mut regs = interpreter.regs.to_vec();
+        regs.remove(Ip as usize);
+        let stack = interpreter.live_stack().to_vec();
+        let recorded = recorded.borrow().clone();
+        (recorded, regs, stack, interpreter.exit_code, error)
+    }
+
+    fn same_as_interpreter(program: &[Instructions], vm: &Vm) {
+        assert_eq!(run(program, vm).0, interpret(program, vm), "{:?}", program);
+    }
+
+    #[test]
+    fn generates() {
+        let vm = Vm::new();
+        let wat = compile(
+            &[Set(A, 1), Tee(A, B), Jmp(0), Mov(Sp, A), Jmp(5), Hlt],
+            &vm,
+        );
+        assert!(wat.contains("  (global $stack_size i64 (i64.const 255))\n"));
+        assert!(
+            wat.contains("(br_table $L0 $invalid $invalid $invalid $invalid $L5 $L6 $invalid\n")
+        );
+        assert!(wat.contains("(then (local.set $target (i64.const 0)) (br $dispatch)))\n"));
+        assert!(wat.contains("(call $set_sp (global.get $a) (i64.const 3))\n"));
+        assert!(wat.contains("(then (br $L5)))"));
+        assert!(wat.contains("(call $ERR_INVALID_JUMP (i64.const 6)))\n"));
+
+        let wat = compile(&[Psh(2), PopR(Ip), Hlt], &vm);
+        assert!(wat.contains("(br_table $L0 $L1 $L2 $L3 $invalid\n"));
+        assert!(wat.contains("(local.set $target (call $pop (i64.const 1)))\n"));
+    }
+
+    #[test]
+    fn examples() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            // errors.vm doesn't parse, .wl files are for `build`
+            let assembly = path.extension().unwrap() == "vm";
+            if !assembly || path.file_name().unwrap() == "errors.vm" {
+                continue;
+            }
+            let options = Default::default();
+            let program = crate::parser::parse_file(path.to_str().unwrap(), options).unwrap();
+            instantiate(&program, &Vm::new());
+        }
+    }
+
+    #[test]
+    fn prints() {
+        let program = [
+            Set(A, 72),
+            Prt(A),
+            Set(A, 300),
+            Prt(A),
+            Drg(A),
+            Fld(Fa, 0.5f64.to_bits()),
+            Fpr(Fa),
+            Psh(1),
+            Psh(-2),
+            Dst,
+            Dmp,
+            HltR(A),
+        ];
+        let (run, printed) = run(&program, &Vm::new());
+        assert_eq!(printed, ["H", "[300]", "[0.5]", "[1]", "[-2]", "dmp 10 1"]);
+        assert_eq!(run.3, 300);
+        assert_eq!(run, interpret(&program, &Vm::new()));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let program = [
+            Set(A, 2147483647),
+            Set(B, 2),
+            Mul(A, B),
+            Sys(100),
+            Mov(A, Of),
+            Sys(100),
+            Set(C, -7),
+            Div(C, B),
+            Psh(9),
+            Psh(-4),
+            SubS,
+            Set(D, i64::MIN),
+            Set(E, -1),
+            Div(D, E),
+            Mul(E, D),
+            Psh(0),
+            DivS,
+            Hlt,
+        ];
+        for overflow in &[
+            OverflowMode::Wrapping,
+            OverflowMode::Saturating,
+            OverflowMode::Trapping,
+        ] {
+            for word_size in &[WordSize::W32, WordSize::W64] {
+                let mut vm = Vm::new();
+                vm.overflow = *overflow;
+                vm.word_size = *word_size;
+                same_as_interpreter(&program, &vm);
+            }
+        }
+    }
+
+    #[test]
+    fn stack_and_jumps() {
+        // Counts down from 5 on the stack, then jumps through ip
+        let program = [
+            Set(A, 5),
+            Set(B, 1),
+            PshR(A),
+            Sub(A, B),
+            Tne(A, F),
+            Jmp(2),
+            Dup,
+            Ovr,
+            Rot,
+            Swp,
+            Psh(14),
+            PopR(Ip),
+            Sys(100),
+            Set(A, 200),
+            Sys(100),
+            Mov(Sp, B),
+            Pop,
+            Pop,
+            Pop,
+            Hlt,
+        ];
+        let mut vm = Vm::new();
+        same_as_interpreter(&program, &vm);
+        vm.fuel = Some(30);
+        same_as_interpreter(&program, &vm);
+        same_as_interpreter(&program, &Vm::with_stack_size(6));
+
+        same_as_interpreter(&[Psh(1), PopR(St), Hlt], &vm);
+        same_as_interpreter(&[Set(A, -1), Mov(Ip, A), Hlt], &vm);
+        same_as_interpreter(&[Set(A, 9), Mov(Ip, A), Hlt], &vm);
+        same_as_interpreter(&[Set(Eq, 1), Jmp(7), Hlt], &vm);
+        same_as_interpreter(&[Set(A, 1)], &vm);
+        same_as_interpreter(&[Sys(7), Hlt], &vm);
+    }
+
+    #[test]
+    fn heap() {
+        let program = [
+            Set(A, 2),
+            Alc(A, B),
+            Alc(A, C),
+            Fre(B),
+            Alc(A, D),
+            Set(E, 104),
+            Sto(D, E),
+            Lod(F, D),
+            Alc(A, B),
+            Set(A, 3),
+            Alc(A, B),
+            Sys(100),
+            Sys(0),
+        ];
+        same_as_interpreter(&program, &Vm::new());
+        let mut vm = Vm::new();
+        vm.heap = crate::heap::Heap::new(6);
+        same_as_interpreter(&program, &vm);
+
+        let vm = Vm::new();
+        same_as_interpreter(&[Set(A, 3), Alc(A, B), Fre(B), Lod(C, B), Hlt], &vm);
+        same_as_interpreter(&[Set(A, 3), Alc(A, B), Fre(B), Fre(B), Hlt], &vm);
+        same_as_interpreter(&[Set(A, 3), Alc(A, B), Add(B, A), Sto(B, A), Hlt], &vm);
+        same_as_interpreter(&[Set(A, 3), Alc(A, B), Add(B, A), Fre(B), Hlt], &vm);
+        same_as_interpreter(&[Alc(A, B), Hlt], &vm);
+    }
+
+    proptest! {
+        #[test]
+        fn same_behaviour(
+            program in program(),
+            overflow in 0..3u8,
+            w64: bool,
+            fuel in prop::option::of(0..100u64),
+        ) {
+            let mut vm = Vm::new();
+            vm.overflow = match overflow {
+                0 => OverflowMode::Wrapping,
+                1 => OverflowMode::Saturating,
+                _ => OverflowMode::Trapping,
+            };
+            vm.word_size = if w64 { WordSize::W64 } else { WordSize::W32 };
+            vm.fuel = fuel;
+            prop_assert_eq!(run(&program, &vm).0, interpret(&program, &vm));
+        }
+    }
+}
@@ -0,0 +1,175 @@
+// Compiles programs to C with `wlvm compile --target c`, builds them with cc and checks that
+// they print the same things and exit with the same code as `wlvm run`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+fn wlvm() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_wlvm"))
+}
+
+fn output(command: &mut Command) -> Output {
+    command.stdin(Stdio::null()).output().unwrap()
+}
+
+fn dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("wlvm-compile-c-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn has_cc() -> bool {
+    let found = Command::new("cc").arg("--version").output().is_ok();
+    if !found {
+        eprintln!("cc not found, skipping");
+    }
+    found
+}
+
+// Runs and compiles the program with the same options, and compares the results
+fn same_as_interpreter(program: &Path, options: &[&str]) {
+    let name = program.file_stem().unwrap().to_string_lossy().into_owned();
+    let dir = dir();
+    let (c, binary) = (dir.join(format!("{}.c", name)), dir.join(&name));
+
+    let compiled = output(
+        wlvm()
+            .args(["compile", "--target", "c"])
+            .args(options)
+            .arg(program)
+            .arg("-o")
+            .arg(&c),
+    );
+    assert!(compiled.status.success(), "{:?}", compiled);
+    let built = output(
+        Command::new("cc")
+            .args(["-std=c11", "-O1", "-o"])
+            .arg(&binary)
+            .arg(&c)
+            .arg("-lm"),
+    );
+    assert!(
+        built.status.success(),
+        "{}",
+        String::from_utf8_lossy(&built.stderr)
+    );
+
+    let expected = output(wlvm().arg("run").args(options).arg(program));
+    let actual = output(&mut Command::new(&binary));
+    assert_eq!(
+        (
+            String::from_utf8_lossy(&actual.stdout),
+            String::from_utf8_lossy(&actual.stderr),
+            actual.status.code()
+        ),
+        (
+            String::from_utf8_lossy(&expected.stdout),
+            String::from_utf8_lossy(&expected.stderr),
+            expected.status.code()
+        ),
+        "{} {:?}",
+        name,
+        options
+    );
+}
+
+fn source(name: &str, source: &str) -> PathBuf {
+    let path = dir().join(format!("{}.vm", name));
+    fs::write(&path, source).unwrap();
+    path
+}
+
+#[test]
+fn examples() {
+    if !has_cc() {
+        return;
+    }
+    for entry in fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("examples")).unwrap() {
+        let path = entry.unwrap().path();
+        // errors.vm doesn't parse, .wl files are for `build`
+        if path.extension().unwrap() == "vm" && path.file_name().unwrap() != "errors.vm" {
+            same_as_interpreter(&path, &[]);
+        }
+    }
+}
+
+#[test]
+fn arithmetic() {
+    if !has_cc() {
+        return;
+    }
+    let program = source(
+        "arithmetic",
+        "set a 2147483647\nset b 2\nmul a b\ndrg a\ndrg of\nset c -7\ndiv c b\ndrg c\n\
+         psh 9\npsh -4\nsub\ndst\npsh 0\ndiv\nhlt",
+    );
+    for options in &[
+        &["--overflow", "wrap"][..],
+        &["--overflow", "saturate"],
+        &["--overflow", "trap"],
+        &["--word-size", "64"],
+        &["--word-size", "64", "--overflow", "saturate"],
+    ] {
+        same_as_interpreter(&program, options);
+    }
+}
+
+#[test]
+fn stack_and_jumps() {
+    if !has_cc() {
+        return;
+    }
+    // Counts down from 5 on the stack, then jumps through ip
+    let program = source(
+        "stack",
+        "set a 5\nset b 1\npsh a\nsub a b\ntne a f\njmp 2\ndup\novr\nrot\nswp\ndmp\n\
+         psh 14\npop ip\nprt a\nset a 200\nprt a\nmov sp b\ndrg st\npop\npop\npop\nhlt",
+    );
+    same_as_interpreter(&program, &[]);
+    same_as_interpreter(&program, &["--stack-size", "6"]);
+    same_as_interpreter(&program, &["--fuel", "30"]);
+
+    let program = source("read_only", "psh 1\npop st\nhlt");
+    same_as_interpreter(&program, &[]);
+    let program = source("negative_jump", "set a -1\nmov ip a\nhlt");
+    same_as_interpreter(&program, &[]);
+    let program = source("past_the_end", "set eq 1\njmp 7\nhlt");
+    same_as_interpreter(&program, &[]);
+}
+
+#[test]
+fn heap_and_syscalls() {
+    if !has_cc() {
+        return;
+    }
+    let program = source(
+        "leaks",
+        "set a 2\nalc a b\nalc a c\nfre b\nalc a d\nset e 104\nsto d e\nadd d a\n\
+         set a 1\nmov b d\nsub b a\nsub b a\nmov c a\nsys 1\nsys 3\nset a 7\nsys 0",
+    );
+    same_as_interpreter(&program, &[]);
+    same_as_interpreter(&program, &["--heap-size", "4"]);
+
+    let program = source("use_after_free", "set a 3\nalc a b\nfre b\nlod c b\nhlt");
+    same_as_interpreter(&program, &[]);
+    let program = source("double_free", "set a 3\nalc a b\nfre b\nfre b\nhlt");
+    same_as_interpreter(&program, &[]);
+    let program = source("unknown_syscall", "sys 42\nhlt");
+    same_as_interpreter(&program, &[]);
+}
+
+#[test]
+fn floats() {
+    if !has_cc() {
+        return;
+    }
+    let program = source(
+        "floats",
+        "fld fa 0.1\nfpr fa\nfld fb 1e21\nfpr fb\nfld fc 0.0000001\nfpr fc\nfld fd -0.0\nfpr fd\n\
+         fld fe NaN\nfpr fe\nfdv fb fd\nfpr fb\nfad fa fc\nfpr fa\nfti a fb\ndrg a\nfti b fe\n\
+         drg b\nfld ff 123456.789\nfpr ff\nfll fc fa\nitf fa eq\ndmp\nhlt",
+    );
+    same_as_interpreter(&program, &[]);
+    same_as_interpreter(&program, &["--word-size", "64"]);
+}